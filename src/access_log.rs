@@ -0,0 +1,501 @@
+use std::fmt::{Display, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::num::NonZero;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::sock_io::Timeout;
+
+/// How every record is written
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Format {
+    /// a JSON object a line
+    #[default]
+    Json,
+    /// `key=value` pairs a line
+    Logfmt,
+}
+
+/// When the log file is moved aside for a new one, it never is by default
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rotation {
+    /// once the file grows past this many bytes
+    pub max_size: Option<NonZero<u64>>,
+    /// once the file has been written to for this long
+    pub every: Option<Duration>,
+    /// how many rotated files to keep, as `<path>.1` (the newest) to `<path>.<keep>`
+    pub keep: usize,
+}
+
+impl Rotation {
+    pub const DEFAULT_KEEP: usize = 5;
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation {
+            max_size: None,
+            every: None,
+            keep: Self::DEFAULT_KEEP,
+        }
+    }
+}
+
+/// Why a session ended
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Close {
+    /// both sides shut down their half
+    Done,
+    Timeout(Timeout),
+    /// no upstream host could be connected to
+    ConnectFailed,
+    /// through the admin api
+    Killed,
+    /// still open once the drain timeout elapsed
+    Shutdown,
+    Error,
+}
+
+impl Display for Close {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Close::Done => "done",
+            Close::Timeout(Timeout::Idle(_)) => "idle_timeout",
+            Close::Timeout(Timeout::Linger(_)) => "linger_timeout",
+            Close::Timeout(Timeout::Lifetime(_)) => "max_lifetime",
+            Close::ConnectFailed => "connect_failed",
+            Close::Killed => "killed",
+            Close::Shutdown => "shutdown",
+            Close::Error => "error",
+        })
+    }
+}
+
+/// A session that ended
+pub struct Record {
+    /// when it ended
+    pub time: SystemTime,
+    pub peer: SocketAddr,
+    pub local: SocketAddr,
+    /// the upstream address it was connected to, if it got that far
+    pub upstream: Option<SocketAddr>,
+    /// time spent resolving the upstream host, across every attempt
+    pub resolve: Duration,
+    /// time spent connecting to the upstream host, across every attempt
+    pub connect: Duration,
+    pub duration: Duration,
+    /// bytes from the client to the upstream
+    pub upload: u64,
+    /// bytes from the upstream to the client
+    pub download: u64,
+    pub close: Close,
+    pub error: Option<String>,
+}
+
+fn millis(duration: Duration) -> impl Display {
+    struct Millis(Duration);
+
+    impl Display for Millis {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:.3}", self.0.as_secs_f64() * 1000.0)
+        }
+    }
+
+    Millis(duration)
+}
+
+fn json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn logfmt_str(out: &mut String, s: &str) {
+    let quote = s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '"' || c == '=');
+    if !quote {
+        return out.push_str(s);
+    }
+
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl Record {
+    /// appends the record as a single line
+    fn write(&self, format: Format, out: &mut String) {
+        let time = humantime::format_rfc3339_millis(self.time).to_string();
+        let upstream = self.upstream.map(|addr| addr.to_string());
+        let fields: [(&str, Option<&dyn Display>, bool); 11] = [
+            ("time", Some(&time), true),
+            ("peer", Some(&self.peer), true),
+            ("local", Some(&self.local), true),
+            ("upstream", upstream.as_ref().map(|s| s as _), true),
+            ("resolve_ms", Some(&millis(self.resolve)), false),
+            ("connect_ms", Some(&millis(self.connect)), false),
+            ("duration_ms", Some(&millis(self.duration)), false),
+            ("upload", Some(&self.upload), false),
+            ("download", Some(&self.download), false),
+            ("close", Some(&self.close), true),
+            ("error", self.error.as_ref().map(|s| s as _), true),
+        ];
+
+        match format {
+            Format::Json => {
+                out.push('{');
+                for (i, (key, value, string)) in fields.into_iter().enumerate() {
+                    if i != 0 {
+                        out.push(',');
+                    }
+                    let _ = write!(out, "\"{key}\":");
+                    match value {
+                        None => out.push_str("null"),
+                        Some(value) if string => json_str(out, &value.to_string()),
+                        Some(value) => {
+                            let _ = write!(out, "{value}");
+                        }
+                    }
+                }
+                out.push('}');
+            }
+            Format::Logfmt => {
+                let fields = fields
+                    .into_iter()
+                    .filter_map(|(key, value, _)| Some((key, value?)));
+                for (i, (key, value)) in fields.enumerate() {
+                    if i != 0 {
+                        out.push(' ');
+                    }
+                    let _ = write!(out, "{key}=");
+                    logfmt_str(out, &value.to_string());
+                }
+            }
+        }
+        out.push('\n');
+    }
+}
+
+/// Where the records end up
+enum Output {
+    Stdout(io::Stdout),
+    File {
+        path: PathBuf,
+        file: BufWriter<File>,
+        size: u64,
+        opened: Instant,
+    },
+}
+
+fn open(path: &Path) -> io::Result<Output> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(Output::File {
+        path: path.to_owned(),
+        size: file.metadata()?.len(),
+        file: BufWriter::new(file),
+        opened: Instant::now(),
+    })
+}
+
+/// moves `path` aside to `<path>.1`, and every older one a number up, dropping the ones past `keep`
+fn rotate_files(path: &Path, keep: usize) -> io::Result<()> {
+    let numbered = |i: usize| {
+        let mut path = path.as_os_str().to_owned();
+        path.push(format!(".{i}"));
+        PathBuf::from(path)
+    };
+
+    if keep == 0 {
+        return std::fs::remove_file(path);
+    }
+
+    for i in (1..keep).rev() {
+        match std::fs::rename(numbered(i), numbered(i + 1)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    std::fs::rename(path, numbered(1))
+}
+
+impl Output {
+    fn rotate_if_due(&mut self, rotation: &Rotation) -> io::Result<()> {
+        let Output::File {
+            path,
+            file,
+            size,
+            opened,
+        } = self
+        else {
+            return Ok(());
+        };
+
+        let too_big = rotation.max_size.is_some_and(|max| *size >= max.get());
+        let too_old = rotation
+            .every
+            .is_some_and(|every| opened.elapsed() >= every);
+        if !(too_big || too_old) || *size == 0 {
+            return Ok(());
+        }
+
+        file.flush()?;
+        rotate_files(path, rotation.keep)?;
+        *self = open(path)?;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.lock().write_all(line.as_bytes()),
+            Output::File { file, size, .. } => {
+                file.write_all(line.as_bytes())?;
+                *size += line.len() as u64;
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush(),
+            Output::File { file, .. } => file.flush(),
+        }
+    }
+}
+
+/// what the writing thread is sent, nearly always a record, so boxing it wouldn't save anything
+#[allow(clippy::large_enum_variant)]
+enum Message {
+    Record(Record),
+    /// write what's queued up and stop
+    Close,
+}
+
+/// Writes a record for every session that ended, from a thread of its own
+pub struct AccessLog {
+    records: flume::Sender<Message>,
+    /// records that didn't fit in the queue, reported by the writing thread
+    dropped: Arc<AtomicU64>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AccessLog {
+    /// how many records can be waiting to be written before new ones are dropped
+    const QUEUE: usize = 16 * 1024;
+
+    /// starts writing to the file at `path`, or stdout if it's `-`
+    pub fn open(path: &Path, format: Format, rotation: Rotation) -> io::Result<Self> {
+        let mut output = match path == Path::new("-") {
+            true => Output::Stdout(io::stdout()),
+            false => open(path)?,
+        };
+
+        let (tx, rx) = flume::bounded::<Message>(Self::QUEUE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer = {
+            let dropped = Arc::clone(&dropped);
+            std::thread::Builder::new()
+                .name("hptp-access-log".to_owned())
+                .spawn(move || {
+                    let mut line = String::new();
+                    let mut closed = false;
+                    while let Ok(message) = rx.recv() {
+                        // everything queued up is written before flushing
+                        let res = std::iter::once(message)
+                            .chain(rx.drain())
+                            .try_for_each(|message| {
+                                let record = match message {
+                                    Message::Record(record) => record,
+                                    Message::Close => {
+                                        closed = true;
+                                        return Ok(());
+                                    }
+                                };
+                                output.rotate_if_due(&rotation)?;
+                                line.clear();
+                                record.write(format, &mut line);
+                                output.write(&line)
+                            })
+                            .and_then(|()| output.flush());
+                        if let Err(err) = res {
+                            tracing::error!("Unable to write the access log: {err}")
+                        }
+
+                        match dropped.swap(0, Ordering::Relaxed) {
+                            0 => {}
+                            n => tracing::warn!("Dropped {n} access log records, it can't keep up"),
+                        }
+                        if closed {
+                            break;
+                        }
+                    }
+                })?
+        };
+
+        Ok(AccessLog {
+            records: tx,
+            dropped,
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    /// queues `record` to be written, without ever waiting
+    pub fn log(&self, record: Record) {
+        if self.records.try_send(Message::Record(record)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// waits for every record queued up to be written, records logged after it are dropped
+    pub fn close(&self) {
+        let writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        let Some(writer) = writer else {
+            return;
+        };
+        // waits for room in the queue, unlike records
+        if self.records.send(Message::Close).is_ok() && writer.join().is_err() {
+            tracing::error!("The access log thread panicked, some records might be missing")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> Record {
+        Record {
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            peer: "192.0.2.1:56324".parse().unwrap(),
+            local: "[::1]:443".parse().unwrap(),
+            upstream: Some("10.0.0.1:8443".parse().unwrap()),
+            resolve: Duration::from_micros(1500),
+            connect: Duration::from_millis(3),
+            duration: Duration::from_secs(2),
+            upload: 100,
+            download: 2000,
+            close: Close::Done,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn json() {
+        let mut line = String::new();
+        record().write(Format::Json, &mut line);
+        assert_eq!(
+            line,
+            r#"{"time":"2023-11-14T22:13:20.000Z","peer":"192.0.2.1:56324","local":"[::1]:443","upstream":"10.0.0.1:8443","resolve_ms":1.500,"connect_ms":3.000,"duration_ms":2000.000,"upload":100,"download":2000,"close":"done","error":null}"#
+                .to_owned()
+                + "\n"
+        );
+
+        let mut line = String::new();
+        Record {
+            upstream: None,
+            close: Close::ConnectFailed,
+            error: Some("connection \"refused\"\n".to_owned()),
+            ..record()
+        }
+        .write(Format::Json, &mut line);
+        assert!(line.contains(r#""upstream":null,"#), "{line}");
+        assert!(
+            line.ends_with(
+                "\"close\":\"connect_failed\",\"error\":\"connection \\\"refused\\\"\\n\"}\n"
+            ),
+            "{line}"
+        );
+    }
+
+    #[test]
+    fn logfmt() {
+        let mut line = String::new();
+        Record {
+            upstream: None,
+            close: Close::Timeout(Timeout::Idle(Duration::from_secs(60))),
+            error: Some("timed out".to_owned()),
+            ..record()
+        }
+        .write(Format::Logfmt, &mut line);
+        assert_eq!(
+            line,
+            "time=2023-11-14T22:13:20.000Z peer=192.0.2.1:56324 local=[::1]:443 \
+             resolve_ms=1.500 connect_ms=3.000 duration_ms=2000.000 upload=100 download=2000 \
+             close=idle_timeout error=\"timed out\"\n"
+        );
+    }
+
+    #[test]
+    fn rotate() {
+        let dir = std::env::temp_dir().join(format!("hptp-access-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let rotation = Rotation {
+            max_size: NonZero::new(10),
+            every: None,
+            keep: 2,
+        };
+
+        let mut output = open(&path).unwrap();
+        for line in ["first line\n", "second line\n", "third line\n", "fourth\n"] {
+            output.rotate_if_due(&rotation).unwrap();
+            output.write(line).unwrap();
+        }
+        output.flush().unwrap();
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).ok();
+        assert_eq!(read("access.log").as_deref(), Some("fourth\n"));
+        assert_eq!(read("access.log.1").as_deref(), Some("third line\n"));
+        assert_eq!(read("access.log.2").as_deref(), Some("second line\n"));
+        assert_eq!(read("access.log.3"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn close() {
+        let dir = std::env::temp_dir().join(format!("hptp-access-close-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let log = AccessLog::open(&path, Format::Logfmt, Rotation::default()).unwrap();
+        for _ in 0..3 {
+            log.log(record())
+        }
+        log.close();
+        // closing again doesn't wait on anything
+        log.close();
+        log.log(record());
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written.lines().count(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,340 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use monoio::time::sleep;
+
+use crate::cidr::{Cidr, Cidrs};
+
+/// Why a client was turned away
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Denied {
+    By(Cidr),
+    NotAllowed,
+}
+
+impl Display for Denied {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Denied::By(cidr) => write!(f, "denied by {cidr}"),
+            Denied::NotAllowed => f.write_str("not in the allow list"),
+        }
+    }
+}
+
+/// Allow and deny lists, a deny always wins,
+/// and if there is anything in the allow list, only clients in it are let through
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Acl {
+    pub allow: Cidrs,
+    pub deny: Cidrs,
+}
+
+impl Acl {
+    pub fn check(&self, ip: IpAddr) -> Result<(), Denied> {
+        if let Some(cidr) = self.deny.find(ip) {
+            return Err(Denied::By(cidr));
+        }
+
+        match self.allow.is_empty() || self.allow.contains(ip) {
+            true => Ok(()),
+            false => Err(Denied::NotAllowed),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AclFileError {
+    #[error("unable to read ACL file `{}`: {}", .0.display(), .1)]
+    Io(PathBuf, io::Error),
+    #[error(
+        "invalid ACL file `{}` line {line}, expected `allow <network>` or `deny <network>`",
+        .path.display()
+    )]
+    Invalid { path: PathBuf, line: usize },
+}
+
+/// parses one `allow <network>` or `deny <network>` entry per line, `#` starts a comment
+fn parse_file(path: &Path, contents: &str) -> Result<Acl, AclFileError> {
+    let (mut allow, mut deny) = (vec![], vec![]);
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = || AclFileError::Invalid {
+            path: path.to_owned(),
+            line: i + 1,
+        };
+        let (action, cidr) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let cidr = cidr.parse::<Cidr>().map_err(|_| invalid())?;
+        match action {
+            "allow" => allow.push(cidr),
+            "deny" => deny.push(cidr),
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(Acl {
+        allow: allow.into(),
+        deny: deny.into(),
+    })
+}
+
+struct Loaded {
+    acl: Arc<Acl>,
+    /// when the file was modified, as of the last time it was read
+    modified: Option<SystemTime>,
+    /// why it couldn't be read the last time, which was logged already
+    failed: Option<String>,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// reads the files, so a slow or hung file system doesn't hold up the runtime threads
+static READER: LazyLock<flume::Sender<Job>> = LazyLock::new(|| {
+    let (tx, rx) = flume::unbounded::<Job>();
+    std::thread::Builder::new()
+        .name("hptp-acl-files".to_owned())
+        .spawn(move || rx.iter().for_each(|job| job()))
+        .expect("unable to spawn the ACL file thread");
+    tx
+});
+
+/// runs `f` on the [`READER`] thread
+async fn off_thread<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> R {
+    let (tx, rx) = flume::bounded(1);
+    let _ = READER.send(Box::new(move || drop(tx.send(f()))));
+    rx.recv_async().await.expect("the ACL file thread stopped")
+}
+
+/// An [`Acl`] kept in a file, which is reloaded whenever it changes
+pub struct AclFile {
+    path: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl AclFile {
+    /// how often the file is checked for changes
+    const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    }
+
+    fn read(path: &Path) -> Result<Acl, AclFileError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|err| AclFileError::Io(path.to_owned(), err))?;
+        parse_file(path, &contents)
+    }
+
+    /// reads the file unless it wasn't modified since `last`, along with when it was
+    fn read_changed(
+        path: &Path,
+        last: Option<SystemTime>,
+    ) -> Option<(Option<SystemTime>, Result<Acl, AclFileError>)> {
+        let modified = Self::modified(path);
+        match modified.is_some() && modified == last {
+            true => None,
+            false => Some((modified, Self::read(path))),
+        }
+    }
+
+    pub fn load(path: PathBuf) -> Result<Self, AclFileError> {
+        let modified = Self::modified(&path);
+        Ok(AclFile {
+            loaded: Mutex::new(Loaded {
+                acl: Arc::new(Self::read(&path)?),
+                modified,
+                failed: None,
+            }),
+            path,
+        })
+    }
+
+    fn with_loaded<R>(&self, f: impl FnOnce(&mut Loaded) -> R) -> R {
+        f(&mut self.loaded.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub fn acl(&self) -> Arc<Acl> {
+        self.with_loaded(|loaded| Arc::clone(&loaded.acl))
+    }
+
+    /// takes what was read, if it can't be read or parsed the previous list stays in effect,
+    /// and why is only logged once, until that changes
+    fn update(&self, modified: Option<SystemTime>, read: Result<Acl, AclFileError>) {
+        self.with_loaded(|loaded| {
+            loaded.modified = modified;
+            match read {
+                Ok(acl) => {
+                    if loaded.failed.take().is_some() || *loaded.acl != acl {
+                        tracing::info!("reloaded ACL file `{}`", self.path.display());
+                        loaded.acl = Arc::new(acl)
+                    }
+                }
+                Err(err) => {
+                    let err = err.to_string();
+                    if loaded.failed.as_ref() != Some(&err) {
+                        tracing::error!("{err}, keeping the previous list")
+                    }
+                    loaded.failed = Some(err)
+                }
+            }
+        })
+    }
+
+    /// reloads the file if it changed since it was last read, blocking
+    #[cfg(test)]
+    fn reload(&self) {
+        let last = self.with_loaded(|loaded| loaded.modified);
+        if let Some((modified, read)) = Self::read_changed(&self.path, last) {
+            self.update(modified, read)
+        }
+    }
+
+    pub async fn watch(&self) -> ! {
+        loop {
+            sleep(Self::POLL_INTERVAL).await;
+            let path = self.path.clone();
+            let last = self.with_loaded(|loaded| loaded.modified);
+            if let Some((modified, read)) =
+                off_thread(move || Self::read_changed(&path, last)).await
+            {
+                self.update(modified, read)
+            }
+        }
+    }
+}
+
+/// An inline [`Acl`], and optionally one kept in a file, a client has to pass both
+#[derive(Default)]
+pub struct AccessList {
+    pub inline: Acl,
+    pub file: Option<AclFile>,
+}
+
+impl AccessList {
+    pub fn check(&self, ip: IpAddr) -> Result<(), Denied> {
+        self.inline.check(ip)?;
+        match &self.file {
+            Some(file) => file.acl().check(ip),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn check() {
+        let acl = Acl {
+            allow: "10.0.0.0/8,::1".parse().unwrap(),
+            deny: "10.0.0.0/24".parse().unwrap(),
+        };
+        assert_eq!(acl.check(ip("10.1.0.1")), Ok(()));
+        assert_eq!(acl.check(ip("::1")), Ok(()));
+        assert_eq!(
+            acl.check(ip("10.0.0.7")),
+            Err(Denied::By("10.0.0.0/24".parse().unwrap()))
+        );
+        assert_eq!(acl.check(ip("192.0.2.1")), Err(Denied::NotAllowed));
+
+        let deny_only = Acl {
+            allow: Cidrs::default(),
+            deny: "192.0.2.0/24".parse().unwrap(),
+        };
+        assert_eq!(deny_only.check(ip("198.51.100.1")), Ok(()));
+        assert!(deny_only.check(ip("192.0.2.1")).is_err());
+    }
+
+    #[test]
+    fn parse_acl_file() {
+        let path = Path::new("acl.txt");
+        let acl = parse_file(
+            path,
+            "# office\nallow 10.0.0.0/8\n\n  deny 10.0.0.13 # the printer\nallow ::1\n",
+        )
+        .unwrap();
+        assert_eq!(acl.allow, "10.0.0.0/8,::1".parse().unwrap());
+        assert_eq!(acl.deny, "10.0.0.13".parse().unwrap());
+
+        assert!(matches!(
+            parse_file(path, "allow 10.0.0.0/8\npermit 10.0.0.1"),
+            Err(AclFileError::Invalid { line: 2, .. })
+        ));
+        assert!(parse_file(path, "deny").is_err());
+        assert!(parse_file(path, "deny example.com").is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn reload_file() {
+        let path = std::env::temp_dir().join(format!("hptp-acl-test-{}", std::process::id()));
+        std::fs::write(&path, "deny 192.0.2.1\n").unwrap();
+        let file = AclFile::load(path.clone()).unwrap();
+        assert!(file.acl().check(ip("192.0.2.1")).is_err());
+
+        std::fs::write(&path, "deny 192.0.2.2\n").unwrap();
+        // make sure the change is visible even on file systems with a coarse mtime
+        let later = SystemTime::now() + Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        file.reload();
+        assert!(file.acl().check(ip("192.0.2.1")).is_ok());
+        assert!(file.acl().check(ip("192.0.2.2")).is_err());
+
+        // a broken file keeps the last good list
+        std::fs::write(&path, "nonsense\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later + Duration::from_secs(5))
+            .unwrap();
+        file.reload();
+        assert!(file.acl().check(ip("192.0.2.2")).is_err());
+        let failed = file.with_loaded(|loaded| loaded.failed.clone());
+        assert!(failed.is_some());
+
+        // so does a deleted one, and why is remembered so it's only logged once
+        std::fs::remove_file(&path).unwrap();
+        file.reload();
+        let failed = file.with_loaded(|loaded| loaded.failed.clone()).unwrap();
+        assert!(failed.starts_with("unable to read ACL file"));
+        file.reload();
+        assert_eq!(
+            file.with_loaded(|loaded| loaded.failed.clone()),
+            Some(failed)
+        );
+        assert!(file.acl().check(ip("192.0.2.2")).is_err());
+
+        std::fs::write(&path, "deny 192.0.2.3\n").unwrap();
+        file.reload();
+        assert_eq!(file.with_loaded(|loaded| loaded.failed.clone()), None);
+        assert!(file.acl().check(ip("192.0.2.3")).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[monoio::test]
+    async fn reads_off_thread() {
+        let name = off_thread(|| std::thread::current().name().map(str::to_owned)).await;
+        assert_eq!(name.as_deref(), Some("hptp-acl-files"));
+    }
+}
@@ -0,0 +1,272 @@
+use std::error::Error;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::num::NonZero;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures::lock::Mutex;
+use monoio::net::TcpListener;
+
+use crate::http::{self, Request, Response};
+use crate::{host, route, stream, Global, Listening, PortMapping};
+
+/// Reads and replaces the filter every log goes through
+pub trait LogFilter {
+    /// the directives in effect
+    fn current(&self) -> String;
+    fn set(&self, directives: &str) -> Result<(), Box<dyn Error>>;
+}
+
+/// Where to serve the admin api, and what it controls
+pub struct Admin {
+    pub addr: SocketAddr,
+    pub log_filter: Box<dyn LogFilter>,
+}
+
+#[derive(Clone)]
+pub struct State {
+    /// where the api is served
+    pub addr: SocketAddr,
+    pub global: Arc<Global>,
+    pub listening: Rc<Mutex<Listening>>,
+    pub log_filter: Rc<dyn LogFilter>,
+}
+
+/// a JSON array of every session still open, one a line
+fn sessions(global: &Global) -> Response {
+    let mut body = String::from("[");
+    for (i, activity) in global.sessions.list().iter().enumerate() {
+        let endpoints = activity.endpoints();
+        let transferred = activity.counters.transferred();
+        let upstream = match endpoints.upstream {
+            Some(upstream) => format!("\"{upstream}\""),
+            None => "null".to_owned(),
+        };
+        let _ = write!(
+            body,
+            "{}\n{{\"id\":{},\"peer\":\"{}\",\"local\":\"{}\",\"upstream\":{upstream},\
+             \"upload\":{},\"download\":{},\"age_ms\":{}}}",
+            if i == 0 { "" } else { "," },
+            activity.id,
+            endpoints.peer,
+            endpoints.local,
+            transferred.client,
+            transferred.server,
+            activity.opened.elapsed().as_millis(),
+        );
+    }
+    body.push_str("\n]\n");
+
+    Response {
+        status: "200 OK",
+        content_type: "application/json",
+        body,
+    }
+}
+
+fn kill(global: &Global, id: &str) -> Response {
+    let Ok(id) = id.parse() else {
+        return Response::text("400 Bad Request", format!("invalid session id `{id}`\n"));
+    };
+
+    match global.sessions.kill(id) {
+        true => {
+            tracing::info!("Killed session {id} through the admin api");
+            Response::text("200 OK", format!("killed session {id}\n"))
+        }
+        false => Response::text("404 Not Found", format!("no session {id} is open\n")),
+    }
+}
+
+/// every address listened on, and where it forwards to
+fn ports(listening: &Listening) -> Response {
+    let mut body = String::new();
+    for (addr, route) in &*listening.routes {
+        let _ = writeln!(body, "{addr} -> {route:?}");
+    }
+    Response::text("200 OK", body)
+}
+
+fn parse_mapping(s: &str) -> Option<PortMapping> {
+    let (listen, upstream) = s.split_once(':').unwrap_or((s, s));
+    Some(PortMapping {
+        listen: listen.parse().ok()?,
+        upstream: upstream.parse().ok()?,
+    })
+}
+
+/// listens on `listen[:upstream]` on every address of a rule, the first one unless `?rule=` says,
+/// answering once every thread listens on them, or with why they couldn't
+async fn add_port(request: &Request, listening: &mut Listening, mapping: &str) -> Response {
+    let Some(mapping) = parse_mapping(mapping) else {
+        return Response::text("400 Bad Request", format!("invalid port `{mapping}`\n"));
+    };
+    let Ok(i) = request.param("rule").unwrap_or("0").parse::<usize>() else {
+        return Response::text("400 Bad Request", "invalid rule index\n");
+    };
+    let Some(rule) = listening.rules.get(i) else {
+        return Response::text("404 Not Found", format!("there is no rule {i}\n"));
+    };
+
+    // a rule's own ports can be remapped, but another rule's are left alone
+    if let Some((addr, _)) = listening.routes.iter().find(|(addr, route)| {
+        !Arc::ptr_eq(&route.rule, rule)
+            && rule
+                .bind
+                .iter()
+                .any(|&ip| stream::overlap(*addr, SocketAddr::new(ip, mapping.listen.get())))
+    }) {
+        return Response::text(
+            "409 Conflict",
+            format!("`{addr}` is listened on by another rule\n"),
+        );
+    }
+
+    let added = rule
+        .bind
+        .iter()
+        .map(|&ip| {
+            (
+                SocketAddr::new(ip, mapping.listen.get()),
+                route(rule, mapping),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut routes = listening
+        .routes
+        .iter()
+        .filter(|(addr, _)| added.iter().all(|(added, _)| added != addr))
+        .cloned()
+        .collect::<Vec<_>>();
+    let addrs = added.iter().map(|(addr, _)| *addr).collect::<Vec<_>>();
+    routes.extend(added);
+    if let Err(err) = listening.set_routes(routes).await {
+        return Response::text("500 Internal Server Error", format!("{err}\n"));
+    }
+
+    tracing::info!(
+        "Listening on {addrs:?} through the admin api, forwarding to port {}",
+        mapping.upstream
+    );
+    Response::text("200 OK", format!("listening on {addrs:?}\n"))
+}
+
+/// stops listening on `port`, on every address
+async fn remove_port(listening: &mut Listening, port: &str) -> Response {
+    let Ok(port) = port.parse::<NonZero<u16>>() else {
+        return Response::text("400 Bad Request", format!("invalid port `{port}`\n"));
+    };
+
+    let (removed, kept) = listening
+        .routes
+        .iter()
+        .cloned()
+        .partition::<Vec<_>, _>(|(addr, _)| addr.port() == port.get());
+    if removed.is_empty() {
+        return Response::text("404 Not Found", format!("port {port} isn't listened on\n"));
+    }
+    if let Err(err) = listening.set_routes(kept).await {
+        return Response::text("500 Internal Server Error", format!("{err}\n"));
+    }
+
+    let addrs = removed.iter().map(|(addr, _)| *addr).collect::<Vec<_>>();
+    tracing::info!("Stopped listening on {addrs:?} through the admin api");
+    Response::text("200 OK", format!("stopped listening on {addrs:?}\n"))
+}
+
+fn set_log_filter(log_filter: &dyn LogFilter, body: &[u8]) -> Response {
+    let directives = String::from_utf8_lossy(body);
+    match log_filter.set(directives.trim()) {
+        Ok(()) => {
+            tracing::info!("Log filter changed to `{}`", log_filter.current());
+            Response::text("200 OK", format!("{}\n", log_filter.current()))
+        }
+        Err(err) => Response::text("400 Bad Request", format!("{err}\n")),
+    }
+}
+
+/// whether `host` names the loopback address the api is served on, and not some domain
+/// a browser was pointed at, that resolves to it (DNS rebinding)
+fn local_host(host: &str, addr: SocketAddr) -> bool {
+    let ip = match addr {
+        SocketAddr::V4(addr) => addr.ip().to_string(),
+        SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+    };
+    [ip, "localhost".to_owned()].iter().any(|name| {
+        let with_port = format!("{name}:{}", addr.port());
+        host.eq_ignore_ascii_case(&with_port)
+            || (addr.port() == 80 && host.eq_ignore_ascii_case(name))
+    })
+}
+
+async fn handle(request: Request, state: &State) -> Response {
+    if !request
+        .host
+        .as_deref()
+        .is_some_and(|host| local_host(host, state.addr))
+    {
+        return Response::text(
+            "403 Forbidden",
+            "the Host header has to be the api's address\n",
+        );
+    }
+
+    let segments = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+    match (&*request.method, &segments[..]) {
+        ("GET", ["sessions"]) => sessions(&state.global),
+        ("DELETE", ["sessions", id]) => kill(&state.global, id),
+        ("GET", ["ports"]) => ports(&*state.listening.lock().await),
+        ("PUT", ["ports", mapping]) => {
+            add_port(&request, &mut *state.listening.lock().await, mapping).await
+        }
+        ("DELETE", ["ports", port]) => remove_port(&mut *state.listening.lock().await, port).await,
+        ("POST", ["dns", "flush"]) => {
+            let hosts = host::flush_dns_caches();
+            tracing::info!("Flushed the DNS cache of {hosts} hosts through the admin api");
+            Response::text(
+                "200 OK",
+                format!("flushed the DNS cache of {hosts} hosts\n"),
+            )
+        }
+        ("GET", ["log"]) => Response::text("200 OK", format!("{}\n", state.log_filter.current())),
+        ("PUT", ["log"]) => set_log_filter(&*state.log_filter, &request.body),
+        (_, ["sessions" | "ports" | "log"] | ["sessions" | "ports", _] | ["dns", "flush"]) => {
+            Response::text("405 Method Not Allowed", "")
+        }
+        _ => Response::text("404 Not Found", ""),
+    }
+}
+
+/// serves the admin api on `listener` until the process exits
+pub async fn serve(listener: TcpListener, state: State) {
+    http::serve(listener, "admin", move |request| {
+        let state = state.clone();
+        async move { handle(request, &state).await }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_header() {
+        let addr = "127.0.0.1:9180".parse().unwrap();
+        assert!(local_host("127.0.0.1:9180", addr));
+        assert!(local_host("LOCALHOST:9180", addr));
+        assert!(!local_host("localhost", addr));
+        assert!(!local_host("127.0.0.1:80", addr));
+        assert!(!local_host("attacker.example:9180", addr));
+
+        let addr = "[::1]:80".parse().unwrap();
+        assert!(local_host("[::1]", addr));
+        assert!(local_host("localhost", addr));
+        assert!(!local_host("::1", addr));
+    }
+}
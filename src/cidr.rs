@@ -0,0 +1,205 @@
+use std::fmt::{Display, Formatter, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug)]
+#[error("invalid network `{0}`, expected an ip address optionally followed by /<prefix length>")]
+pub struct CidrParseError(String);
+
+/// ipv4 addresses accepted on a dual stack socket show up as ipv4 mapped ipv6 addresses
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// A network in CIDR notation, a bare address is a network of just that address
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn mask(self) -> u128 {
+        let bits = match self.addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        match self.prefix {
+            0 => 0,
+            prefix => (u128::MAX << (bits - prefix)) & (u128::MAX >> (128 - bits)),
+        }
+    }
+
+    fn bits(ip: IpAddr) -> u128 {
+        match ip {
+            IpAddr::V4(ip) => u128::from(u32::from(ip)),
+            IpAddr::V6(ip) => u128::from(ip),
+        }
+    }
+
+    pub fn contains(self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        self.addr.is_ipv4() == ip.is_ipv4()
+            && Self::bits(ip) & self.mask() == Self::bits(self.addr) & self.mask()
+    }
+
+    /// the network of the given length `ip` is part of
+    pub fn of(ip: IpAddr, prefix: u8) -> Self {
+        let ip = canonical(ip);
+        let max = match ip {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let network = Cidr {
+            addr: ip,
+            prefix: prefix.min(max),
+        };
+
+        let bits = Self::bits(ip) & network.mask();
+        Cidr {
+            addr: match ip {
+                IpAddr::V4(_) => IpAddr::V4((bits as u32).into()),
+                IpAddr::V6(_) => IpAddr::V6(bits.into()),
+            },
+            ..network
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || CidrParseError(s.to_owned());
+        let s = s.trim();
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+
+        let addr = canonical(addr.parse::<IpAddr>().map_err(|_| err())?);
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(err)?,
+            None => max,
+        };
+
+        Ok(Cidr::of(addr, prefix))
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// A comma separated list of [`Cidr`]s
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cidrs(Vec<Cidr>);
+
+impl Cidrs {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.find(ip).is_some()
+    }
+
+    /// the first network `ip` is part of
+    pub fn find(&self, ip: IpAddr) -> Option<Cidr> {
+        self.0.iter().copied().find(|cidr| cidr.contains(ip))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<Vec<Cidr>> for Cidrs {
+    fn from(cidrs: Vec<Cidr>) -> Self {
+        Cidrs(cidrs)
+    }
+}
+
+impl FromStr for Cidrs {
+    type Err = CidrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Cidrs)
+    }
+}
+
+impl Display for Cidrs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, cidr) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_char(',')?;
+            }
+            Display::fmt(cidr, f)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_cidrs() {
+        let cidrs = "10.1.2.3/8, 192.0.2.1,2001:db8::1/32,::/0"
+            .parse::<Cidrs>()
+            .unwrap();
+        assert_eq!(
+            cidrs.to_string(),
+            "10.0.0.0/8,192.0.2.1/32,2001:db8::/32,::/0"
+        );
+        assert_eq!(cidrs.to_string().parse::<Cidrs>().unwrap(), cidrs);
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+        assert!("".parse::<Cidrs>().is_err());
+    }
+
+    #[test]
+    fn contains() {
+        let cidr = "10.0.0.0/8".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(ip("10.255.0.1")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert!(cidr.contains(IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped())));
+        assert!(!cidr.contains(ip("::1")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+        assert!("::/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert!("2001:db8::/32"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(ip("2001:db8:ffff::1")));
+        assert!(!"2001:db8::/32"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(ip("2001:db9::1")));
+        assert_eq!(
+            Cidr::of(ip("192.0.2.77"), 24),
+            "192.0.2.0/24".parse().unwrap()
+        );
+    }
+}
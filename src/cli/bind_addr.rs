@@ -0,0 +1,205 @@
+use itertools::Itertools;
+use std::fmt::{Display, Formatter, Write};
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug)]
+pub enum BindError {
+    #[error("no network interface named `{0}`")]
+    NoSuchInterface(String),
+    #[error("network interface `{0}` has no address that can be bound")]
+    NoAddresses(String),
+    #[error("unable to list the network interfaces: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "invalid bind address `{0}`, \
+    expected an ipv4 address, an ipv6 address optionally wrapped in [], or a network interface name"
+)]
+pub struct BindAddrParseError(String);
+
+/// An address to listen on, either given directly or as the name of a network interface,
+/// in which case every address assigned to that interface is used
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindAddr {
+    Ip(IpAddr),
+    Interface(String),
+}
+
+impl FromStr for BindAddr {
+    type Err = BindAddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let unbracketed = s.strip_prefix('[').and_then(|s| s.strip_suffix(']'));
+        if let Ok(ip) = unbracketed.unwrap_or(s).parse::<IpAddr>() {
+            return Ok(BindAddr::Ip(ip));
+        }
+
+        let is_interface_name = !s.is_empty()
+            && unbracketed.is_none()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'));
+
+        match is_interface_name {
+            true => Ok(BindAddr::Interface(s.to_owned())),
+            false => Err(BindAddrParseError(s.to_owned())),
+        }
+    }
+}
+
+impl Display for BindAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BindAddr::Ip(IpAddr::V6(ip)) => write!(f, "[{ip}]"),
+            BindAddr::Ip(IpAddr::V4(ip)) => write!(f, "{ip}"),
+            BindAddr::Interface(name) => f.write_str(name),
+        }
+    }
+}
+
+impl BindAddr {
+    fn resolve(&self) -> Result<Vec<IpAddr>, BindError> {
+        match self {
+            &BindAddr::Ip(ip) => Ok(vec![ip]),
+            BindAddr::Interface(name) => interface_addrs(name),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn interface_addrs(name: &str) -> Result<Vec<IpAddr>, BindError> {
+    let mut found = false;
+    let addrs = nix::ifaddrs::getifaddrs()
+        .map_err(io::Error::from)?
+        .filter(|iface| iface.interface_name == name)
+        .inspect(|_| found = true)
+        .filter_map(|iface| {
+            let addr = iface.address?;
+            addr.as_sockaddr_in()
+                .map(|addr| IpAddr::V4(addr.ip()))
+                .or_else(|| addr.as_sockaddr_in6().map(|addr| IpAddr::V6(addr.ip())))
+        })
+        .filter(|ip| match ip {
+            // link local addresses can't be bound without a scope id
+            IpAddr::V6(ip) if ip.is_unicast_link_local() => {
+                tracing::debug!("skipping link local address {ip} of interface `{name}`");
+                false
+            }
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+
+    match (found, addrs.is_empty()) {
+        (false, _) => Err(BindError::NoSuchInterface(name.to_owned())),
+        (true, true) => Err(BindError::NoAddresses(name.to_owned())),
+        (true, false) => Ok(addrs),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn interface_addrs(_: &str) -> Result<Vec<IpAddr>, BindError> {
+    Err(BindError::Io(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to a network interface by name isn't supported on this platform",
+    )))
+}
+
+/// A comma separated list of [`BindAddr`]s
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BindAddrs(Vec<BindAddr>);
+
+impl BindAddrs {
+    /// resolves every interface to its current addresses
+    pub fn resolve(&self) -> Result<Vec<IpAddr>, BindError> {
+        self.0
+            .iter()
+            .map(BindAddr::resolve)
+            .flatten_ok()
+            .process_results(|addrs| addrs.unique().collect())
+    }
+}
+
+impl From<Vec<BindAddr>> for BindAddrs {
+    fn from(addrs: Vec<BindAddr>) -> Self {
+        BindAddrs(addrs)
+    }
+}
+
+impl FromStr for BindAddrs {
+    type Err = BindAddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',').map(str::parse).try_collect().map(BindAddrs)
+    }
+}
+
+impl Display for BindAddrs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, addr) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_char(',')?;
+            }
+            Display::fmt(addr, f)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn parse_bind_addrs() {
+        let addrs = "10.0.0.5, 127.0.0.1,[fd00::1],::1,eth1"
+            .parse::<BindAddrs>()
+            .unwrap();
+
+        assert_eq!(
+            addrs,
+            BindAddrs(vec![
+                BindAddr::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5))),
+                BindAddr::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                BindAddr::Ip(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1))),
+                BindAddr::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+                BindAddr::Interface("eth1".to_owned()),
+            ])
+        );
+
+        assert_eq!(addrs.to_string(), "10.0.0.5,127.0.0.1,[fd00::1],[::1],eth1");
+        assert_eq!(addrs.to_string().parse::<BindAddrs>().unwrap(), addrs);
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert!("".parse::<BindAddrs>().is_err());
+        assert!("10.0.0.1,".parse::<BindAddrs>().is_err());
+        assert!("[eth0]".parse::<BindAddrs>().is_err());
+        assert!("[10.0.0.1".parse::<BindAddrs>().is_err());
+        assert!("eth 0".parse::<BindAddrs>().is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(miri, ignore)]
+    fn resolve_interface() {
+        let addrs = "lo,127.0.0.1".parse::<BindAddrs>().unwrap();
+        let resolved = addrs.resolve().unwrap();
+        assert!(resolved.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert_eq!(resolved.iter().unique().count(), resolved.len());
+
+        assert!(matches!(
+            "hptp-does-not-exist"
+                .parse::<BindAddrs>()
+                .unwrap()
+                .resolve(),
+            Err(BindError::NoSuchInterface(_))
+        ));
+    }
+}
@@ -0,0 +1,620 @@
+use std::fmt::Display;
+use std::io;
+use std::net::SocketAddr;
+use std::num::NonZero;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+
+use crate::acl::{AccessList, Acl, AclFile, AclFileError};
+use crate::cidr::Cidr;
+use crate::cli::bind_addr::{BindAddr, BindAddrs, BindError};
+use crate::cli::ports_array::PortsArray;
+use crate::connect::{ConnectOverrides, Failover};
+use crate::health::{EjectConfig, Health, HealthConfig, ProbeConfig};
+use crate::payload::Payload;
+use crate::proxy_protocol::{self, AcceptProxy, SendProxy, Tlv, TlvParseError};
+use crate::shape::{Bandwidth, Rates, ShapeConfig, Shaper};
+use crate::sock_io::Timeouts;
+use crate::stream;
+use crate::upstream::{Balance, UpstreamHosts, Upstreams};
+use crate::{AllowProtocol, Rule};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("unable to read config file: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid config file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("config file doesn't define any rules")]
+    NoRules,
+    #[error("`{0}` is bound by more than one rule")]
+    DuplicateAddress(SocketAddr),
+    #[error("`{0}` overlaps `{1}`, they can't both be bound")]
+    OverlappingAddress(SocketAddr, SocketAddr),
+    #[error(transparent)]
+    Bind(#[from] BindError),
+    #[error("health check options were given without an `interval` to probe at")]
+    ProbeWithoutInterval,
+    #[error("PROXY protocol TLVs can only be sent with v2 headers")]
+    TlvsWithoutV2,
+    #[error(transparent)]
+    Tlvs(#[from] TlvParseError),
+    #[error(transparent)]
+    Acl(#[from] AclFileError),
+    #[error("`proxy_protocol_timeout` was given without any `accept_proxy_protocol` networks")]
+    TimeoutWithoutTrusted,
+    #[error("`{0}` can only be given with `--runtime multi-threaded`")]
+    NeedsMultiThreaded(&'static str),
+    #[error("unable to open the access log `{0}`: {1}")]
+    AccessLog(PathBuf, io::Error),
+}
+
+/// The on disk representation of a config file
+///
+/// ```toml
+/// [[rule]]
+/// bind = ["0.0.0.0", "::", "eth1"]
+/// ports = "[80, 443]"
+/// host = "example.com"
+///
+/// [[rule]]
+/// ports = "[8080:80]"
+/// host = "10.0.0.1=3, 10.0.0.2"
+/// balance = "least-connections"
+/// idle_timeout = "5m"
+/// max_lifetime = "12h"
+///
+/// [rule.health]
+/// interval = "5s"
+/// send = "PING\r\n"
+/// expect = "+PONG"
+/// eject_after = 3
+///
+/// [rule.connect]
+/// timeout = "2s"
+/// retries = 2
+/// failover = "upstream"
+///
+/// [[rule]]
+/// ports = "[25]"
+/// host = "mail.internal"
+/// accept_proxy_protocol = ["10.0.0.0/8"]
+/// allow = ["10.0.0.0/8"]
+/// deny = ["10.6.6.0/24"]
+/// acl_file = "mail.acl"
+/// send_proxy_protocol = "v2"
+/// proxy_protocol_tlvs = ["0xE0=edge-1"]
+///
+/// [rule.bandwidth]
+/// upload = "1MiB"
+/// download_per_ip = "10MiB"
+/// download_per_listener = "100MB"
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(rename = "rule", default)]
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    #[serde(default = "default_bind", deserialize_with = "from_str_seq")]
+    bind: Vec<BindAddr>,
+    #[serde(deserialize_with = "from_str")]
+    ports: PortsArray,
+    #[serde(deserialize_with = "from_str", alias = "hosts")]
+    host: UpstreamHosts,
+    #[serde(default, deserialize_with = "from_str")]
+    balance: Balance,
+    #[serde(default)]
+    health: HealthRuleConfig,
+    #[serde(default, deserialize_with = "from_str_seq")]
+    accept_proxy_protocol: Vec<Cidr>,
+    #[serde(default, deserialize_with = "duration")]
+    proxy_protocol_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    send_proxy_protocol: Option<proxy_protocol::Version>,
+    #[serde(default, deserialize_with = "from_str_seq")]
+    proxy_protocol_tlvs: Vec<Tlv>,
+    #[serde(default, deserialize_with = "from_str_seq")]
+    allow: Vec<Cidr>,
+    #[serde(default, deserialize_with = "from_str_seq")]
+    deny: Vec<Cidr>,
+    /// relative to the config file
+    acl_file: Option<PathBuf>,
+    #[serde(default)]
+    bandwidth: BandwidthRuleConfig,
+    #[serde(default, deserialize_with = "duration")]
+    idle_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    linger_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    max_lifetime: Option<Duration>,
+    #[serde(default)]
+    connect: ConnectRuleConfig,
+}
+
+/// overrides the global `--connect-*` flags, anything left out is taken from them
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConnectRuleConfig {
+    #[serde(default, deserialize_with = "duration")]
+    happy_eyeballs_delay: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    timeout: Option<Duration>,
+    retries: Option<u32>,
+    #[serde(default, deserialize_with = "duration")]
+    backoff: Option<Duration>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    failover: Option<Failover>,
+}
+
+impl From<ConnectRuleConfig> for ConnectOverrides {
+    fn from(config: ConnectRuleConfig) -> Self {
+        ConnectOverrides {
+            attempt_delay: config.happy_eyeballs_delay,
+            timeout: config.timeout,
+            retries: config.retries,
+            backoff: config.backoff,
+            failover: config.failover,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct BandwidthRuleConfig {
+    #[serde(default, deserialize_with = "from_str_opt")]
+    upload: Option<Bandwidth>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    download: Option<Bandwidth>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    upload_per_ip: Option<Bandwidth>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    download_per_ip: Option<Bandwidth>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    upload_per_listener: Option<Bandwidth>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    download_per_listener: Option<Bandwidth>,
+}
+
+impl From<BandwidthRuleConfig> for ShapeConfig {
+    fn from(config: BandwidthRuleConfig) -> Self {
+        ShapeConfig {
+            upload: Rates {
+                connection: config.upload,
+                ip: config.upload_per_ip,
+                listener: config.upload_per_listener,
+            },
+            download: Rates {
+                connection: config.download,
+                ip: config.download_per_ip,
+                listener: config.download_per_listener,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct HealthRuleConfig {
+    #[serde(default, deserialize_with = "duration")]
+    interval: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    timeout: Option<Duration>,
+    port: Option<NonZero<u16>>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    send: Option<Payload>,
+    #[serde(default, deserialize_with = "from_str_opt")]
+    expect: Option<Payload>,
+    eject_after: Option<NonZero<u32>>,
+    #[serde(default, deserialize_with = "duration")]
+    eject_for: Option<Duration>,
+}
+
+impl TryFrom<HealthRuleConfig> for HealthConfig {
+    type Error = ConfigError;
+
+    fn try_from(config: HealthRuleConfig) -> Result<Self, Self::Error> {
+        let HealthRuleConfig {
+            interval,
+            timeout,
+            port,
+            send,
+            expect,
+            eject_after,
+            eject_for,
+        } = config;
+
+        let probe = match interval {
+            Some(interval) => Some(ProbeConfig {
+                interval,
+                timeout: timeout.unwrap_or(ProbeConfig::DEFAULT_TIMEOUT),
+                port,
+                send: send.unwrap_or_default(),
+                expect: expect.unwrap_or_default(),
+            }),
+            None if timeout.is_some() || port.is_some() || send.is_some() || expect.is_some() => {
+                return Err(ConfigError::ProbeWithoutInterval)
+            }
+            None => None,
+        };
+
+        Ok(HealthConfig {
+            probe,
+            eject: eject_after.map(|after| EjectConfig {
+                after,
+                duration: eject_for.unwrap_or(EjectConfig::DEFAULT_DURATION),
+            }),
+        })
+    }
+}
+
+fn default_bind() -> Vec<BindAddr> {
+    AllowProtocol::Ipv4
+        .bind_addrs()
+        .into_iter()
+        .map(BindAddr::Ip)
+        .collect()
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err: Display>,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(D::Error::custom)
+}
+
+fn from_str_opt<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err: Display>,
+{
+    from_str(deserializer).map(Some)
+}
+
+fn duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    from_str_opt::<_, humantime::Duration>(deserializer).map(|duration| duration.map(Into::into))
+}
+
+fn from_str_seq<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err: Display>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(D::Error::custom))
+        .collect()
+}
+
+pub fn send_proxy(
+    version: Option<proxy_protocol::Version>,
+    tlvs: Vec<Tlv>,
+) -> Result<Option<SendProxy>, ConfigError> {
+    match version {
+        Some(proxy_protocol::Version::V1) if !tlvs.is_empty() => Err(ConfigError::TlvsWithoutV2),
+        Some(version) => Ok(Some(SendProxy::new(version, tlvs)?)),
+        None if !tlvs.is_empty() => Err(ConfigError::TlvsWithoutV2),
+        None => Ok(None),
+    }
+}
+
+pub fn load(path: &Path) -> Result<Vec<Rule>, ConfigError> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    parse(&std::fs::read_to_string(path)?, dir)
+}
+
+fn parse(config: &str, dir: &Path) -> Result<Vec<Rule>, ConfigError> {
+    let Config { rules } = toml::from_str(config)?;
+    if rules.is_empty() {
+        return Err(ConfigError::NoRules);
+    }
+
+    let mut bound = Vec::<SocketAddr>::new();
+    rules
+        .into_iter()
+        .map(|rule| {
+            let RuleConfig {
+                bind,
+                ports,
+                host,
+                balance,
+                health,
+                accept_proxy_protocol,
+                proxy_protocol_timeout,
+                send_proxy_protocol,
+                proxy_protocol_tlvs,
+                allow,
+                deny,
+                acl_file,
+                bandwidth,
+                idle_timeout,
+                linger_timeout,
+                max_lifetime,
+                connect,
+            } = rule;
+
+            let bind = BindAddrs::from(bind).resolve()?;
+            let ports = ports.into_ports_vec();
+            for &ip in &bind {
+                for port in &ports {
+                    let addr = SocketAddr::new(ip, port.listen.get());
+                    match bound.iter().find(|&&other| stream::overlap(other, addr)) {
+                        Some(&other) if other == addr => {
+                            return Err(ConfigError::DuplicateAddress(addr))
+                        }
+                        Some(&other) => return Err(ConfigError::OverlappingAddress(addr, other)),
+                        None => bound.push(addr),
+                    }
+                }
+            }
+
+            Ok(Rule {
+                bind,
+                ports,
+                upstream: Upstreams::new(host, balance),
+                health: Health::new(health.try_into()?),
+                accept_proxy: match (accept_proxy_protocol.is_empty(), proxy_protocol_timeout) {
+                    (true, None) => None,
+                    (true, Some(_)) => return Err(ConfigError::TimeoutWithoutTrusted),
+                    (false, timeout) => Some(AcceptProxy {
+                        trusted: accept_proxy_protocol.into(),
+                        timeout: timeout.unwrap_or(AcceptProxy::DEFAULT_TIMEOUT),
+                    }),
+                },
+                send_proxy: send_proxy(send_proxy_protocol, proxy_protocol_tlvs)?,
+                acl: AccessList {
+                    inline: Acl {
+                        allow: allow.into(),
+                        deny: deny.into(),
+                    },
+                    file: acl_file
+                        .map(|file| AclFile::load(dir.join(file)))
+                        .transpose()?,
+                },
+                shaper: Shaper::new(bandwidth.into()),
+                timeouts: Timeouts {
+                    idle: idle_timeout,
+                    linger: linger_timeout,
+                    lifetime: max_lifetime,
+                },
+                connect: connect.into(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: &str) -> Result<Vec<Rule>, ConfigError> {
+        super::parse(config, Path::new(""))
+    }
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn parse_rule() {
+        let rules = parse(
+            r#"
+            [[rule]]
+            bind = ["127.0.0.1"]
+            ports = "[80, 8080..8082:9080..9082]"
+            host = "192.0.2.1"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].bind, [IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        assert_eq!(
+            rules[0]
+                .ports
+                .iter()
+                .map(|p| p.listen.get())
+                .collect::<Vec<_>>(),
+            [80, 8080, 8081, 8082]
+        );
+        assert_eq!(
+            rules[0]
+                .ports
+                .iter()
+                .map(|p| p.upstream.get())
+                .collect::<Vec<_>>(),
+            [80, 9080, 9081, 9082]
+        );
+        assert_eq!(rules[0].upstream.to_string(), "192.0.2.1");
+    }
+
+    #[test]
+    fn parse_many_rules() {
+        let rules = parse(
+            r#"
+            [[rule]]
+            ports = "[80, 443]"
+            host = "example.com"
+
+            [[rule]]
+            bind = ["127.0.0.1", "::1"]
+            ports = "5432"
+            host = "10.0.0.7=2, 10.0.0.8"
+            balance = "consistent-hash"
+            accept_proxy_protocol = ["10.0.0.0/8", "::1"]
+            proxy_protocol_timeout = "1s"
+            send_proxy_protocol = "v2"
+            proxy_protocol_tlvs = ["0xE0=edge-1", "2=db.internal"]
+            deny = ["10.6.6.0/24"]
+            idle_timeout = "10m"
+            linger_timeout = "30s"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].bind, [IpAddr::V4(Ipv4Addr::UNSPECIFIED)]);
+        assert_eq!(rules[0].upstream.to_string(), "\"example.com\"");
+        assert_eq!(rules[1].bind.len(), 2);
+        assert_eq!(
+            rules[1].upstream.to_string(),
+            "[10.0.0.7=2, 10.0.0.8] (consistent-hash)"
+        );
+        assert!(rules[0].send_proxy.is_none());
+        assert!(rules[0].accept_proxy.is_none());
+        let accept_proxy = rules[1].accept_proxy.as_ref().unwrap();
+        assert!(accept_proxy
+            .trusted
+            .contains(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))));
+        assert_eq!(accept_proxy.timeout, Duration::from_secs(1));
+        assert!(rules[0]
+            .acl
+            .check(IpAddr::V4(Ipv4Addr::new(10, 6, 6, 6)))
+            .is_ok());
+        assert!(rules[1]
+            .acl
+            .check(IpAddr::V4(Ipv4Addr::new(10, 6, 6, 6)))
+            .is_err());
+        assert_eq!(rules[0].timeouts, Timeouts::default());
+        assert_eq!(
+            rules[1].timeouts,
+            Timeouts {
+                idle: Some(Duration::from_secs(600)),
+                linger: Some(Duration::from_secs(30)),
+                lifetime: None,
+            }
+        );
+        assert_eq!(
+            rules[1].send_proxy,
+            Some(
+                SendProxy::new(
+                    proxy_protocol::Version::V2,
+                    vec![
+                        "0xE0=edge-1".parse().unwrap(),
+                        "2=db.internal".parse().unwrap()
+                    ]
+                )
+                .unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn parse_health() {
+        let rules = parse(
+            r#"
+            [[rule]]
+            ports = "6379"
+            host = "192.0.2.1"
+
+            [rule.health]
+            interval = "5s"
+            send = 'PING\r\n'
+            expect = "+PONG"
+            eject_after = 3
+
+            [rule.bandwidth]
+            upload = "1MiB"
+            download_per_ip = "10 MB/s"
+
+            [rule.connect]
+            timeout = "500ms"
+            retries = 2
+            failover = "upstream"
+
+            [[rule]]
+            ports = "80"
+            host = "192.0.2.2"
+            "#,
+        )
+        .unwrap();
+
+        let HealthConfig { probe, eject } = rules[0].health.config();
+        let probe = probe.as_ref().unwrap();
+        assert_eq!(probe.interval, Duration::from_secs(5));
+        assert_eq!(probe.timeout, ProbeConfig::DEFAULT_TIMEOUT);
+        assert_eq!(probe.send, "PING\r\n".parse().unwrap());
+        assert_eq!(probe.expect, "+PONG".parse().unwrap());
+        assert_eq!(eject.unwrap().after.get(), 3);
+        assert_eq!(eject.unwrap().duration, EjectConfig::DEFAULT_DURATION);
+        let ShapeConfig { upload, download } = *rules[0].shaper.config();
+        assert_eq!(upload.connection, "1MiB".parse().ok());
+        assert_eq!(download.ip, "10MB".parse().ok());
+        assert!(upload.ip.is_none() && download.connection.is_none());
+        assert_eq!(
+            rules[0].connect,
+            ConnectOverrides {
+                timeout: Some(Duration::from_millis(500)),
+                retries: Some(2),
+                failover: Some(Failover::Upstream),
+                ..ConnectOverrides::default()
+            }
+        );
+        assert_eq!(rules[1].connect, ConnectOverrides::default());
+
+        assert!(rules[1].health.config().probe.is_none());
+        assert!(rules[1].health.config().eject.is_none());
+        assert_eq!(*rules[1].shaper.config(), ShapeConfig::default());
+
+        assert!(matches!(
+            parse(
+                "[[rule]]\nports = \"80\"\nhost = \"192.0.2.1\"\n\
+                 send_proxy_protocol = \"v1\"\nproxy_protocol_tlvs = [\"1=a\"]"
+            ),
+            Err(ConfigError::TlvsWithoutV2)
+        ));
+        assert!(matches!(
+            parse("[[rule]]\nports = \"80\"\nhost = \"192.0.2.1\"\n[rule.health]\nexpect = \"OK\""),
+            Err(ConfigError::ProbeWithoutInterval)
+        ));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert!(matches!(parse(""), Err(ConfigError::NoRules)));
+        assert!(matches!(
+            parse("[[rule]]\nports = \"[80]\""),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            parse("[[rule]]\nports = \"[abc]\"\nhost = \"192.0.2.1\""),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            parse("[[rule]]\nports = \"[80]\"\nhost = \"192.0.2.1\"\nfoo = 1"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            parse(
+                "[[rule]]\nports = \"[80, 81]\"\nhost = \"192.0.2.1\"\n\
+                 [[rule]]\nports = \"[81]\"\nhost = \"192.0.2.2\""
+            ),
+            Err(ConfigError::DuplicateAddress(addr)) if addr.port() == 81
+        ));
+        assert!(matches!(
+            parse(
+                "[[rule]]\nports = \"[80]\"\nhost = \"192.0.2.1\"\nbind = [\"0.0.0.0\"]\n\
+                 [[rule]]\nports = \"[80]\"\nhost = \"192.0.2.2\"\nbind = [\"127.0.0.1\"]"
+            ),
+            Err(ConfigError::OverlappingAddress(addr, other))
+                if addr == SocketAddr::from(([127, 0, 0, 1], 80))
+                    && other == SocketAddr::from(([0, 0, 0, 0], 80))
+        ));
+        assert!(parse(
+            "[[rule]]\nports = \"[80]\"\nhost = \"192.0.2.1\"\nbind = [\"0.0.0.0\"]\n\
+             [[rule]]\nports = \"[80]\"\nhost = \"192.0.2.2\"\nbind = [\"::\"]"
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,362 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+use crate::admin::LogFilter;
+
+pub const fn default_log_level() -> LevelFilter {
+    const {
+        match cfg!(debug_assertions) {
+            true => LevelFilter::TRACE,
+            false => LevelFilter::WARN,
+        }
+    }
+}
+
+arg_enum! {
+    #[error_message("invalid log format, expected either 'compact', 'full' or 'json'")]
+    #[derive(Default, PartialEq, Eq)]
+    pub enum LogFormat {
+        #[default]
+        Compact = "compact",
+        Full = "full",
+        Json = "json"
+    }
+}
+
+arg_enum! {
+    #[error_message("invalid log output, expected either 'stdout', 'journald' or 'syslog'")]
+    #[derive(Default, PartialEq, Eq)]
+    pub enum LogOutput {
+        #[default]
+        Stdout = "stdout",
+        Journald = "journald",
+        Syslog = "syslog"
+    }
+}
+
+fn parse_filter(s: &str) -> Result<String, String> {
+    EnvFilter::try_new(s)
+        .map(|_| s.to_owned())
+        .map_err(|err| format!("invalid log filter `{s}`: {err}"))
+}
+
+#[derive(clap::Args)]
+pub struct LogArgs {
+    #[clap(long, default_value_t = default_log_level())]
+    pub log: LevelFilter,
+    #[clap(
+        long,
+        value_name = "per module levels overriding --log, e.g. info,hptp::health=debug",
+        value_parser = parse_filter
+    )]
+    log_filter: Option<String>,
+    #[clap(long, value_name = "compact, full or json", default_value_t = LogFormat::default())]
+    log_format: LogFormat,
+    #[clap(
+        long,
+        value_name = "stdout, journald (linux), or syslog over /dev/log (unix)",
+        default_value_t = LogOutput::default()
+    )]
+    log_output: LogOutput,
+    #[clap(
+        long,
+        value_name = "append the logs to this file instead of writing them to stdout",
+        conflicts_with = "log_output"
+    )]
+    log_file: Option<PathBuf>,
+}
+
+/// A syslog message a log line at a time, see RFC 5424
+#[cfg(unix)]
+struct Syslog {
+    socket: std::os::unix::net::UnixDatagram,
+    hostname: String,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    /// where the syslog daemon listens on linux, and on macos
+    const SOCKETS: [&str; 2] = ["/dev/log", "/var/run/syslog"];
+    /// the `daemon` facility
+    const FACILITY: u8 = 3;
+
+    fn severity(level: tracing::Level) -> u8 {
+        match level {
+            tracing::Level::ERROR => 3,
+            tracing::Level::WARN => 4,
+            tracing::Level::INFO => 6,
+            tracing::Level::DEBUG | tracing::Level::TRACE => 7,
+        }
+    }
+
+    fn connect() -> io::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        let mut res = Ok(());
+        for path in Self::SOCKETS {
+            res = socket.connect(path);
+            if res.is_ok() {
+                break;
+            }
+        }
+        res.map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("unable to reach the syslog daemon: {err}"),
+            )
+        })?;
+
+        let hostname = nix::unistd::gethostname()
+            .ok()
+            .and_then(|name| name.into_string().ok())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "-".to_owned());
+        Ok(Syslog { socket, hostname })
+    }
+}
+
+/// Buffers one formatted event, and sends it once it's dropped
+#[cfg(unix)]
+struct SyslogMessage<'a> {
+    syslog: &'a Syslog,
+    severity: u8,
+    line: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl io::Write for SyslogMessage<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SyslogMessage<'_> {
+    fn drop(&mut self) {
+        let line = self.line.trim_ascii_end();
+        if line.is_empty() {
+            return;
+        }
+
+        let header = format!(
+            "<{}>1 {} {} hptp {} - - ",
+            Syslog::FACILITY * 8 + self.severity,
+            humantime::format_rfc3339_micros(std::time::SystemTime::now()),
+            self.syslog.hostname,
+            std::process::id(),
+        );
+        let mut message = header.into_bytes();
+        message.extend_from_slice(line);
+        // nowhere to report it, the logs are what failed
+        let _ = self.syslog.socket.send(&message);
+    }
+}
+
+#[cfg(unix)]
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogMessage<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogMessage {
+            syslog: self,
+            // informational, the level of the event is unknown
+            severity: 6,
+            line: vec![],
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &tracing::Metadata<'_>) -> Self::Writer {
+        let mut writer = self.make_writer();
+        writer.severity = Syslog::severity(*meta.level());
+        writer
+    }
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// a layer formatting events as `format` says, and writing them with `writer`,
+/// without `header` the time and level of events are left out, for outputs that record them
+fn fmt_layer<W>(format: LogFormat, writer: W, ansi: bool, header: bool) -> BoxedLayer
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_target(false)
+        .with_level(header);
+
+    match (format, header) {
+        (LogFormat::Compact, true) => layer.compact().boxed(),
+        (LogFormat::Compact, false) => layer.compact().without_time().boxed(),
+        (LogFormat::Full, true) => layer.boxed(),
+        (LogFormat::Full, false) => layer.without_time().boxed(),
+        (LogFormat::Json, true) => layer.json().boxed(),
+        (LogFormat::Json, false) => layer.json().without_time().boxed(),
+    }
+}
+
+impl LogArgs {
+    fn default_output(&self) -> bool {
+        self.log_output == LogOutput::default() && self.log_file.is_none()
+    }
+
+    /// logs through `output` instead of stdout, unless another output was picked,
+    /// a daemon's stdout usually goes nowhere
+    pub fn default_to(&mut self, output: LogOutput) {
+        if self.default_output() {
+            self.log_output = output
+        }
+    }
+
+    /// like [`LogArgs::default_to`], but appending to the file at `path`
+    #[cfg(any(windows, target_os = "macos"))]
+    pub fn default_to_file(&mut self, path: impl Into<PathBuf>) {
+        if self.default_output() {
+            self.log_file = Some(path.into())
+        }
+    }
+
+    /// every non default flag, to hand them to a daemon
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(filter) = &self.log_filter {
+            args.extend(["--log-filter".to_owned(), filter.clone()]);
+        }
+        if self.log_format != LogFormat::default() {
+            args.extend(["--log-format".to_owned(), self.log_format.to_string()]);
+        }
+        if self.log_output != LogOutput::default() {
+            args.extend(["--log-output".to_owned(), self.log_output.to_string()]);
+        }
+        if let Some(file) = &self.log_file {
+            let file = std::path::absolute(file).unwrap_or_else(|_| file.clone());
+            args.extend(["--log-file".to_owned(), file.display().to_string()]);
+        }
+        args.extend(["--log".to_owned(), self.log.to_string()]);
+        args
+    }
+
+    fn layer(&self) -> io::Result<BoxedLayer> {
+        if let Some(path) = &self.log_file {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            return Ok(fmt_layer(self.log_format, Arc::new(file), false, true));
+        }
+
+        match self.log_output {
+            LogOutput::Stdout => Ok(fmt_layer(
+                self.log_format,
+                io::stdout,
+                io::stdout().is_terminal(),
+                true,
+            )),
+            #[cfg(target_os = "linux")]
+            LogOutput::Journald => Ok(tracing_journald::layer()?
+                .with_syslog_identifier("hptp".to_owned())
+                .boxed()),
+            // syslog records the time and severity itself
+            #[cfg(unix)]
+            LogOutput::Syslog => Ok(fmt_layer(self.log_format, Syslog::connect()?, false, false)),
+            #[allow(unreachable_patterns)]
+            output => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("logging to {output} isn't supported on this platform"),
+            )),
+        }
+    }
+
+    /// returns what changes the filter later on
+    pub fn init(&self) -> io::Result<FilterHandle> {
+        let filter = match &self.log_filter {
+            Some(filter) => EnvFilter::new(filter),
+            None => EnvFilter::new(self.log.to_string()),
+        };
+
+        let (filter, handle) = reload::Layer::new(filter);
+        tracing_subscriber::registry()
+            .with(self.layer()?.with_filter(filter))
+            .init();
+        Ok(FilterHandle(handle))
+    }
+}
+
+/// Changes the filter of the logs set up by [`LogArgs::init`], for the admin api
+pub struct FilterHandle(reload::Handle<EnvFilter, Registry>);
+
+impl LogFilter for FilterHandle {
+    fn current(&self) -> String {
+        self.0
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+
+    fn set(&self, directives: &str) -> Result<(), Box<dyn Error>> {
+        let filter = EnvFilter::try_new(directives)?;
+        Ok(self.0.reload(filter)?)
+    }
+}
+
+impl Display for LogArgs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.log_filter {
+            Some(filter) => write!(f, "filter is {filter}")?,
+            None => write!(f, "level is {}", self.log)?,
+        }
+        match &self.log_file {
+            Some(path) => write!(f, ", to `{}`", path.display()),
+            None => write!(f, ", to {}", self.log_output),
+        }
+    }
+}
+
+/// logs to stdout at `level`, for the subcommands that don't run the proxy
+pub fn init_stdout(level: LevelFilter) {
+    tracing_subscriber::fmt()
+        .with_ansi(io::stdout().is_terminal())
+        .with_max_level(level)
+        .with_target(false)
+        .compact()
+        .init()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn syslog_message() {
+        let (socket, peer) = UnixDatagram::pair().unwrap();
+        let syslog = Syslog {
+            socket,
+            hostname: "box".to_owned(),
+        };
+
+        let mut writer = syslog.make_writer();
+        writer.severity = Syslog::severity(tracing::Level::WARN);
+        writer.write_all(b"upstream down\n").unwrap();
+        drop(writer);
+
+        let mut buf = [0; 1024];
+        let len = peer.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(message.starts_with("<28>1 "), "{message}");
+        let tail = format!(" box hptp {} - - upstream down", std::process::id());
+        assert!(message.ends_with(&tail), "{message}");
+    }
+}
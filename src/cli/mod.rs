@@ -1,18 +1,42 @@
 use clap::Parser;
 use std::borrow::Cow;
-use std::fmt::{Display, Formatter};
-use std::io;
-use std::io::IsTerminal;
-use std::str::FromStr;
-use tracing::level_filters::LevelFilter;
+use std::fmt::{Display, Formatter, Write};
+use std::net::SocketAddr;
+use std::num::NonZero;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 
+use crate::access_log::{self, AccessLog, Rotation};
+use crate::acl::{AccessList, Acl, AclFile};
+use crate::admin::Admin;
+use crate::cidr::Cidrs;
+use crate::cli::bind_addr::BindAddrs;
+use crate::cli::config::ConfigError;
+use crate::cli::logging::{default_log_level, LogArgs};
 use crate::cli::ports_array::PortsArray;
-use crate::host::Host;
-use crate::{real_main, AllowProtocol, ProgramArgs};
+use crate::connect::{ConnectConfig, ConnectOverrides, Failover};
+use crate::health::{EjectConfig, Health, HealthConfig, ProbeConfig};
+use crate::limit::{LimitConfig, Limiter, Limits};
+use crate::payload::Payload;
+use crate::proxy_protocol::{self, AcceptProxy, Tlv};
+use crate::reload::ConfigFile;
+use crate::shape::{self, Bandwidth, Rates, ShapeConfig, Shaper};
+use crate::shutdown::{self, Sessions};
+use crate::sock_io::Timeouts;
+use crate::upstream::{Balance, UpstreamHosts, Upstreams};
+use crate::worker::ThreadConfig;
+use crate::{block_on, log_rules, real_main, AllowProtocol, Global, ProgramArgs, Rule};
 
+mod bind_addr;
+mod config;
 mod ports_array;
 mod startup;
 
+/// [`shutdown::DEFAULT_DRAIN_TIMEOUT`] the way `--drain-timeout` takes it
+static DEFAULT_DRAIN_TIMEOUT: LazyLock<String> =
+    LazyLock::new(|| humantime::format_duration(shutdown::DEFAULT_DRAIN_TIMEOUT).to_string());
+
 #[derive(Parser)]
 #[command(name = "hptp")]
 #[command(version = env!("CARGO_PKG_VERSION"))]
@@ -25,6 +49,9 @@ enum CliArgs {
         #[clap(value_name = "the daemon to use for startup example")]
         #[cfg_attr(any(windows, target_os = "macos"), clap(default_value_t = Daemon::default()))]
         daemon: Daemon,
+        /// have systemd bind the listening addresses from a .socket unit, and run hptp as an unprivileged user
+        #[clap(long)]
+        socket_activation: bool,
         #[clap(flatten)]
         args: RunArgs,
     },
@@ -35,27 +62,473 @@ enum CliArgs {
     },
 }
 
-const fn default_log_level() -> LevelFilter {
-    const {
-        match cfg!(debug_assertions) {
-            true => LevelFilter::TRACE,
-            false => LevelFilter::WARN,
-        }
-    }
-}
-
 #[derive(Parser)]
 struct RunArgs {
-    #[clap(long, value_name = "the host this proxy shall forward to")]
-    host: Host,
-    #[clap(long, value_name = r"the port\s this proxy shall forward")]
-    ports: PortsArray,
+    #[clap(
+        long,
+        value_name = "the host\\s this proxy shall forward to",
+        required_unless_present = "config"
+    )]
+    host: Option<UpstreamHosts>,
+    #[clap(
+        long,
+        value_name = "how to pick an upstream host for every connection",
+        default_value_t = Balance::default()
+    )]
+    balance: Balance,
+    #[clap(
+        long,
+        value_name = r"the port\s this proxy shall forward",
+        required_unless_present = "config"
+    )]
+    ports: Option<PortsArray>,
     #[clap(long, alias = "v4")]
     ipv4: bool,
     #[clap(long, alias = "v6")]
     ipv6: bool,
-    #[clap(long, default_value_t = default_log_level())]
-    log: LevelFilter,
+    #[clap(
+        long,
+        value_name = "the addresses or network interfaces to listen on",
+        conflicts_with_all = ["ipv4", "ipv6"]
+    )]
+    bind: Option<BindAddrs>,
+    #[clap(
+        long,
+        value_name = "a config file describing the forwarding rules",
+        conflicts_with_all = [
+            "host",
+            "balance",
+            "ports",
+            "ipv4",
+            "ipv6",
+            "bind",
+            "health_check_interval",
+            "eject_after",
+            "send_proxy_protocol",
+            "accept_proxy_protocol",
+            "upload_limit",
+            "download_limit",
+            "upload_limit_per_ip",
+            "download_limit_per_ip",
+            "upload_limit_per_listener",
+            "download_limit_per_listener",
+            "idle_timeout",
+            "linger_timeout",
+            "max_lifetime",
+        ]
+    )]
+    config: Option<PathBuf>,
+    /// reload the config file whenever it changes, on top of on SIGHUP
+    #[clap(long, requires = "config")]
+    watch_config: bool,
+    #[clap(flatten)]
+    connect: ConnectArgs,
+    #[clap(flatten)]
+    health: HealthArgs,
+    #[clap(
+        long,
+        value_name = "send the client's address to the upstream in a PROXY protocol header, either v1 or v2"
+    )]
+    send_proxy_protocol: Option<proxy_protocol::Version>,
+    #[clap(
+        long,
+        value_name = "a <type>=<value> TLV to add to v2 PROXY protocol headers, can be repeated",
+        requires = "send_proxy_protocol"
+    )]
+    proxy_protocol_tlv: Vec<Tlv>,
+    #[clap(
+        long,
+        value_name = "the networks trusted to start connections with a PROXY protocol header, e.g. 10.0.0.0/8,::1"
+    )]
+    accept_proxy_protocol: Option<Cidrs>,
+    #[clap(
+        long,
+        value_name = "how long a trusted peer has to send its PROXY protocol header",
+        default_value = "3s",
+        value_parser = humantime::parse_duration,
+        requires = "accept_proxy_protocol"
+    )]
+    proxy_protocol_timeout: Duration,
+    #[clap(
+        long,
+        value_name = "only accept clients from these comma separated networks, applies to every rule"
+    )]
+    allow: Option<Cidrs>,
+    #[clap(
+        long,
+        value_name = "reject clients from these comma separated networks, applies to every rule"
+    )]
+    deny: Option<Cidrs>,
+    #[clap(
+        long,
+        value_name = "a file of `allow <network>` and `deny <network>` lines, reloaded when it changes"
+    )]
+    acl_file: Option<PathBuf>,
+    #[clap(flatten)]
+    limits: LimitArgs,
+    #[clap(flatten)]
+    bandwidth: BandwidthArgs,
+    #[clap(
+        long,
+        value_name = "close connections that sent nothing either way for this long",
+        value_parser = humantime::parse_duration
+    )]
+    idle_timeout: Option<Duration>,
+    #[clap(
+        long,
+        value_name = "close connections this long after one side shut down its half, if the other didn't follow",
+        value_parser = humantime::parse_duration
+    )]
+    linger_timeout: Option<Duration>,
+    #[clap(
+        long,
+        value_name = "close connections that have been open for this long",
+        value_parser = humantime::parse_duration
+    )]
+    max_lifetime: Option<Duration>,
+    #[clap(
+        long,
+        value_name = "how long to wait for open connections to finish on SIGTERM or SIGINT before exiting",
+        default_value = DEFAULT_DRAIN_TIMEOUT.as_str(),
+        value_parser = humantime::parse_duration
+    )]
+    drain_timeout: Duration,
+    #[clap(
+        long,
+        value_name = "serve prometheus metrics over http on this address, e.g. 127.0.0.1:9100"
+    )]
+    metrics: Option<SocketAddr>,
+    #[clap(
+        long,
+        value_name = "serve the admin api over http on this loopback address, e.g. 127.0.0.1:9180",
+        value_parser = parse_admin_addr
+    )]
+    admin: Option<SocketAddr>,
+    #[clap(flatten)]
+    access_log: AccessLogArgs,
+    #[cfg(unix)]
+    #[clap(
+        long,
+        value_name = "a unix socket to take the listeners over from a running hptp, and to hand them over to the next"
+    )]
+    upgrade_socket: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "single-threaded, or multi-threaded to handle connections on every core",
+        default_value_t = RuntimeType::SingleThread
+    )]
+    runtime: RuntimeType,
+    #[clap(
+        long,
+        value_name = "how many threads the multi-threaded runtime uses, defaults to the number of cores"
+    )]
+    threads: Option<NonZero<usize>>,
+    /// every thread accepts connections on listeners of its own, bound with SO_REUSEPORT,
+    /// the kernel spreads the connections between them
+    #[clap(long)]
+    reuse_port: bool,
+    /// pin every thread to a core of its own
+    #[clap(long)]
+    pin_threads: bool,
+    #[clap(flatten)]
+    logging: LogArgs,
+}
+
+#[derive(clap::Args)]
+struct AccessLogArgs {
+    #[clap(
+        long,
+        value_name = "write a record of every session that ended to this file, or - for stdout"
+    )]
+    access_log: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "json or logfmt",
+        default_value_t = access_log::Format::default(),
+        requires = "access_log"
+    )]
+    access_log_format: access_log::Format,
+    #[clap(
+        long,
+        value_name = "move the access log aside once it grows past this size, e.g. 100MiB",
+        value_parser = parse_size,
+        requires = "access_log"
+    )]
+    access_log_max_size: Option<NonZero<u64>>,
+    #[clap(
+        long,
+        value_name = "move the access log aside once it has been written to for this long, e.g. 1day",
+        value_parser = humantime::parse_duration,
+        requires = "access_log"
+    )]
+    access_log_rotate: Option<Duration>,
+    #[clap(
+        long,
+        value_name = "how many rotated access logs to keep",
+        default_value_t = Rotation::DEFAULT_KEEP,
+        requires = "access_log"
+    )]
+    access_log_keep: usize,
+}
+
+fn parse_admin_addr(s: &str) -> Result<SocketAddr, String> {
+    let addr = s.parse::<SocketAddr>().map_err(|err| err.to_string())?;
+    match addr.ip().is_loopback() {
+        true => Ok(addr),
+        false => Err(format!(
+            "`{addr}` isn't a loopback address, the admin api has no authentication"
+        )),
+    }
+}
+
+fn parse_size(s: &str) -> Result<NonZero<u64>, String> {
+    shape::parse_bytes(s)
+        .ok_or_else(|| format!("invalid size `{s}`, expected bytes like 512KiB, 10MB or 1GiB"))
+}
+
+impl AccessLogArgs {
+    fn open(&self) -> Result<Option<AccessLog>, ConfigError> {
+        let Some(path) = &self.access_log else {
+            return Ok(None);
+        };
+
+        let rotation = Rotation {
+            max_size: self.access_log_max_size,
+            every: self.access_log_rotate,
+            keep: self.access_log_keep,
+        };
+        AccessLog::open(path, self.access_log_format, rotation)
+            .map(Some)
+            .map_err(|err| ConfigError::AccessLog(path.clone(), err))
+    }
+}
+
+#[derive(clap::Args)]
+struct ConnectArgs {
+    #[clap(
+        long,
+        value_name = "how long to wait on an upstream connection attempt before also trying the next address",
+        default_value = "250ms",
+        value_parser = humantime::parse_duration
+    )]
+    happy_eyeballs_delay: Duration,
+    #[clap(
+        long,
+        value_name = "how long connecting to an upstream host may take, resolving it and trying all of its addresses",
+        default_value = "15s",
+        value_parser = humantime::parse_duration
+    )]
+    connect_timeout: Duration,
+    #[clap(
+        long,
+        value_name = "how many more times to try connecting upstream once it failed",
+        default_value_t = 0
+    )]
+    connect_retries: u32,
+    #[clap(
+        long,
+        value_name = "how long to wait before the first retry, doubled for every retry after it",
+        default_value = "100ms",
+        value_parser = humantime::parse_duration
+    )]
+    connect_backoff: Duration,
+    #[clap(
+        long,
+        value_name = "what to try once connecting failed, none, the next address of the host, or the next upstream host",
+        default_value_t = Failover::default()
+    )]
+    failover: Failover,
+}
+
+impl ConnectArgs {
+    fn config(&self) -> ConnectConfig {
+        ConnectConfig {
+            attempt_delay: self.happy_eyeballs_delay,
+            timeout: self.connect_timeout,
+            retries: self.connect_retries,
+            backoff: self.connect_backoff,
+            failover: self.failover,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct HealthArgs {
+    #[clap(
+        long,
+        value_name = "how often to probe every upstream address, probing is off if absent",
+        value_parser = humantime::parse_duration
+    )]
+    health_check_interval: Option<Duration>,
+    #[clap(
+        long,
+        value_name = "how long a probe may take before the address is considered unhealthy",
+        default_value = "2s",
+        value_parser = humantime::parse_duration,
+        requires = "health_check_interval"
+    )]
+    health_check_timeout: Duration,
+    #[clap(
+        long,
+        value_name = "the upstream port to probe, defaults to the first upstream port",
+        requires = "health_check_interval"
+    )]
+    health_check_port: Option<NonZero<u16>>,
+    #[clap(
+        long,
+        value_name = r"bytes to send once a probe connects, \r \n \xHH escapes are allowed",
+        requires = "health_check_interval"
+    )]
+    health_check_send: Option<Payload>,
+    #[clap(
+        long,
+        value_name = "bytes the probed upstream has to reply with",
+        requires = "health_check_interval"
+    )]
+    health_check_expect: Option<Payload>,
+    #[clap(
+        long,
+        value_name = "eject an upstream address after this many failed connections in a row"
+    )]
+    eject_after: Option<NonZero<u32>>,
+    #[clap(
+        long,
+        value_name = "how long an ejected upstream address is skipped for",
+        default_value = "30s",
+        value_parser = humantime::parse_duration,
+        requires = "eject_after"
+    )]
+    eject_for: Duration,
+}
+
+#[derive(clap::Args)]
+struct LimitArgs {
+    #[clap(
+        long,
+        value_name = "the most connections open at once, new ones wait for a free slot"
+    )]
+    max_connections: Option<NonZero<usize>>,
+    #[clap(
+        long,
+        value_name = "the most connections a single client ip can have open"
+    )]
+    max_connections_per_ip: Option<NonZero<u32>>,
+    #[clap(
+        long,
+        value_name = "the most new connections a second from a single client ip"
+    )]
+    max_rate_per_ip: Option<NonZero<u32>>,
+    #[clap(
+        long,
+        value_name = "the most connections a client network can have open"
+    )]
+    max_connections_per_prefix: Option<NonZero<u32>>,
+    #[clap(
+        long,
+        value_name = "the most new connections a second from a client network"
+    )]
+    max_rate_per_prefix: Option<NonZero<u32>>,
+    #[clap(
+        long,
+        value_name = "the prefix length ipv4 client networks are grouped by",
+        default_value_t = LimitConfig::DEFAULT_PREFIX_V4,
+        value_parser = clap::value_parser!(u8).range(0..=32)
+    )]
+    limit_prefix_v4: u8,
+    #[clap(
+        long,
+        value_name = "the prefix length ipv6 client networks are grouped by",
+        default_value_t = LimitConfig::DEFAULT_PREFIX_V6,
+        value_parser = clap::value_parser!(u8).range(0..=128)
+    )]
+    limit_prefix_v6: u8,
+}
+
+#[derive(clap::Args)]
+struct BandwidthArgs {
+    #[clap(
+        long,
+        value_name = "the most bytes a second every connection can send upstream, e.g. 512KiB or 10MB"
+    )]
+    upload_limit: Option<Bandwidth>,
+    #[clap(
+        long,
+        value_name = "the most bytes a second every connection can receive from upstream"
+    )]
+    download_limit: Option<Bandwidth>,
+    #[clap(
+        long,
+        value_name = "the most bytes a second a client ip can send upstream across its connections"
+    )]
+    upload_limit_per_ip: Option<Bandwidth>,
+    #[clap(
+        long,
+        value_name = "the most bytes a second a client ip can receive across its connections"
+    )]
+    download_limit_per_ip: Option<Bandwidth>,
+    #[clap(
+        long,
+        value_name = "the most bytes a second sent upstream through each listening address"
+    )]
+    upload_limit_per_listener: Option<Bandwidth>,
+    #[clap(
+        long,
+        value_name = "the most bytes a second received through each listening address"
+    )]
+    download_limit_per_listener: Option<Bandwidth>,
+}
+
+impl BandwidthArgs {
+    fn config(&self) -> ShapeConfig {
+        ShapeConfig {
+            upload: Rates {
+                connection: self.upload_limit,
+                ip: self.upload_limit_per_ip,
+                listener: self.upload_limit_per_listener,
+            },
+            download: Rates {
+                connection: self.download_limit,
+                ip: self.download_limit_per_ip,
+                listener: self.download_limit_per_listener,
+            },
+        }
+    }
+}
+
+impl LimitArgs {
+    fn config(&self) -> LimitConfig {
+        LimitConfig {
+            per_ip: Limits {
+                connections: self.max_connections_per_ip,
+                rate: self.max_rate_per_ip,
+            },
+            per_prefix: Limits {
+                connections: self.max_connections_per_prefix,
+                rate: self.max_rate_per_prefix,
+            },
+            prefix_v4: self.limit_prefix_v4,
+            prefix_v6: self.limit_prefix_v6,
+            max_connections: self.max_connections,
+        }
+    }
+}
+
+impl HealthArgs {
+    fn config(&self) -> HealthConfig {
+        HealthConfig {
+            probe: self.health_check_interval.map(|interval| ProbeConfig {
+                interval,
+                timeout: self.health_check_timeout,
+                port: self.health_check_port,
+                send: self.health_check_send.clone().unwrap_or_default(),
+                expect: self.health_check_expect.clone().unwrap_or_default(),
+            }),
+            eject: self.eject_after.map(|after| EjectConfig {
+                after,
+                duration: self.eject_for,
+            }),
+        }
+    }
 }
 
 impl RunArgs {
@@ -66,27 +539,110 @@ impl RunArgs {
             (true, true) => AllowProtocol::Both,
         }
     }
-}
 
-impl RunArgs {
-    #[cfg_attr(target_os = "linux", expect(dead_code))]
-    pub fn args(&self) -> impl Iterator<Item = Cow<'static, str>> + Clone {
-        let mut allow_args = ["--v4", "--v6"].into_iter();
-        match self.allow_protocol() {
-            AllowProtocol::Ipv4 => {
-                // skip "--v6"
-                let _ = allow_args.next_back();
+    fn threads(&self) -> Result<ThreadConfig, ConfigError> {
+        let threads = match (self.runtime, self.threads) {
+            (RuntimeType::SingleThread, None) => NonZero::<usize>::MIN,
+            (RuntimeType::SingleThread, Some(_)) => {
+                return Err(ConfigError::NeedsMultiThreaded("--threads"))
             }
-            AllowProtocol::Ipv6 => {
-                // skip "--v4"
-                let _ = allow_args.next();
+            (RuntimeType::MultiThreaded, Some(threads)) => threads,
+            (RuntimeType::MultiThreaded, None) => {
+                std::thread::available_parallelism().unwrap_or(NonZero::<usize>::MIN)
+            }
+        };
+
+        if let RuntimeType::SingleThread = self.runtime {
+            for (flag, given) in [
+                ("--reuse-port", self.reuse_port),
+                ("--pin-threads", self.pin_threads),
+            ] {
+                if given {
+                    return Err(ConfigError::NeedsMultiThreaded(flag));
+                }
             }
-            _ => {}
+        }
+
+        Ok(ThreadConfig {
+            threads,
+            reuse_port: self.reuse_port,
+            pin: self.pin_threads,
+        })
+    }
+
+    /// how many seconds a service manager should wait for the daemon to drain before killing it
+    fn stop_timeout(&self) -> u64 {
+        self.drain_timeout.as_secs() + 5
+    }
+
+    fn global(&self) -> Result<Global, ConfigError> {
+        Ok(Global {
+            connect: self.connect.config(),
+            acl: AccessList {
+                inline: Acl {
+                    allow: self.allow.clone().unwrap_or_default(),
+                    deny: self.deny.clone().unwrap_or_default(),
+                },
+                file: self.acl_file.clone().map(AclFile::load).transpose()?,
+            },
+            limiter: Arc::new(Limiter::new(self.limits.config())),
+            sessions: Sessions::default(),
+            drain_timeout: self.drain_timeout,
+            access_log: self.access_log.open()?,
+        })
+    }
+
+    fn rules(&self) -> Result<Vec<Rule>, ConfigError> {
+        if let Some(path) = &self.config {
+            return config::load(path);
+        }
+
+        let (Some(hosts), Some(ports)) = (&self.host, &self.ports) else {
+            unreachable!("clap requires --host and --ports when --config is absent")
+        };
+
+        let bind = match &self.bind {
+            Some(bind) => bind.resolve()?,
+            None => self.allow_protocol().bind_addrs(),
         };
 
+        Ok(vec![Rule {
+            bind,
+            ports: ports.clone().into_ports_vec(),
+            upstream: Upstreams::new(hosts.clone(), self.balance),
+            health: Health::new(self.health.config()),
+            accept_proxy: self
+                .accept_proxy_protocol
+                .clone()
+                .map(|trusted| AcceptProxy {
+                    trusted,
+                    timeout: self.proxy_protocol_timeout,
+                }),
+            send_proxy: config::send_proxy(
+                self.send_proxy_protocol,
+                self.proxy_protocol_tlv.clone(),
+            )?,
+            // --allow and --deny apply to every rule, so they're part of the global list
+            acl: AccessList::default(),
+            shaper: Shaper::new(self.bandwidth.config()),
+            timeouts: Timeouts {
+                idle: self.idle_timeout,
+                linger: self.linger_timeout,
+                lifetime: self.max_lifetime,
+            },
+            // the --connect-* flags apply to every rule, so they're part of the global settings
+            connect: ConnectOverrides::default(),
+        }])
+    }
+}
+
+impl RunArgs {
+    pub fn args(&self) -> impl Iterator<Item = Cow<'static, str>> + Clone {
+        let mut args = vec![];
+
         macro_rules! kwargs {
             ($($kw: literal, ($arg: expr) $({$to_str: ident})?),+ $(,)?) => {
-                [$(Cow::Borrowed($kw), Cow::Owned(kwargs!(@resolve-to-str $arg $(, $to_str)?))),+]
+                args.extend([$(Cow::Borrowed($kw), Cow::Owned(kwargs!(@resolve-to-str $arg $(, $to_str)?))),+])
             };
             (@resolve-to-str $arg: expr) => {
                 $arg.to_string()
@@ -96,55 +652,262 @@ impl RunArgs {
             };
         }
 
-        allow_args.map(Cow::Borrowed).chain(kwargs!(
-            "--host", (self.host) {as_string},
-            "--ports", (self.ports),
-            "--log", (self.log),
-        ))
+        match (&self.config, &self.host, &self.ports) {
+            (Some(config), _, _) => {
+                // the daemon doesn't run from our working directory
+                let config = std::path::absolute(config).unwrap_or_else(|_| config.clone());
+                kwargs!("--config", (config.display()));
+                if self.watch_config {
+                    args.push(Cow::Borrowed("--watch-config"))
+                }
+            }
+            (None, Some(host), Some(ports)) => {
+                match (&self.bind, self.allow_protocol()) {
+                    (Some(bind), _) => kwargs!("--bind", (bind)),
+                    (None, AllowProtocol::Ipv4) => args.push(Cow::Borrowed("--v4")),
+                    (None, AllowProtocol::Ipv6) => args.push(Cow::Borrowed("--v6")),
+                    (None, AllowProtocol::Both) => args.extend(["--v4", "--v6"].map(Cow::Borrowed)),
+                }
+
+                kwargs!("--host", (host), "--ports", (ports),);
+
+                if self.balance != Balance::default() {
+                    kwargs!("--balance", (self.balance))
+                }
+
+                let HealthConfig { probe, eject } = self.health.config();
+                if let Some(probe) = probe {
+                    kwargs!(
+                        "--health-check-interval",
+                        (humantime::format_duration(probe.interval))
+                    );
+                    if probe.timeout != ProbeConfig::DEFAULT_TIMEOUT {
+                        kwargs!(
+                            "--health-check-timeout",
+                            (humantime::format_duration(probe.timeout))
+                        )
+                    }
+                    if let Some(port) = probe.port {
+                        kwargs!("--health-check-port", (port))
+                    }
+                    if !probe.send.is_empty() {
+                        kwargs!("--health-check-send", (probe.send))
+                    }
+                    if !probe.expect.is_empty() {
+                        kwargs!("--health-check-expect", (probe.expect))
+                    }
+                }
+                if let Some(eject) = eject {
+                    kwargs!("--eject-after", (eject.after));
+                    if eject.duration != EjectConfig::DEFAULT_DURATION {
+                        kwargs!("--eject-for", (humantime::format_duration(eject.duration)))
+                    }
+                }
+
+                if let Some(trusted) = &self.accept_proxy_protocol {
+                    kwargs!("--accept-proxy-protocol", (trusted));
+                    if self.proxy_protocol_timeout != AcceptProxy::DEFAULT_TIMEOUT {
+                        kwargs!(
+                            "--proxy-protocol-timeout",
+                            (humantime::format_duration(self.proxy_protocol_timeout))
+                        )
+                    }
+                }
+                if let Some(version) = self.send_proxy_protocol {
+                    kwargs!("--send-proxy-protocol", (version));
+                    for tlv in &self.proxy_protocol_tlv {
+                        kwargs!("--proxy-protocol-tlv", (tlv))
+                    }
+                }
+
+                let ShapeConfig { upload, download } = self.bandwidth.config();
+                for (flag, rate) in [
+                    ("--upload-limit", upload.connection),
+                    ("--download-limit", download.connection),
+                    ("--upload-limit-per-ip", upload.ip),
+                    ("--download-limit-per-ip", download.ip),
+                    ("--upload-limit-per-listener", upload.listener),
+                    ("--download-limit-per-listener", download.listener),
+                ] {
+                    if let Some(rate) = rate {
+                        args.extend([Cow::Borrowed(flag), Cow::Owned(rate.to_string())])
+                    }
+                }
+
+                for (flag, timeout) in [
+                    ("--idle-timeout", self.idle_timeout),
+                    ("--linger-timeout", self.linger_timeout),
+                    ("--max-lifetime", self.max_lifetime),
+                ] {
+                    if let Some(timeout) = timeout {
+                        let timeout = humantime::format_duration(timeout).to_string();
+                        args.extend([Cow::Borrowed(flag), Cow::Owned(timeout)])
+                    }
+                }
+            }
+            _ => unreachable!("clap requires --host and --ports when --config is absent"),
+        }
+
+        let connect = self.connect.config();
+        let defaults = ConnectConfig::default();
+        if connect.attempt_delay != defaults.attempt_delay {
+            kwargs!(
+                "--happy-eyeballs-delay",
+                (humantime::format_duration(connect.attempt_delay))
+            )
+        }
+        if connect.timeout != defaults.timeout {
+            kwargs!(
+                "--connect-timeout",
+                (humantime::format_duration(connect.timeout))
+            )
+        }
+        if connect.retries != defaults.retries {
+            kwargs!("--connect-retries", (connect.retries))
+        }
+        if connect.backoff != defaults.backoff {
+            kwargs!(
+                "--connect-backoff",
+                (humantime::format_duration(connect.backoff))
+            )
+        }
+        if connect.failover != defaults.failover {
+            kwargs!("--failover", (connect.failover))
+        }
+
+        if let Some(allow) = &self.allow {
+            kwargs!("--allow", (allow))
+        }
+        if let Some(deny) = &self.deny {
+            kwargs!("--deny", (deny))
+        }
+        if let Some(acl_file) = &self.acl_file {
+            let acl_file = std::path::absolute(acl_file).unwrap_or_else(|_| acl_file.clone());
+            kwargs!("--acl-file", (acl_file.display()))
+        }
+
+        let LimitConfig {
+            per_ip,
+            per_prefix,
+            prefix_v4,
+            prefix_v6,
+            max_connections,
+        } = self.limits.config();
+        for (flag, limit) in [
+            ("--max-connections-per-ip", per_ip.connections),
+            ("--max-rate-per-ip", per_ip.rate),
+            ("--max-connections-per-prefix", per_prefix.connections),
+            ("--max-rate-per-prefix", per_prefix.rate),
+        ] {
+            if let Some(limit) = limit {
+                args.extend([Cow::Borrowed(flag), Cow::Owned(limit.to_string())])
+            }
+        }
+        if let Some(max) = max_connections {
+            kwargs!("--max-connections", (max))
+        }
+        if prefix_v4 != LimitConfig::DEFAULT_PREFIX_V4 {
+            kwargs!("--limit-prefix-v4", (prefix_v4))
+        }
+        if prefix_v6 != LimitConfig::DEFAULT_PREFIX_V6 {
+            kwargs!("--limit-prefix-v6", (prefix_v6))
+        }
+
+        if self.drain_timeout != shutdown::DEFAULT_DRAIN_TIMEOUT {
+            kwargs!(
+                "--drain-timeout",
+                (humantime::format_duration(self.drain_timeout))
+            )
+        }
+
+        if let Some(metrics) = self.metrics {
+            kwargs!("--metrics", (metrics))
+        }
+        if let Some(admin) = self.admin {
+            kwargs!("--admin", (admin))
+        }
+
+        let access_log = &self.access_log;
+        if let Some(path) = &access_log.access_log {
+            let path = match path.as_os_str() == "-" {
+                true => path.clone(),
+                false => std::path::absolute(path).unwrap_or_else(|_| path.clone()),
+            };
+            kwargs!("--access-log", (path.display()));
+            if access_log.access_log_format != access_log::Format::default() {
+                kwargs!("--access-log-format", (access_log.access_log_format))
+            }
+            if let Some(max_size) = access_log.access_log_max_size {
+                kwargs!("--access-log-max-size", (max_size))
+            }
+            if let Some(every) = access_log.access_log_rotate {
+                kwargs!("--access-log-rotate", (humantime::format_duration(every)))
+            }
+            if access_log.access_log_keep != Rotation::DEFAULT_KEEP {
+                kwargs!("--access-log-keep", (access_log.access_log_keep))
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(upgrade_socket) = &self.upgrade_socket {
+            let upgrade_socket =
+                std::path::absolute(upgrade_socket).unwrap_or_else(|_| upgrade_socket.clone());
+            kwargs!("--upgrade-socket", (upgrade_socket.display()))
+        }
+
+        if let RuntimeType::MultiThreaded = self.runtime {
+            kwargs!("--runtime", (self.runtime));
+            if let Some(threads) = self.threads {
+                kwargs!("--threads", (threads))
+            }
+            if self.reuse_port {
+                args.push(Cow::Borrowed("--reuse-port"))
+            }
+            if self.pin_threads {
+                args.push(Cow::Borrowed("--pin-threads"))
+            }
+        }
+
+        args.extend(self.logging.args().into_iter().map(Cow::Owned));
+
+        args.into_iter()
     }
 }
 
 impl Display for RunArgs {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        struct ArgAllowProtocol(AllowProtocol);
-
-        impl Display for ArgAllowProtocol {
-            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-                match self.0 {
-                    AllowProtocol::Ipv4 => f.write_str("--v4"),
-                    AllowProtocol::Ipv6 => f.write_str("--v6"),
-                    AllowProtocol::Both => f.write_str("--v4 --v6"),
-                }
+        for (i, arg) in self.args().enumerate() {
+            if i != 0 {
+                f.write_char(' ')?;
+            }
+
+            match arg.contains(|c: char| c.is_whitespace() || c == '"') {
+                true => write!(f, "{arg:?}")?,
+                false => f.write_str(&arg)?,
             }
         }
 
-        f.write_fmt(format_args!(
-            "{proto} --host {host} --ports \"{ports}\" --log {log}",
-            proto = ArgAllowProtocol(self.allow_protocol()),
-            host = self.host.as_string(),
-            ports = self.ports,
-            log = self.log,
-        ))
+        Ok(())
     }
 }
 
 macro_rules! arg_enum {
-    (#[error_message($error_msg:literal)] $(#[$($attr:tt)*])* enum $name: ident  {
+    (#[error_message($error_msg:literal)] $(#[$($attr:tt)*])* $vis:vis enum $name: ident  {
         $(#[$($inner_attr:tt)*])*
         $($val: ident = $str_val: literal),*
     }) => {paste::paste! {
         #[derive(Copy, Clone)]
         $(#[$($attr)*])*
-        enum $name {
+        $vis enum $name {
             $(#[$($inner_attr)*])*
             $($val),*
         }
 
         #[derive(thiserror::Error, Debug)]
         #[error($error_msg)]
-        struct [<$name ParseError>](());
+        $vis struct [<$name ParseError>](());
 
-        impl FromStr for $name {
+        impl std::str::FromStr for $name {
             type Err = [<$name ParseError>];
 
             fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -155,8 +918,8 @@ macro_rules! arg_enum {
             }
         }
 
-        impl Display for $name {
-            fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
                 f.write_str(match *self {
                     $($name::$val => $str_val),*
                 })
@@ -165,6 +928,9 @@ macro_rules! arg_enum {
     }};
 }
 
+// declared after `arg_enum`, which it uses
+mod logging;
+
 arg_enum! {
     #[error_message("invalid runtime, expected either 'single-threaded' or 'multi-threaded'")]
     enum RuntimeType {
@@ -202,43 +968,56 @@ arg_enum! {
     }
 }
 
-fn init_logging(log_lvl: LevelFilter) {
-    if let Some(lvl) = log_lvl.into_level() {
-        tracing_subscriber::fmt()
-            .with_ansi(io::stdout().is_terminal())
-            .with_max_level(lvl)
-            .with_target(false)
-            .compact()
-            .init()
-    }
-}
-
 pub fn main() -> ! {
     let args = match CliArgs::parse() {
         CliArgs::Run(run_args) => run_args,
-        CliArgs::SetUpDaemon { daemon, args } => {
-            init_logging(default_log_level());
-            startup::setup_startup(daemon, args)
+        CliArgs::SetUpDaemon {
+            daemon,
+            socket_activation,
+            args,
+        } => {
+            logging::init_stdout(default_log_level());
+            startup::setup_startup(daemon, args, socket_activation)
         }
         CliArgs::RemoveDaemon { daemon } => {
-            init_logging(default_log_level());
+            logging::init_stdout(default_log_level());
             startup::remove_startup(daemon)
         }
     };
 
-    init_logging(args.log);
-
-    let allow = args.allow_protocol();
-    let ports = args.ports.into_ports_vec();
-    let host = args.host;
+    let log_filter = args.logging.init().unwrap_or_else(|err| {
+        logging::init_stdout(default_log_level());
+        tracing::error!("unable to set up logging: {err}");
+        std::process::exit(1)
+    });
 
-    tracing::info!("logging level is {}", args.log);
+    let (rules, global, threads) = args
+        .rules()
+        .and_then(|rules| Ok((rules, args.global()?, args.threads()?)))
+        .unwrap_or_else(|err| {
+            tracing::error!("{err}");
+            std::process::exit(1)
+        });
 
-    tracing::info!("Listening on ip {allow} on ports {ports:?} and forwarding to {host}");
+    tracing::info!("logging {}", args.logging);
+    log_rules(&rules);
 
-    monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
-        .enable_timer()
-        .build()
-        .unwrap()
-        .block_on(real_main(ProgramArgs { ports, host, allow }))
+    block_on(real_main(ProgramArgs {
+        rules,
+        global,
+        threads,
+        config_file: args.config.clone().map(|path| ConfigFile {
+            path,
+            watch: args.watch_config,
+            load: |path| Ok(config::load(path)?),
+        }),
+        metrics: args.metrics,
+        admin: args.admin.map(|addr| Admin {
+            addr,
+            log_filter: Box::new(log_filter),
+        }),
+        #[cfg(unix)]
+        upgrade_socket: args.upgrade_socket.clone(),
+    }));
+    std::process::exit(0)
 }
@@ -4,108 +4,178 @@ use std::iter::FusedIterator;
 use std::num::NonZero;
 use std::str::FromStr;
 
+use crate::PortMapping;
+
 #[derive(thiserror::Error, Debug)]
-#[error(
-    "invalid ports array, \
-    expected [$(<ELM>),+] where <ELM> can be a port number, \
-    inclusive range x..y, or an exclusive range x..!=y\
-    "
-)]
 #[cfg_attr(test, derive(PartialEq))]
-pub struct PortsArrayParseError(());
+pub enum PortsArrayParseError {
+    #[error(
+        "invalid ports array, \
+        expected [$(<ELM>),+] where <ELM> can be a port number, \
+        inclusive range x..y, or an exclusive range x..!=y, \
+        optionally mapped to an upstream port or range with <ELM>:<ELM>\
+        "
+    )]
+    Invalid,
+    #[error(
+        "invalid port mapping `{0}`, \
+        a range can only be mapped to a single port or to a range of the same length"
+    )]
+    MismatchedMapping(String),
+    #[error("port {port} can't be mapped to both {} and {}", .upstream.0, .upstream.1)]
+    Conflict {
+        port: NonZero<u16>,
+        upstream: (NonZero<u16>, NonZero<u16>),
+    },
+}
 
+/// a sorted list of the ports to listen on, and the upstream port each one forwards to
 #[derive(Clone)]
-#[cfg_attr(test, derive(Debug, Ord, PartialOrd, Eq, PartialEq))]
-pub struct PortsArray(Vec<NonZero<u16>>);
+#[cfg_attr(test, derive(Debug, Eq, PartialEq))]
+pub struct PortsArray(Vec<PortMapping>);
 
 impl PortsArray {
-    pub fn into_ports_vec(self) -> Vec<NonZero<u16>> {
+    pub fn into_ports_vec(self) -> Vec<PortMapping> {
         self.0
     }
 }
 
-impl FromStr for PortsArray {
-    type Err = PortsArrayParseError;
+#[derive(Clone)]
+enum OneOrRange<T> {
+    One(T),
+    RangeInclusive(T, T, bool),
+    RangeExclusive(T, T),
+}
+use OneOrRange::*;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        s.trim()
-            .strip_prefix('[')
-            .and_then(|s| s.strip_suffix(']'))
-            .and_then(|s| {
-                enum OneOrRange<T> {
-                    One(T),
-                    RangeInclusive(T, T, bool),
-                    RangeExclusive(T, T),
+impl Iterator for OneOrRange<NonZero<u16>> {
+    type Item = NonZero<u16>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            One(one) => {
+                // empty
+                let one = *one;
+                *self = RangeExclusive(NonZero::<u16>::MAX, NonZero::<u16>::MAX);
+                Some(one)
+            }
+            RangeInclusive(start, end, exhausted) => {
+                if start > end {
+                    None
+                } else if start == end && !*exhausted {
+                    *exhausted = true;
+                    Some(*start)
+                } else {
+                    let tmp = *start;
+                    *start = start.checked_add(1)?;
+                    Some(tmp)
                 }
-                use OneOrRange::*;
-
-                impl Iterator for OneOrRange<NonZero<u16>> {
-                    type Item = NonZero<u16>;
-
-                    fn next(&mut self) -> Option<Self::Item> {
-                        match self {
-                            One(one) => {
-                                // empty
-                                let one = *one;
-                                *self = RangeExclusive(NonZero::<u16>::MAX, NonZero::<u16>::MAX);
-                                Some(one)
-                            }
-                            RangeInclusive(start, end, exhausted) => {
-                                if start > end {
-                                    None
-                                } else if start == end && !*exhausted {
-                                    *exhausted = true;
-                                    Some(*start)
-                                } else {
-                                    let tmp = *start;
-                                    *start = start.checked_add(1)?;
-                                    Some(tmp)
-                                }
-                            }
-                            RangeExclusive(start, end) => {
-                                if start >= end {
-                                    None
-                                } else {
-                                    let tmp = *start;
-                                    *start = start.checked_add(1)?;
-                                    Some(tmp)
-                                }
-                            }
-                        }
-                    }
+            }
+            RangeExclusive(start, end) => {
+                if start >= end {
+                    None
+                } else {
+                    let tmp = *start;
+                    *start = start.checked_add(1)?;
+                    Some(tmp)
                 }
-                impl FusedIterator for OneOrRange<NonZero<u16>> {}
-
-                let array = s
-                    .split(',')
-                    .map(str::trim)
-                    .map(|s| {
-                        s.parse::<NonZero<u16>>().ok().map(One).or_else(|| {
-                            let parse_range =
-                                |(s1, s2): (&str, &str)| Some((s1.parse().ok()?, s2.parse().ok()?));
-                            s.split_once("..")
-                                .and_then(parse_range)
-                                .map(|(x, y)| RangeInclusive(x, y, false))
-                                .or_else(|| {
-                                    s.split_once("..!=")
-                                        .and_then(parse_range)
-                                        .map(|(x, y)| RangeExclusive(x, y))
-                                })
-                        })
+            }
+        }
+    }
+}
+impl FusedIterator for OneOrRange<NonZero<u16>> {}
+
+impl FromStr for OneOrRange<NonZero<u16>> {
+    type Err = PortsArrayParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        s.parse::<NonZero<u16>>()
+            .ok()
+            .map(One)
+            .or_else(|| {
+                let parse_range =
+                    |(s1, s2): (&str, &str)| Some((s1.parse().ok()?, s2.parse().ok()?));
+                s.split_once("..")
+                    .and_then(parse_range)
+                    .map(|(x, y)| RangeInclusive(x, y, false))
+                    .or_else(|| {
+                        s.split_once("..!=")
+                            .and_then(parse_range)
+                            .map(|(x, y)| RangeExclusive(x, y))
                     })
-                    .collect::<Option<Vec<_>>>()?
-                    .into_iter()
-                    .flatten()
-                    .unique()
-                    .sorted()
-                    .collect::<Vec<NonZero<u16>>>();
+            })
+            .ok_or(PortsArrayParseError::Invalid)
+    }
+}
 
-                Some(array)
+fn parse_element(s: &str) -> Result<Vec<PortMapping>, PortsArrayParseError> {
+    let s = s.trim();
+    let Some((listen, upstream)) = s.split_once(':') else {
+        return Ok(s
+            .parse::<OneOrRange<_>>()?
+            .map(|port| PortMapping {
+                listen: port,
+                upstream: port,
             })
-            .filter(|x| !x.is_empty())
-            .or_else(|| Some(vec![s.parse().ok()?]))
-            .ok_or(PortsArrayParseError(()))
-            .map(PortsArray)
+            .collect());
+    };
+
+    let listen = listen.parse::<OneOrRange<_>>()?;
+    let mappings = match upstream.parse::<OneOrRange<_>>()? {
+        One(upstream) => listen
+            .map(|listen| PortMapping { listen, upstream })
+            .collect(),
+        upstream => listen
+            .zip_longest(upstream)
+            .map(|ports| {
+                ports
+                    .both()
+                    .map(|(listen, upstream)| PortMapping { listen, upstream })
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(|| PortsArrayParseError::MismatchedMapping(s.to_owned()))?,
+    };
+
+    Ok(mappings)
+}
+
+impl FromStr for PortsArray {
+    type Err = PortsArrayParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let mappings = match s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            Some(s) => s
+                .split(',')
+                .map(parse_element)
+                .flatten_ok()
+                .collect::<Result<Vec<_>, _>>()?,
+            // note, passing in one element also works
+            None => parse_element(s)?,
+        };
+
+        let mappings = mappings
+            .into_iter()
+            .sorted_by_key(|mapping| mapping.listen)
+            .dedup()
+            .collect::<Vec<_>>();
+
+        if let Some((a, b)) = mappings
+            .iter()
+            .tuple_windows()
+            .find(|(a, b)| a.listen == b.listen)
+        {
+            return Err(PortsArrayParseError::Conflict {
+                port: a.listen,
+                upstream: (a.upstream, b.upstream),
+            });
+        }
+
+        match mappings.is_empty() {
+            true => Err(PortsArrayParseError::Invalid),
+            false => Ok(PortsArray(mappings)),
+        }
     }
 }
 
@@ -117,11 +187,25 @@ impl Display for PortsArray {
         while let Some(start) = ports.next() {
             let mut end = start;
 
+            // a run is either every listen port mapping to the same upstream port
+            // or mapping to a range of upstream ports, offset by a constant
+            let fixed_upstream = ports
+                .clone()
+                .next()
+                .is_some_and(|next| next.upstream == start.upstream);
+
             macro_rules! peek_end {
                 () => {
                     (|| {
-                        let new_end = end.checked_add(1)?;
-                        (new_end == ports.clone().next()?).then_some(new_end)
+                        let next = ports.clone().next()?;
+                        let expected = PortMapping {
+                            listen: end.listen.checked_add(1)?,
+                            upstream: match fixed_upstream {
+                                true => end.upstream,
+                                false => end.upstream.checked_add(1)?,
+                            },
+                        };
+                        (next == expected).then_some(next)
                     })()
                 };
             }
@@ -131,12 +215,31 @@ impl Display for PortsArray {
                 ports.next();
             }
 
-            if (end.get() - start.get()) < 2 {
-                for i in start.get()..=end.get() {
-                    list_dbg.entry(&i);
+            if (end.listen.get() - start.listen.get()) < 2 {
+                for i in 0..=(end.listen.get() - start.listen.get()) {
+                    let listen = start.listen.get() + i;
+                    let upstream = match fixed_upstream {
+                        true => start.upstream.get(),
+                        false => start.upstream.get() + i,
+                    };
+
+                    match listen == upstream {
+                        true => list_dbg.entry(&listen),
+                        false => list_dbg.entry(&format_args!("{listen}:{upstream}")),
+                    };
                 }
+            } else if fixed_upstream {
+                list_dbg.entry(&format_args!(
+                    "{}..{}:{}",
+                    start.listen, end.listen, start.upstream
+                ));
+            } else if start.listen == start.upstream {
+                list_dbg.entry(&format_args!("{}..{}", start.listen, end.listen));
             } else {
-                list_dbg.entry(&format_args!("{}..{}", start, end));
+                list_dbg.entry(&format_args!(
+                    "{}..{}:{}..{}",
+                    start.listen, end.listen, start.upstream, end.upstream
+                ));
             }
         }
 
@@ -152,6 +255,17 @@ mod test_port_array {
         NonZero::new(x).unwrap()
     }
 
+    fn mapping((listen, upstream): (u16, u16)) -> PortMapping {
+        PortMapping {
+            listen: non_zero(listen),
+            upstream: non_zero(upstream),
+        }
+    }
+
+    fn identity(ports: impl IntoIterator<Item = u16>) -> PortsArray {
+        PortsArray(ports.into_iter().map(|x| mapping((x, x))).collect())
+    }
+
     #[test]
     // too slow on miri, and there is no unsafe to test
     #[cfg_attr(miri, ignore)]
@@ -159,24 +273,22 @@ mod test_port_array {
         // Test valid input strings and expected PortsArray values
         assert_eq!(
             "[80, 443, 20..24, 8080]".parse::<PortsArray>(),
-            Ok(PortsArray(
+            Ok(identity(
                 [80, 443]
                     .into_iter()
                     .chain(20..=24)
                     .chain([8080])
-                    .map(non_zero)
                     .unique()
                     .sorted()
-                    .collect()
             ))
         );
         assert_eq!(
             format!("[1..{}]", u16::MAX).parse::<PortsArray>(),
-            Ok(PortsArray((1..=u16::MAX).map(non_zero).collect()))
+            Ok(identity(1..=u16::MAX))
         );
         assert_eq!(
             format!("[1..!={}]", u16::MAX).parse::<PortsArray>(),
-            Ok(PortsArray((1..u16::MAX).map(non_zero).collect()))
+            Ok(identity(1..u16::MAX))
         );
     }
 
@@ -192,7 +304,7 @@ mod test_port_array {
     #[test]
     fn test_ports_array_display() {
         assert_eq!(
-            PortsArray((1..=u16::MAX).map(non_zero).collect()).to_string(),
+            identity(1..=u16::MAX).to_string(),
             format!("[1..{}]", u16::MAX)
         );
 
@@ -212,4 +324,75 @@ mod test_port_array {
             "[80, 81, 443, 444, 8080..8083]"
         )
     }
+
+    #[test]
+    fn test_port_mappings() {
+        assert_eq!(
+            "[8080:80, 9000..9010:7000..7010]".parse::<PortsArray>(),
+            Ok(PortsArray(
+                [(8080, 80)]
+                    .into_iter()
+                    .chain((9000..=9010).zip(7000..=7010))
+                    .map(mapping)
+                    .collect()
+            ))
+        );
+        assert_eq!(
+            "[8443..!=8446:443, 22]".parse::<PortsArray>(),
+            Ok(PortsArray(
+                [(22, 22), (8443, 443), (8444, 443), (8445, 443)]
+                    .map(mapping)
+                    .to_vec()
+            ))
+        );
+        assert_eq!(
+            "8443:443".parse::<PortsArray>(),
+            Ok(PortsArray(vec![mapping((8443, 443))]))
+        );
+        // the same mapping twice is fine
+        assert!("[8080:80, 8080..8081:80]".parse::<PortsArray>().is_ok());
+
+        assert_eq!(
+            "[9000..9010:7000..7009]".parse::<PortsArray>(),
+            Err(PortsArrayParseError::MismatchedMapping(
+                "9000..9010:7000..7009".to_owned()
+            ))
+        );
+        assert_eq!(
+            "[80:8080..8081]".parse::<PortsArray>(),
+            Err(PortsArrayParseError::MismatchedMapping(
+                "80:8080..8081".to_owned()
+            ))
+        );
+        assert_eq!(
+            "[8080:80, 8080:81]".parse::<PortsArray>(),
+            Err(PortsArrayParseError::Conflict {
+                port: non_zero(8080),
+                upstream: (non_zero(80), non_zero(81))
+            })
+        );
+        assert!("[80:]".parse::<PortsArray>().is_err());
+        assert!("[80:0]".parse::<PortsArray>().is_err());
+    }
+
+    #[test]
+    fn test_port_mappings_display_round_trip() {
+        for (input, display) in [
+            ("[8080:80]", "[8080:80]"),
+            ("[9000..9010:7000..7010]", "[9000..9010:7000..7010]"),
+            ("[9000..9010:80]", "[9000..9010:80]"),
+            ("[9000, 9001:80]", "[9000, 9001:80]"),
+            ("[8080:80, 8081:80]", "[8080:80, 8081:80]"),
+            ("[8080:80, 8081:81]", "[8080:80, 8081:81]"),
+            (
+                "[22, 80..82, 8080..8082:80, 8083..8090:9083..9090]",
+                "[22, 80..82, 8080..8082:80, 8083..8090:9083..9090]",
+            ),
+            ("[80..82:80..82]", "[80..82]"),
+        ] {
+            let ports = input.parse::<PortsArray>().unwrap();
+            assert_eq!(ports.to_string(), display);
+            assert_eq!(display.parse::<PortsArray>(), Ok(ports));
+        }
+    }
 }
@@ -1,19 +1,25 @@
+use crate::cli::logging::LogOutput;
 use crate::cli::startup::{exe_path, rm_file};
 use crate::cli::{Daemon, RunArgs};
 use std::fs::OpenOptions;
 use std::io;
 use std::io::Write;
+use std::net::SocketAddr;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 
-pub fn setup_startup(daemon: Daemon, args: RunArgs) -> ! {
+pub fn setup_startup(daemon: Daemon, args: RunArgs, socket_activation: bool) -> ! {
     if !nix::unistd::Uid::effective().is_root() {
         eprintln!("must be root to set up startup");
         std::process::exit(1)
     }
 
     match daemon {
-        Daemon::SystemD => systemd_setup_startup(args),
+        Daemon::SystemD => systemd_setup_startup(args, socket_activation),
+        Daemon::OpenRC if socket_activation => {
+            eprintln!("socket activation is only supported by systemd");
+            std::process::exit(1)
+        }
         Daemon::OpenRC => openrc_setup_startup(args),
     }
 
@@ -50,31 +56,77 @@ pub fn write_file(path: &Path, data: &[u8], mode: u32) -> io::Result<()> {
 const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 
 const SYSTEMD_PATH: &str = "/etc/systemd/system/xyz.vrtgs.hptp.service";
+const SYSTEMD_SOCKET_PATH: &str = "/etc/systemd/system/xyz.vrtgs.hptp.socket";
 
 const APP_NAME: &str = "xyz.vrtgs.hptp";
 const OPEN_RC_PATH: &str = "/etc/init.d/xyz.vrtgs.hptp";
 
-fn systemd_service_name() -> &'static str {
-    let Some((_, name)) = SYSTEMD_PATH.rsplit_once('/') else {
-        unreachable!("{SYSTEMD_PATH} should always contain a /")
+fn systemd_unit_name(path: &'static str) -> &'static str {
+    let Some((_, name)) = path.rsplit_once('/') else {
+        unreachable!("{path} should always contain a /")
     };
     name
 }
 
-fn systemd_remove_startup() {
-    cmd!("rc-service" APP_NAME "start");
-    cmd!("rc-update" "delete" APP_NAME "default");
-    rm_file(SYSTEMD_PATH).unwrap();
+fn systemd_service_name() -> &'static str {
+    systemd_unit_name(SYSTEMD_PATH)
+}
+
+fn systemd_socket_name() -> &'static str {
+    systemd_unit_name(SYSTEMD_SOCKET_PATH)
 }
 
 fn openrc_remove_startup() {
+    cmd!("rc-service" APP_NAME "stop");
+    cmd!("rc-update" "delete" APP_NAME "default");
+    rm_file(OPEN_RC_PATH).unwrap();
+}
+
+fn systemd_remove_startup() {
     cmd!("systemctl" "stop" systemd_service_name());
     cmd!("systemctl" "disable" SYSTEMD_PATH);
     rm_file(SYSTEMD_PATH).unwrap();
+
+    if Path::new(SYSTEMD_SOCKET_PATH).try_exists().unwrap() {
+        cmd!("systemctl" "stop" systemd_socket_name());
+        cmd!("systemctl" "disable" SYSTEMD_SOCKET_PATH);
+        rm_file(SYSTEMD_SOCKET_PATH).unwrap();
+    }
     cmd!("systemctl" "daemon-reload");
 }
 
-fn systemd_setup_startup(args: RunArgs) {
+/// the `.socket` unit binding every address `args` listens on, for systemd to pass to hptp
+fn systemd_socket_unit(args: &RunArgs) -> String {
+    if args.reuse_port {
+        eprintln!("--reuse-port can't be used with socket activation, the threads couldn't bind the sockets systemd did");
+        std::process::exit(1)
+    }
+
+    let rules = args.rules().unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1)
+    });
+
+    let listen_streams = rules
+        .iter()
+        .flat_map(|rule| {
+            rule.bind.iter().flat_map(|&ip| {
+                rule.ports.iter().map(move |port| {
+                    format!("ListenStream={}", SocketAddr::new(ip, port.listen.get()))
+                })
+            })
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        include_str!("./systemd.template.socket"),
+        description = DESCRIPTION,
+        listen_streams = listen_streams
+    )
+}
+
+fn systemd_setup_startup(mut args: RunArgs, socket_activation: bool) {
     if !Path::new(SYSTEMD_PATH)
         .parent()
         .unwrap()
@@ -84,25 +136,49 @@ fn systemd_setup_startup(args: RunArgs) {
         panic!("systemd not found on this system!")
     }
 
+    args.logging.default_to(LogOutput::Journald);
+
+    let (socket, user) = match socket_activation {
+        true => {
+            let socket_file = systemd_socket_unit(&args);
+            write_file(SYSTEMD_SOCKET_PATH.as_ref(), socket_file.as_bytes(), 0o644).unwrap();
+
+            let socket = systemd_socket_name();
+            (
+                format!("Requires={socket}\nAfter={socket}\n"),
+                "DynamicUser=yes",
+            )
+        }
+        false => (String::new(), "User=root"),
+    };
+
     let service_file = format!(
         include_str!("./systemd.template.service"),
         description = DESCRIPTION,
-        exec_command = format_args!("{} run {args}", exe_path())
+        exec_command = format_args!("{} run {args}", exe_path()),
+        stop_timeout = args.stop_timeout(),
+        socket = socket,
+        user = user
     );
 
     write_file(SYSTEMD_PATH.as_ref(), service_file.as_bytes(), 0o644).unwrap();
 
     cmd!("systemctl" "daemon-reload");
+    if socket_activation {
+        cmd!("systemctl" "enable" "--now" SYSTEMD_SOCKET_PATH);
+    }
     cmd!("systemctl" "enable" "--now" SYSTEMD_PATH);
     cmd!("systemctl" "status" systemd_service_name());
 }
 
-fn openrc_setup_startup(args: RunArgs) {
+fn openrc_setup_startup(mut args: RunArgs) {
+    args.logging.default_to(LogOutput::Syslog);
     let service_file = format!(
         include_str!("./openrc-service"),
         description = DESCRIPTION,
         exe_path = exe_path(),
-        args = format_args!("run {args}") // openrc has some VERY weird behaviour regarding CRLF see OpenRC/openrc#733
+        args = format_args!("run {args}"), // openrc has some VERY weird behaviour regarding CRLF see OpenRC/openrc#733
+        stop_timeout = args.stop_timeout()
     )
     .replace("\r\n", "\n");
 
@@ -3,14 +3,16 @@ use crate::cli::{Daemon, RunArgs};
 use std::fmt::{Display, Formatter};
 
 const LAUNCHD_FILE: &str = "/Library/LaunchDaemons/xyz.vrtgs.hptp.plist";
+const LOG_FILE: &str = "/var/log/hptp.log";
 
-pub fn setup_startup(daemon: Daemon, args: RunArgs) -> ! {
+pub fn setup_startup(daemon: Daemon, mut args: RunArgs) -> ! {
     if !nix::unistd::Uid::effective().is_root() {
         eprintln!("must be root to set up startup");
         std::process::exit(1)
     }
 
     let Daemon::LaunchDaemons = daemon;
+    args.logging.default_to_file(LOG_FILE);
 
     struct LDArgs<I>(I);
 
@@ -29,7 +31,8 @@ pub fn setup_startup(daemon: Daemon, args: RunArgs) -> ! {
         format!(
             include_str!("./macos_launchd.plist"),
             program_path = exe_path(),
-            args = LDArgs(std::iter::once("run".into()).chain(args.args()))
+            args = LDArgs(std::iter::once("run".into()).chain(args.args())),
+            stop_timeout = args.stop_timeout()
         ),
     )
     .unwrap();
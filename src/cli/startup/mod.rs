@@ -49,8 +49,18 @@ fn rm_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
 #[cfg_attr(target_os = "macos", path = "macos.rs")]
 mod sys;
 
-pub fn setup_startup(daemon: Daemon, args: RunArgs) -> ! {
-    sys::setup_startup(daemon, args)
+pub fn setup_startup(daemon: Daemon, args: RunArgs, socket_activation: bool) -> ! {
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            sys::setup_startup(daemon, args, socket_activation)
+        } else {
+            if socket_activation {
+                eprintln!("socket activation is only supported by systemd");
+                std::process::exit(1)
+            }
+            sys::setup_startup(daemon, args)
+        }
+    }
 }
 
 pub fn remove_startup(daemon: Daemon) -> ! {
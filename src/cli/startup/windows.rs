@@ -129,8 +129,11 @@ fn ensure_admin() -> io::Result<()> {
     Ok(())
 }
 
-pub fn setup_startup(daemon: Daemon, args: RunArgs) -> ! {
+pub fn setup_startup(daemon: Daemon, mut args: RunArgs) -> ! {
     let Daemon::Registry = daemon;
+    // it runs without a console, next to the executable is somewhere it can write to
+    args.logging
+        .default_to_file(std::path::Path::new(&exe_path()).with_file_name("hptp.log"));
 
     ensure_admin().unwrap();
 
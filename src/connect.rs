@@ -0,0 +1,354 @@
+use std::cell::RefCell;
+use std::future::{pending, Future};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::time::Duration;
+
+use futures::future::{select, Either};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use monoio::net::TcpStream;
+use monoio::time::{sleep, timeout};
+use smallvec::SmallVec;
+
+/// What to try once connecting to an upstream address failed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum Failover {
+    /// nothing, only the first address of the upstream host is tried
+    None,
+    /// the other addresses the upstream host resolved to, see [`happy_eyeballs`]
+    #[default]
+    Address,
+    /// the other addresses, and then the other upstream hosts
+    Upstream,
+}
+
+/// How connections to the upstream are established
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ConnectConfig {
+    /// how long to wait on a connection attempt before racing it against the next address
+    pub attempt_delay: Duration,
+    /// how long connecting to an upstream host may take, resolving it and trying all of its addresses
+    pub timeout: Duration,
+    /// how many more times to try once connecting failed
+    pub retries: u32,
+    /// how long to wait before the first retry, doubled for every retry after it
+    pub backoff: Duration,
+    pub failover: Failover,
+}
+
+impl ConnectConfig {
+    /// The "Connection Attempt Delay" recommended by RFC 8305
+    pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+    pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(100);
+    /// no matter how many retries came before, one doesn't wait longer than this
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+    /// how long to wait before the `retry`th retry, starting from 1
+    pub fn backoff(&self, retry: u32) -> Duration {
+        2u32.checked_pow(retry.saturating_sub(1))
+            .and_then(|factor| self.backoff.checked_mul(factor))
+            .map_or(Self::MAX_BACKOFF, |backoff| backoff.min(Self::MAX_BACKOFF))
+    }
+}
+
+impl Default for ConnectConfig {
+    fn default() -> Self {
+        ConnectConfig {
+            attempt_delay: Self::DEFAULT_ATTEMPT_DELAY,
+            timeout: Self::DEFAULT_TIMEOUT,
+            retries: 0,
+            backoff: Self::DEFAULT_BACKOFF,
+            failover: Failover::default(),
+        }
+    }
+}
+
+/// Changes a rule makes to the [`ConnectConfig`] every rule starts out with
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectOverrides {
+    pub attempt_delay: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub retries: Option<u32>,
+    pub backoff: Option<Duration>,
+    pub failover: Option<Failover>,
+}
+
+impl ConnectOverrides {
+    pub fn apply(self, config: ConnectConfig) -> ConnectConfig {
+        ConnectConfig {
+            attempt_delay: self.attempt_delay.unwrap_or(config.attempt_delay),
+            timeout: self.timeout.unwrap_or(config.timeout),
+            retries: self.retries.unwrap_or(config.retries),
+            backoff: self.backoff.unwrap_or(config.backoff),
+            failover: self.failover.unwrap_or(config.failover),
+        }
+    }
+}
+
+/// orders addresses as RFC 8305 section 4 describes,
+/// alternating between address families, starting with ipv6
+fn interleave(addrs: &[SocketAddr]) -> impl Iterator<Item = SocketAddr> + '_ {
+    let (mut v6, mut v4) = (
+        addrs.iter().copied().filter(SocketAddr::is_ipv6),
+        addrs.iter().copied().filter(SocketAddr::is_ipv4),
+    );
+
+    let mut ipv6_turn = true;
+    std::iter::from_fn(move || {
+        let next = match ipv6_turn {
+            true => v6.next().or_else(|| v4.next()),
+            false => v4.next().or_else(|| v6.next()),
+        };
+        ipv6_turn = !ipv6_turn;
+        next
+    })
+}
+
+/// Connects to one of `addrs` using "Happy Eyeballs" (RFC 8305),
+/// a new attempt is started every `attempt_delay`, or as soon as the previous attempt fails,
+/// the first connection to be established wins, and every other attempt is canceled,
+/// `report` is told the outcome of every attempt that finished,
+/// and that the attempts still in flight failed once `limit` runs out
+pub async fn happy_eyeballs(
+    addrs: &[SocketAddr],
+    attempt_delay: Duration,
+    limit: Duration,
+    report: impl Fn(SocketAddr, bool),
+) -> io::Result<TcpStream> {
+    race(addrs, attempt_delay, limit, TcpStream::connect_addr, report).await
+}
+
+/// [`happy_eyeballs`], connecting with `connect`
+async fn race<S, F>(
+    addrs: &[SocketAddr],
+    attempt_delay: Duration,
+    limit: Duration,
+    connect: impl Fn(SocketAddr) -> F,
+    report: impl Fn(SocketAddr, bool),
+) -> io::Result<S>
+where
+    F: Future<Output = io::Result<S>>,
+{
+    let in_flight = RefCell::new(SmallVec::<SocketAddr, 4>::new());
+    let (connect, report, in_flight_ref) = (&connect, &report, &in_flight);
+    let attempt = |addr| {
+        in_flight_ref.borrow_mut().push(addr);
+        async move {
+            tracing::trace!("Attempting to connect to {addr}");
+            let res = connect(addr)
+                .await
+                .inspect_err(|err| tracing::debug!("Connecting to {addr} failed: {err}"));
+            in_flight_ref
+                .borrow_mut()
+                .retain(|&mut other| other != addr);
+            report(addr, res.is_ok());
+            res
+        }
+    };
+
+    let res = timeout(limit, async {
+        let mut addrs = interleave(addrs).peekable();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+
+        loop {
+            if attempts.is_empty() {
+                match addrs.next() {
+                    Some(addr) => attempts.push(attempt(addr)),
+                    None => {
+                        return Err(last_err.unwrap_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "no addresses to connect to",
+                            )
+                        }))
+                    }
+                }
+            }
+
+            let more_addrs = addrs.peek().is_some();
+            let next_attempt = pin!(async {
+                match more_addrs {
+                    true => sleep(attempt_delay).await,
+                    false => pending().await,
+                }
+            });
+
+            let finished = match select(attempts.next(), next_attempt).await {
+                Either::Left((finished, _)) => finished,
+                Either::Right(((), _)) => None,
+            };
+
+            match finished {
+                Some(Ok(stream)) => return Ok(stream),
+                // the next attempt starts right away, instead of waiting out the delay
+                Some(Err(err)) => last_err = Some(err),
+                // the delay ran out
+                None => {}
+            }
+
+            if let Some(addr) = addrs.next() {
+                attempts.push(attempt(addr))
+            }
+        }
+    })
+    .await;
+
+    res.unwrap_or_else(|elapsed| {
+        // the attempts were dropped along with the timed out future
+        for addr in in_flight.take() {
+            report(addr, false)
+        }
+        Err(elapsed.into())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use monoio::net::TcpListener;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::time::Instant;
+
+    fn v4(i: u8) -> SocketAddr {
+        (Ipv4Addr::new(192, 0, 2, i), 80).into()
+    }
+
+    fn v6(i: u16) -> SocketAddr {
+        (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i), 80).into()
+    }
+
+    #[test]
+    fn backoff() {
+        let config = ConnectConfig {
+            backoff: Duration::from_millis(100),
+            ..ConnectConfig::default()
+        };
+        assert_eq!(config.backoff(1), Duration::from_millis(100));
+        assert_eq!(config.backoff(2), Duration::from_millis(200));
+        assert_eq!(config.backoff(4), Duration::from_millis(800));
+        assert_eq!(config.backoff(20), ConnectConfig::MAX_BACKOFF);
+        assert_eq!(config.backoff(u32::MAX), ConnectConfig::MAX_BACKOFF);
+
+        let overridden = ConnectOverrides {
+            retries: Some(3),
+            failover: Some(Failover::Upstream),
+            ..ConnectOverrides::default()
+        }
+        .apply(config);
+        assert_eq!(overridden.retries, 3);
+        assert_eq!(overridden.failover, Failover::Upstream);
+        assert_eq!(overridden.backoff, config.backoff);
+    }
+
+    #[test]
+    fn interleaves_families() {
+        assert_eq!(
+            interleave(&[v4(1), v4(2), v4(3), v6(1), v6(2)]).collect::<Vec<_>>(),
+            [v6(1), v4(1), v6(2), v4(2), v4(3)]
+        );
+        assert_eq!(
+            interleave(&[v4(1), v4(2)]).collect::<Vec<_>>(),
+            [v4(1), v4(2)]
+        );
+        assert_eq!(
+            interleave(&[v6(1), v6(2), v4(1)]).collect::<Vec<_>>(),
+            [v6(1), v4(1), v6(2)]
+        );
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    #[cfg_attr(miri, ignore)]
+    async fn skips_failed_addresses() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let refused = {
+            let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let start = Instant::now();
+        let reports = RefCell::new(vec![]);
+        let stream = happy_eyeballs(
+            &[refused, addr],
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+            |addr, ok| reports.borrow_mut().push((addr, ok)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert_eq!(reports.into_inner(), [(refused, false), (addr, true)]);
+        // the refused attempt shouldn't hold up the next one
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        assert!(happy_eyeballs(
+            &[refused],
+            Duration::from_millis(10),
+            Duration::from_secs(10),
+            |_, _| {}
+        )
+        .await
+        .is_err());
+        assert!(happy_eyeballs(
+            &[],
+            Duration::from_millis(10),
+            Duration::from_secs(10),
+            |_, _| {}
+        )
+        .await
+        .is_err());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    #[cfg_attr(miri, ignore)]
+    async fn reports_attempts_in_flight_on_timeout() {
+        // v4(2) is refused, the others never answer
+        let connect = |addr| async move {
+            match addr == v4(2) {
+                true => Err(io::ErrorKind::ConnectionRefused.into()),
+                false => pending::<io::Result<()>>().await,
+            }
+        };
+
+        let reports = RefCell::new(vec![]);
+        let err = race(
+            &[v4(1), v4(2), v4(3)],
+            Duration::from_millis(10),
+            Duration::from_millis(100),
+            connect,
+            |addr, ok| reports.borrow_mut().push((addr, ok)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            reports.into_inner(),
+            [(v4(2), false), (v4(1), false), (v4(3), false)]
+        );
+
+        // the attempts canceled by a winner neither failed nor are left in flight
+        let reports = RefCell::new(vec![]);
+        let connect = |addr| async move {
+            match addr == v4(2) {
+                true => Ok(()),
+                false => pending().await,
+            }
+        };
+        race(
+            &[v4(1), v4(2), v4(3)],
+            Duration::from_millis(10),
+            Duration::from_millis(100),
+            connect,
+            |addr, ok| reports.borrow_mut().push((addr, ok)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reports.into_inner(), [(v4(2), true)]);
+    }
+}
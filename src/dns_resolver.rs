@@ -10,11 +10,13 @@ use smallvec::SmallVec;
 use std::fmt::{Debug, Formatter};
 use std::net::SocketAddr;
 use std::num::NonZeroUsize;
-use std::sync::LazyLock;
+use std::sync::{LazyLock, Mutex, PoisonError};
 use std::thread::available_parallelism;
 use std::time::Instant;
 
-pub struct DnsResolver(DnsLru);
+use crate::metrics::METRICS;
+
+pub struct DnsResolver(Mutex<DnsLru>);
 
 type DomainRequest = (Name, oneshot::Sender<Result<LookupIp, ResolveError>>);
 
@@ -57,22 +59,27 @@ impl DnsResolver {
     ) -> Result<SmallVec<SocketAddr, 4>, ResolveError> {
         let mut query = Query::query(host, RecordType::A);
         let now = Instant::now();
+        let cache = self.cache();
 
-        let res = self.0.get(&query, now).or_else(|| {
+        let res = cache.get(&query, now).or_else(|| {
             query.set_query_type(RecordType::AAAA);
-            self.0.get(&query, now)
+            cache.get(&query, now)
         });
 
         let iter = match res {
-            Some(res) => res?.into(),
+            Some(res) => {
+                METRICS.dns_hits.inc();
+                res?.into()
+            }
             None => {
+                METRICS.dns_misses.inc();
                 let (tx, rx) = oneshot::channel();
                 DNS_RESOLVER
                     .send((query.into_parts().name, tx))
                     .map_err(|_| "dns resolver disconnected")?;
                 let ret = rx.await.map_err(|_| "dns resolver didn't reply")??;
 
-                self.0.insert_records(
+                cache.insert_records(
                     ret.query().clone(),
                     ret.as_lookup().records().iter().cloned(),
                     Instant::now(),
@@ -86,9 +93,30 @@ impl DnsResolver {
     }
 }
 
+impl DnsResolver {
+    const CAPACITY: usize = 128;
+
+    fn cache(&self) -> DnsLru {
+        // clones share their entries, a lookup racing a flush only fills the forgotten cache
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// forgets every cached lookup
+    pub fn flush(&self) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) =
+            DnsLru::new(Self::CAPACITY, TtlConfig::default())
+    }
+}
+
 impl Default for DnsResolver {
     fn default() -> Self {
-        DnsResolver(DnsLru::new(128, TtlConfig::default()))
+        DnsResolver(Mutex::new(DnsLru::new(
+            Self::CAPACITY,
+            TtlConfig::default(),
+        )))
     }
 }
 
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZero;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use monoio::io::{AsyncReadRent, AsyncWriteRentExt};
+use monoio::net::TcpStream;
+use monoio::time::{sleep, timeout};
+use smallvec::SmallVec;
+
+use crate::host::Host;
+use crate::payload::Payload;
+use crate::upstream::Upstreams;
+
+/// Periodic probes of every upstream address
+#[derive(Clone, Debug)]
+pub struct ProbeConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// the upstream port to probe, defaults to the rule's first upstream port
+    pub port: Option<NonZero<u16>>,
+    /// written to the upstream once connected
+    pub send: Payload,
+    /// the upstream has to reply with a response that starts with these bytes
+    pub expect: Payload,
+}
+
+impl ProbeConfig {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+}
+
+/// Ejecting addresses that real connections keep failing to reach
+#[derive(Copy, Clone, Debug)]
+pub struct EjectConfig {
+    /// how many connection failures in a row it takes to eject an address
+    pub after: NonZero<u32>,
+    /// how long an ejected address is skipped for
+    pub duration: Duration,
+}
+
+impl EjectConfig {
+    pub const DEFAULT_DURATION: Duration = Duration::from_secs(30);
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HealthConfig {
+    pub probe: Option<ProbeConfig>,
+    pub eject: Option<EjectConfig>,
+}
+
+#[derive(Default)]
+struct AddrState {
+    failures: u32,
+    ejected_until: Option<Instant>,
+    probe_failed: bool,
+}
+
+impl AddrState {
+    fn is_healthy(&self, now: Instant) -> bool {
+        !self.probe_failed && self.ejected_until.is_none_or(|until| now >= until)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    addrs: HashMap<IpAddr, AddrState>,
+    /// the addresses each host resolved to last
+    resolved: HashMap<Host, SmallVec<IpAddr, 4>>,
+}
+
+/// Tracks which upstream addresses of a rule are healthy
+pub struct Health {
+    config: HealthConfig,
+    state: Mutex<State>,
+}
+
+impl Health {
+    pub fn new(config: HealthConfig) -> Self {
+        Health {
+            config,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn config(&self) -> &HealthConfig {
+        &self.config
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        f(&mut self.state.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    #[cfg(test)]
+    fn is_healthy(&self, ip: IpAddr) -> bool {
+        self.with_state(|state| {
+            state
+                .addrs
+                .get(&ip)
+                .is_none_or(|addr| addr.is_healthy(Instant::now()))
+        })
+    }
+
+    /// a host is worth picking unless every address it resolved to last is unhealthy
+    pub fn is_usable(&self, host: Host) -> bool {
+        self.with_state(|state| {
+            let now = Instant::now();
+            state.resolved.get(&host).is_none_or(|ips| {
+                ips.iter()
+                    .any(|ip| state.addrs.get(ip).is_none_or(|addr| addr.is_healthy(now)))
+            })
+        })
+    }
+
+    /// drops the unhealthy addresses of `host`,
+    /// unless all of them are, in which case they're all worth a try
+    pub fn filter(&self, host: Host, addrs: SmallVec<SocketAddr, 4>) -> SmallVec<SocketAddr, 4> {
+        self.with_state(|state| {
+            state
+                .resolved
+                .insert(host, addrs.iter().map(SocketAddr::ip).collect());
+
+            let now = Instant::now();
+            let healthy = addrs
+                .iter()
+                .copied()
+                .filter(|addr| {
+                    state
+                        .addrs
+                        .get(&addr.ip())
+                        .is_none_or(|state| state.is_healthy(now))
+                })
+                .collect::<SmallVec<_, 4>>();
+
+            match healthy.is_empty() {
+                true => {
+                    tracing::warn!("every address of {host} is unhealthy, trying them anyway");
+                    addrs
+                }
+                false => healthy,
+            }
+        })
+    }
+
+    /// records the outcome of a real connection attempt
+    pub fn report_connect(&self, ip: IpAddr, ok: bool) {
+        self.with_state(|state| {
+            let addr = state.addrs.entry(ip).or_default();
+            if ok {
+                if addr.failures != 0 && addr.ejected_until.is_some() {
+                    tracing::info!("upstream {ip} is reachable again");
+                }
+                addr.failures = 0;
+                addr.ejected_until = None;
+                return;
+            }
+
+            addr.failures = addr.failures.saturating_add(1);
+            let Some(eject) = self.config.eject else {
+                return;
+            };
+
+            if addr.failures >= eject.after.get() {
+                tracing::warn!(
+                    "ejecting upstream {ip} for {:?} after {} failed connections",
+                    eject.duration,
+                    addr.failures
+                );
+                addr.ejected_until = Some(Instant::now() + eject.duration);
+            }
+        })
+    }
+
+    fn report_probe(&self, ip: IpAddr, res: io::Result<()>) {
+        self.with_state(|state| {
+            let addr = state.addrs.entry(ip).or_default();
+            match res {
+                Ok(()) => {
+                    if !addr.is_healthy(Instant::now()) {
+                        tracing::info!("upstream {ip} passed its health check");
+                    }
+                    *addr = AddrState::default();
+                }
+                Err(err) => {
+                    if !addr.probe_failed {
+                        tracing::warn!("upstream {ip} failed its health check: {err}");
+                    }
+                    addr.probe_failed = true;
+                }
+            }
+        })
+    }
+}
+
+async fn probe(addr: SocketAddr, config: &ProbeConfig) -> io::Result<()> {
+    let check = async {
+        let mut stream = TcpStream::connect_addr(addr).await?;
+        if !config.send.is_empty() {
+            let (res, _) = stream.write_all(config.send.as_bytes().to_vec()).await;
+            res?;
+        }
+
+        let expect = config.expect.as_bytes();
+        let mut received = Vec::with_capacity(expect.len());
+        while received.len() < expect.len() {
+            let buf = Vec::with_capacity(expect.len() - received.len());
+            let (res, buf) = stream.read(buf).await;
+            if res? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            received.extend_from_slice(&buf);
+        }
+
+        match received.starts_with(expect) {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected response `{}`", received.escape_ascii()),
+            )),
+        }
+    };
+
+    timeout(config.timeout, check)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)))
+}
+
+/// Probes every address of every upstream host every `config.interval`
+pub async fn probe_forever(
+    upstreams: &Upstreams,
+    health: &Health,
+    config: &ProbeConfig,
+    port: u16,
+) -> ! {
+    let port = config.port.map_or(port, NonZero::get);
+
+    loop {
+        let probes = upstreams.hosts().map(|weighted| async move {
+            let host = weighted.host;
+            let addrs = match host.to_hosts(port).await {
+                Ok(addrs) => addrs,
+                Err(err) => {
+                    tracing::warn!("unable to resolve upstream {host} for its health check: {err}");
+                    return;
+                }
+            };
+
+            health.with_state(|state| {
+                state
+                    .resolved
+                    .insert(host, addrs.iter().map(SocketAddr::ip).collect())
+            });
+
+            futures::future::join_all(addrs.iter().map(|&addr| async move {
+                health.report_probe(addr.ip(), probe(addr, config).await)
+            }))
+            .await;
+        });
+
+        futures::future::join_all(probes).await;
+        sleep(config.interval).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use monoio::net::TcpListener;
+    use std::net::Ipv4Addr;
+
+    fn ip(i: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, i))
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn passive_ejection() {
+        let health = Health::new(HealthConfig {
+            probe: None,
+            eject: Some(EjectConfig {
+                after: NonZero::new(2).unwrap(),
+                duration: Duration::from_secs(60),
+            }),
+        });
+        let host = Host::new_static(ip(1));
+        let addrs = smallvec::smallvec![SocketAddr::new(ip(1), 80), SocketAddr::new(ip(2), 80)];
+
+        health.report_connect(ip(1), false);
+        assert!(health.is_healthy(ip(1)));
+        health.report_connect(ip(1), false);
+        assert!(!health.is_healthy(ip(1)));
+        assert_eq!(
+            health.filter(host, addrs.clone()).as_slice(),
+            [SocketAddr::new(ip(2), 80)]
+        );
+
+        // with nothing healthy left, everything gets tried
+        health.report_connect(ip(2), false);
+        health.report_connect(ip(2), false);
+        assert!(!health.is_usable(host));
+        assert_eq!(health.filter(host, addrs.clone()), addrs);
+
+        health.report_connect(ip(1), true);
+        assert!(health.is_healthy(ip(1)));
+        assert!(health.is_usable(host));
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    #[cfg_attr(miri, ignore)]
+    async fn send_expect_probe() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        monoio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (res, buf) = stream.read(Vec::with_capacity(16)).await;
+                res.unwrap();
+                let reply = match &*buf {
+                    b"PING\r\n" => &b"+PONG\r\n"[..],
+                    _ => &b"-ERR\r\n"[..],
+                };
+                let (res, _) = stream.write_all(reply).await;
+                res.unwrap();
+            }
+        });
+
+        let config = |send: &str, expect: &str| ProbeConfig {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+            port: None,
+            send: send.parse().unwrap(),
+            expect: expect.parse().unwrap(),
+        };
+
+        probe(addr, &config("", "")).await.unwrap();
+        probe(addr, &config(r"PING\r\n", "+PONG")).await.unwrap();
+        assert!(probe(addr, &config("HELLO", "+PONG")).await.is_err());
+
+        let refused = {
+            let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            listener.local_addr().unwrap()
+        };
+        assert!(probe(refused, &config("", "")).await.is_err());
+    }
+}
@@ -34,7 +34,8 @@ union HostPointsTo {
     dynamic_host: ManuallyDrop<DynamicHost>,
 }
 
-#[derive(Copy, Clone)]
+/// Hosts are interned, so two hosts are equal exactly when they point to the same data
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Host {
     ptr: NonNull<HostPointsTo>,
     _marker: PhantomData<HostRpr>,
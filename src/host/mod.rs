@@ -1,8 +1,9 @@
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter, Write};
 use std::io;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::sync::OnceLock;
+use std::sync::{LazyLock, Mutex, PoisonError};
 
 use hickory_resolver::proto::ProtoError as DnsProtoError;
 use hickory_resolver::Name;
@@ -15,40 +16,56 @@ mod host_bitpacked;
 
 pub type Host = host_bitpacked::Host;
 
-pub fn try_insert_with<T, F: FnOnce() -> T>(once_lock: &OnceLock<T>, f: F) -> Option<&T> {
-    let mut run = Some(f);
-    let res = once_lock.get_or_init(|| run.take().unwrap()());
-    match run {
-        None => Some(res),
-        Some(_) => None,
+// Every host ever created, hosts are interned and never freed,
+// so a `Host` can stay a `Copy` pointer, and parsing the same host twice
+// yields the same `Host` which shares its `DnsResolver` cache
+static STATIC_HOSTS: LazyLock<Mutex<HashMap<IpAddr, &'static AlignedIp>>> =
+    LazyLock::new(Default::default);
+static DYNAMIC_HOSTS: LazyLock<Mutex<HashMap<Name, &'static DynamicHost>>> =
+    LazyLock::new(Default::default);
+
+impl Host {
+    pub fn new_static(ip: IpAddr) -> Self {
+        let mut hosts = STATIC_HOSTS.lock().unwrap_or_else(PoisonError::into_inner);
+        Host::from(
+            *hosts
+                .entry(ip)
+                .or_insert_with(|| Box::leak(Box::new(AlignedIp(ip)))),
+        )
+    }
+
+    pub fn new_dynamic(name: Name) -> Self {
+        let mut hosts = DYNAMIC_HOSTS.lock().unwrap_or_else(PoisonError::into_inner);
+        let host = hosts.entry(name).or_insert_with_key(|name| {
+            Box::leak(Box::new(DynamicHost {
+                name: name.clone(),
+                resolver: DnsResolver::default(),
+            }))
+        });
+
+        Host::from(*host)
     }
 }
 
+/// forgets the cached lookups of every host, and returns how many hosts there are
+pub fn flush_dns_caches() -> usize {
+    let hosts = DYNAMIC_HOSTS.lock().unwrap_or_else(PoisonError::into_inner);
+    for host in hosts.values() {
+        host.resolver.flush()
+    }
+    hosts.len()
+}
+
 impl FromStr for Host {
     type Err = DnsProtoError;
 
     fn from_str(s: &str) -> Result<Self, DnsProtoError> {
-        static DYNAMIC_HOST: OnceLock<Result<DynamicHost, DnsProtoError>> = OnceLock::new();
-        static STATIC_HOST: OnceLock<AlignedIp> = OnceLock::new();
-
-        const MANY_HOST_ERROR: &str = "Can only make one host per program";
-
         let s = s.trim();
         if let Ok(addr) = IpAddr::from_str(s) {
-            let rpr = try_insert_with(&STATIC_HOST, || AlignedIp(addr)).expect(MANY_HOST_ERROR);
-
-            return Ok(Host::from(rpr));
+            return Ok(Host::new_static(addr));
         }
 
-        let host = try_insert_with(&DYNAMIC_HOST, || {
-            Name::from_str(s).map(|name| DynamicHost {
-                name,
-                resolver: DnsResolver::default(),
-            })
-        })
-        .expect(MANY_HOST_ERROR);
-
-        Ok(Host::from(host.as_ref().map_err(Clone::clone)?))
+        Name::from_str(s).map(Host::new_dynamic)
     }
 }
 
@@ -94,3 +111,39 @@ impl Debug for Host {
         f.debug_tuple("Host").field(&DebugDisplay(*self)).finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn many_hosts() {
+        let hosts = ["127.0.0.1", "::1", "example.com", "docs.rs", "10.0.0.1"]
+            .map(|host| Host::from_str(host).unwrap());
+
+        for (i, a) in hosts.iter().enumerate() {
+            for (j, b) in hosts.iter().enumerate() {
+                assert_eq!(i == j, a == b, "{a} == {b}");
+            }
+        }
+
+        assert_eq!(
+            hosts.map(|host| host.as_string()),
+            ["127.0.0.1", "::1", "example.com", "docs.rs", "10.0.0.1"]
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn hosts_are_interned() {
+        assert_eq!(
+            Host::from_str("192.0.2.7").unwrap(),
+            Host::from_str(" 192.0.2.7 ").unwrap()
+        );
+        assert_eq!(
+            Host::from_str("vrtgs.xyz").unwrap(),
+            Host::from_str("VRTGS.xyz").unwrap()
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use monoio::io::{AsyncReadRent, AsyncWriteRentExt};
+use monoio::net::{TcpListener, TcpStream};
+use monoio::time::timeout;
+
+/// how long a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// the longest request that's read, body included
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// A request to one of the tiny http servers, metrics and admin
+pub struct Request {
+    pub method: String,
+    /// the path, without the query
+    pub path: String,
+    pub query: String,
+    /// the `Host` header
+    pub host: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// the value of `key` in the query, if it's in it
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find_map(|(k, v)| (k == key).then_some(v))
+    }
+}
+
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// reads a request, its body only if it has a `Content-Length`
+async fn read_request(stream: &mut TcpStream) -> io::Result<Request> {
+    let mut buf = Vec::new();
+    let head = loop {
+        if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if buf.len() > MAX_REQUEST {
+            return Err(invalid("request too long"));
+        }
+
+        let (res, chunk) = stream.read(Vec::with_capacity(1024)).await;
+        match res? {
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            _ => buf.extend_from_slice(&chunk),
+        }
+    };
+
+    let mut lines = buf[..head]
+        .split(|&b| b == b'\n')
+        .map(|line| String::from_utf8_lossy(line.trim_ascii()).into_owned());
+    let request_line = lines.next().unwrap_or_default();
+    let headers = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+        })
+        .collect::<Vec<_>>();
+    let header = |key: &str| {
+        headers
+            .iter()
+            .find_map(|(name, value)| (name == key).then_some(value))
+    };
+    let content_length = header("content-length")
+        .map(|value| value.parse::<usize>())
+        .transpose()
+        .map_err(|_| invalid("invalid content length"))?
+        .unwrap_or(0);
+    if head + 4 + content_length > MAX_REQUEST {
+        return Err(invalid("request too long"));
+    }
+
+    let mut body = buf.split_off(head + 4);
+    while body.len() < content_length {
+        let (res, chunk) = stream.read(Vec::with_capacity(1024)).await;
+        match res? {
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            _ => body.extend_from_slice(&chunk),
+        }
+    }
+    body.truncate(content_length);
+
+    let mut parts = request_line.split(' ');
+    let method = parts.next().unwrap_or_default().to_owned();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Ok(Request {
+        method,
+        path: path.to_owned(),
+        query: query.to_owned(),
+        host: header("host").cloned(),
+        body,
+    })
+}
+
+async fn respond<F>(mut stream: TcpStream, handle: impl Fn(Request) -> F) -> io::Result<()>
+where
+    F: Future<Output = Response>,
+{
+    let request = timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)))?;
+
+    let Response {
+        status,
+        content_type,
+        body,
+    } = handle(request).await;
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    );
+    let (res, _) = stream.write_all(response.into_bytes()).await;
+    res.map(drop)
+}
+
+/// answers every request made on `listener` with what `handle` says, until the process exits,
+/// every connection on a task of its own, `what` names what's served in the logs
+pub async fn serve<F>(
+    listener: TcpListener,
+    what: &'static str,
+    handle: impl Fn(Request) -> F + Clone + 'static,
+) where
+    F: Future<Output = Response>,
+{
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let handle = handle.clone();
+                monoio::spawn(async move {
+                    if let Err(err) = respond(stream, handle).await {
+                        tracing::debug!("Unable to answer a {what} request from `{peer}`: {err}")
+                    }
+                });
+            }
+            Err(err) => {
+                tracing::warn!("Unable to accept a {what} connection: {err}");
+                monoio::time::sleep(Duration::from_millis(100)).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[monoio::test(timer_enabled = true)]
+    #[cfg_attr(miri, ignore)]
+    async fn request() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = monoio::join!(TcpStream::connect_addr(addr), listener.accept());
+        let (mut client, (mut server, _)) = (client.unwrap(), accepted.unwrap());
+
+        let sent = b"PUT /log?verbose=1&x HTTP/1.1\r\nHost: localhost\r\ncontent-length: 15\r\n\r\ninfo,hptp=debug";
+        let (res, _) = client.write_all(sent.to_vec()).await;
+        res.unwrap();
+
+        let request = read_request(&mut server).await.unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/log");
+        assert_eq!(request.param("verbose"), Some("1"));
+        assert_eq!(request.param("x"), None);
+        assert_eq!(request.host.as_deref(), Some("localhost"));
+        assert_eq!(request.body, b"info,hptp=debug");
+    }
+}
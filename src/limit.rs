@@ -0,0 +1,494 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::num::NonZero;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use monoio::time::sleep;
+
+use crate::cidr::{self, Cidr};
+
+/// A token bucket holding up to `capacity` tokens, refilled at `rate` tokens a second
+#[derive(Copy, Clone, Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// a full bucket
+    pub fn new(capacity: f64, rate: f64) -> Self {
+        TokenBucket {
+            capacity,
+            rate,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+
+    /// whether `amount` tokens can be taken without going into debt
+    pub fn has(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= amount
+    }
+
+    /// takes `amount` tokens even if the bucket goes into debt,
+    /// returning how long to wait for the debt to be paid off
+    pub fn take(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= amount;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Limits on a single client, or a whole network of them
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// connections open at the same time
+    pub connections: Option<NonZero<u32>>,
+    /// new connections per second
+    pub rate: Option<NonZero<u32>>,
+}
+
+impl Limits {
+    fn is_unlimited(self) -> bool {
+        self.connections.is_none() && self.rate.is_none()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LimitConfig {
+    pub per_ip: Limits,
+    pub per_prefix: Limits,
+    /// the prefix lengths `per_prefix` groups clients by
+    pub prefix_v4: u8,
+    pub prefix_v6: u8,
+    /// connections open at the same time across every rule,
+    /// once reached no new connections are accepted until one closes
+    pub max_connections: Option<NonZero<usize>>,
+}
+
+impl LimitConfig {
+    pub const DEFAULT_PREFIX_V4: u8 = 24;
+    pub const DEFAULT_PREFIX_V6: u8 = 64;
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        LimitConfig {
+            per_ip: Limits::default(),
+            per_prefix: Limits::default(),
+            prefix_v4: Self::DEFAULT_PREFIX_V4,
+            prefix_v6: Self::DEFAULT_PREFIX_V6,
+            max_connections: None,
+        }
+    }
+}
+
+/// Why a client was refused
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Refused {
+    Connections { client: Client, limit: NonZero<u32> },
+    Rate { client: Client, limit: NonZero<u32> },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Client {
+    Ip(IpAddr),
+    Prefix(Cidr),
+}
+
+impl Display for Client {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Client::Ip(ip) => Display::fmt(ip, f),
+            Client::Prefix(cidr) => write!(f, "network {cidr}"),
+        }
+    }
+}
+
+impl Display for Refused {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refused::Connections { client, limit } => {
+                write!(f, "{client} already has {limit} connections open")
+            }
+            Refused::Rate { client, limit } => {
+                write!(
+                    f,
+                    "{client} is opening more than {limit} connections a second"
+                )
+            }
+        }
+    }
+}
+
+struct Usage {
+    active: u32,
+    bucket: Option<TokenBucket>,
+}
+
+impl Usage {
+    fn new(limits: Limits) -> Self {
+        Usage {
+            active: 0,
+            bucket: limits.rate.map(|rate| {
+                let rate = f64::from(rate.get());
+                TokenBucket::new(rate, rate)
+            }),
+        }
+    }
+
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.active == 0
+            && self
+                .bucket
+                .as_mut()
+                .is_none_or(|bucket| bucket.is_full(now))
+    }
+}
+
+/// the usage of every client, or network, seen recently
+struct Usages<K> {
+    limits: Limits,
+    usages: HashMap<K, Usage>,
+}
+
+impl<K: Hash + Eq + Copy> Usages<K> {
+    fn new(limits: Limits) -> Self {
+        Usages {
+            limits,
+            usages: HashMap::new(),
+        }
+    }
+
+    /// whether `key` can open another connection, without counting it or taking from its rate
+    fn check(&mut self, key: K, client: Client, now: Instant) -> Result<(), Refused> {
+        if self.limits.is_unlimited() {
+            return Ok(());
+        }
+
+        let limits = self.limits;
+        let usage = self.usages.entry(key).or_insert_with(|| Usage::new(limits));
+        if let Some(limit) = limits.connections {
+            if usage.active >= limit.get() {
+                return Err(Refused::Connections { client, limit });
+            }
+        }
+
+        if let (Some(limit), Some(bucket)) = (limits.rate, &mut usage.bucket) {
+            if !bucket.has(1.0, now) {
+                return Err(Refused::Rate { client, limit });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// counts a connection [`Usages::check`] let through
+    fn open(&mut self, key: K, now: Instant) {
+        if let Some(usage) = self.usages.get_mut(&key) {
+            usage.active += 1;
+            if let Some(bucket) = &mut usage.bucket {
+                bucket.take(1.0, now);
+            }
+        }
+    }
+
+    fn close(&mut self, key: K) {
+        if let Some(usage) = self.usages.get_mut(&key) {
+            usage.active = usage.active.saturating_sub(1)
+        }
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        self.usages.retain(|_, usage| !usage.is_idle(now))
+    }
+}
+
+struct Clients {
+    ips: Usages<IpAddr>,
+    prefixes: Usages<Cidr>,
+}
+
+/// Enforces a [`LimitConfig`]
+pub struct Limiter {
+    config: LimitConfig,
+    clients: Mutex<Clients>,
+    /// one token for every connection that can still be opened
+    slots: Option<(flume::Sender<()>, flume::Receiver<()>)>,
+}
+
+/// A connection counted against its client's limits until dropped
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+    prefix: Cidr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.with_clients(|clients| {
+            clients.ips.close(self.ip);
+            clients.prefixes.close(self.prefix);
+        })
+    }
+}
+
+/// A connection counted against the global connection cap until dropped
+pub struct Slot {
+    tokens: Option<flume::Sender<()>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(tokens) = &self.tokens {
+            let _ = tokens.try_send(());
+        }
+    }
+}
+
+impl Limiter {
+    /// how often clients that went quiet are forgotten
+    const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+    pub fn new(config: LimitConfig) -> Self {
+        let slots = config.max_connections.map(|max| {
+            let (tx, rx) = flume::bounded(max.get());
+            for _ in 0..max.get() {
+                let _ = tx.try_send(());
+            }
+            (tx, rx)
+        });
+
+        Limiter {
+            clients: Mutex::new(Clients {
+                ips: Usages::new(config.per_ip),
+                prefixes: Usages::new(config.per_prefix),
+            }),
+            config,
+            slots,
+        }
+    }
+
+    fn with_clients<R>(&self, f: impl FnOnce(&mut Clients) -> R) -> R {
+        f(&mut self.clients.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn prefix(&self, ip: IpAddr) -> Cidr {
+        match ip {
+            IpAddr::V4(_) => Cidr::of(ip, self.config.prefix_v4),
+            IpAddr::V6(_) => Cidr::of(ip, self.config.prefix_v6),
+        }
+    }
+
+    /// counts a new connection from `ip`, unless that puts it, or its network, over a limit
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Permit, Refused> {
+        let ip = cidr::canonical(ip);
+        let prefix = self.prefix(ip);
+        let now = Instant::now();
+
+        self.with_clients(|clients| {
+            clients.ips.check(ip, Client::Ip(ip), now)?;
+            clients
+                .prefixes
+                .check(prefix, Client::Prefix(prefix), now)?;
+            // only once both let it through, so a refused connection doesn't use up any rate
+            clients.ips.open(ip, now);
+            clients.prefixes.open(prefix, now);
+            Ok(())
+        })?;
+
+        Ok(Permit {
+            limiter: Arc::clone(self),
+            ip,
+            prefix,
+        })
+    }
+
+    /// waits until another connection can be opened without going over the connection cap
+    pub async fn slot(&self) -> Slot {
+        let (Some((tx, rx)), Some(max)) = (&self.slots, self.config.max_connections) else {
+            return Slot { tokens: None };
+        };
+
+        if rx.try_recv().is_err() {
+            tracing::warn!("reached the limit of {max} open connections, delaying new ones");
+            let _ = rx.recv_async().await;
+        }
+
+        Slot {
+            tokens: Some(tx.clone()),
+        }
+    }
+
+    /// periodically forgets about clients with nothing open, and nothing counted against their rate
+    pub async fn sweep_forever(&self) -> ! {
+        loop {
+            sleep(Self::SWEEP_INTERVAL).await;
+            let now = Instant::now();
+            self.with_clients(|clients| {
+                clients.ips.sweep(now);
+                clients.prefixes.sweep(now);
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ip(i: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, i))
+    }
+
+    fn limiter(config: LimitConfig) -> Arc<Limiter> {
+        Arc::new(Limiter::new(config))
+    }
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 1.0);
+        assert!(bucket.has(2.0, start));
+        assert_eq!(bucket.take(2.0, start), Duration::ZERO);
+        assert!(!bucket.has(1.0, start));
+        assert!(bucket.has(1.0, start + Duration::from_secs(1)));
+        assert_eq!(
+            bucket.take(1.0, start + Duration::from_secs(1)),
+            Duration::ZERO
+        );
+        assert!(!bucket.is_full(start + Duration::from_secs(1)));
+        assert!(bucket.is_full(start + Duration::from_secs(3)));
+
+        let mut bucket = TokenBucket::new(10.0, 10.0);
+        assert_eq!(bucket.take(5.0, start), Duration::ZERO);
+        assert_eq!(bucket.take(10.0, start), Duration::from_millis(500));
+        assert_eq!(
+            bucket.take(5.0, start + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn connection_limits() {
+        let limiter = limiter(LimitConfig {
+            per_ip: Limits {
+                connections: NonZero::new(2),
+                rate: None,
+            },
+            per_prefix: Limits {
+                connections: NonZero::new(3),
+                rate: None,
+            },
+            ..LimitConfig::default()
+        });
+
+        let first = limiter.admit(ip(1)).unwrap();
+        let _second = limiter.admit(ip(1)).unwrap();
+        assert!(matches!(
+            limiter.admit(ip(1)),
+            Err(Refused::Connections {
+                client: Client::Ip(_),
+                ..
+            })
+        ));
+
+        let _third = limiter.admit(ip(2)).unwrap();
+        assert!(matches!(
+            limiter.admit(ip(3)),
+            Err(Refused::Connections {
+                client: Client::Prefix(_),
+                ..
+            })
+        ));
+        // other networks aren't affected
+        assert!(limiter
+            .admit(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)))
+            .is_ok());
+
+        drop(first);
+        assert!(limiter.admit(ip(1)).is_ok());
+    }
+
+    #[test]
+    fn rate_limits() {
+        let limiter = limiter(LimitConfig {
+            per_ip: Limits {
+                connections: None,
+                rate: NonZero::new(3),
+            },
+            ..LimitConfig::default()
+        });
+
+        for _ in 0..3 {
+            limiter.admit(ip(1)).unwrap();
+        }
+        assert!(matches!(limiter.admit(ip(1)), Err(Refused::Rate { .. })));
+        assert!(limiter.admit(ip(2)).is_ok());
+    }
+
+    #[test]
+    fn refused_connections_take_no_rate() {
+        let limiter = limiter(LimitConfig {
+            per_ip: Limits {
+                connections: None,
+                rate: NonZero::new(2),
+            },
+            per_prefix: Limits {
+                connections: NonZero::new(1),
+                rate: None,
+            },
+            ..LimitConfig::default()
+        });
+
+        let first = limiter.admit(ip(1)).unwrap();
+        for _ in 0..3 {
+            assert!(matches!(
+                limiter.admit(ip(1)),
+                Err(Refused::Connections {
+                    client: Client::Prefix(_),
+                    ..
+                })
+            ));
+        }
+        // the refusals didn't use up the second connection `ip(1)` may open this second
+        drop(first);
+        assert!(limiter.admit(ip(1)).is_ok());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn connection_cap() {
+        let limiter = limiter(LimitConfig {
+            max_connections: NonZero::new(1),
+            ..LimitConfig::default()
+        });
+
+        let slot = limiter.slot().await;
+        let waiting = monoio::time::timeout(Duration::from_millis(50), limiter.slot()).await;
+        assert!(waiting.is_err());
+
+        drop(slot);
+        let _slot = limiter.slot().await;
+    }
+}
@@ -1,24 +1,69 @@
+use futures::future::{self, Either};
+use futures::lock::Mutex;
+use monoio::io::AsyncWriteRentExt;
 use monoio::net::TcpStream;
-use monoio::time::timeout;
-use std::fmt::Display;
+use monoio::time::{sleep, timeout};
+use smallvec::SmallVec;
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::num::NonZero;
-use std::time::Duration;
-use tracing::instrument;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{field, instrument, Span};
 
+use crate::access_log::{AccessLog, Close, Record};
+use crate::acl::AccessList;
+use crate::connect::{ConnectConfig, ConnectOverrides, Failover};
+use crate::health::Health;
 use crate::host::Host;
-use crate::stream::{ManyRecvResult, ManyTcpListener};
+use crate::limit::{Limiter, Permit, Slot};
+use crate::metrics::{Rejection, METRICS};
+use crate::proxy_protocol::{AcceptProxy, SendProxy};
+use crate::reload::ConfigFile;
+use crate::shape::{Buckets, Shaper};
+use crate::shutdown::{Kill, Session, Sessions};
+use crate::sock_io::{Closed, Timeouts, Transferred};
+use crate::stream::{Event, Inherited, ManyRecvResult, ManyTcpListener};
+#[cfg(unix)]
+use crate::upgrade::Handover;
+use crate::upstream::{Selected, Upstreams};
+use crate::worker::{Accepted, ThreadConfig, Workers};
 
+mod access_log;
+mod acl;
+mod admin;
+mod cidr;
+mod connect;
 mod dns_resolver;
+mod health;
 mod host;
+mod http;
+mod limit;
+mod metrics;
+mod payload;
+mod proxy_protocol;
+mod reload;
+mod shape;
+mod shutdown;
+#[cfg(unix)]
+mod socket_activation;
 mod stream;
+#[cfg(unix)]
+mod upgrade;
+mod upstream;
+mod worker;
 
 #[cfg(feature = "cli")]
 mod cli;
 mod sock_io;
 
-#[derive(strum::Display)]
+#[derive(Copy, Clone, strum::Display)]
 enum AllowProtocol {
     #[strum(to_string = "0.0.0.0")]
     Ipv4,
@@ -28,97 +73,882 @@ enum AllowProtocol {
     Both,
 }
 
-enum Never {}
+impl AllowProtocol {
+    fn bind_addrs(self) -> Vec<IpAddr> {
+        const V4: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        const V6: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
 
-impl Never {
-    fn never(self) -> ! {
-        match self {}
+        match self {
+            AllowProtocol::Ipv4 => vec![V4],
+            AllowProtocol::Ipv6 => vec![V6],
+            AllowProtocol::Both => vec![V4, V6],
+        }
     }
 }
 
-#[instrument(level = "error", skip_all, fields(peer = display(_peer), port = display(port)))]
-async fn copy_to(host: Host, port: u16, downstream: TcpStream, _peer: SocketAddr) {
-    let res = async move {
-        let upstream = timeout(Duration::from_secs(15), async {
-            TcpStream::connect(&*host.to_hosts(port).await?).await
-        })
+/// How long getting connected to an upstream host took, across every attempt
+#[derive(Copy, Clone, Default)]
+struct Timings {
+    resolve: Duration,
+    connect: Duration,
+}
+
+/// connects to the addresses of `host` that `connect.failover` allows, within `connect.timeout`
+async fn connect_host(
+    host: Host,
+    port: u16,
+    health: &Health,
+    connect: ConnectConfig,
+    timings: &mut Timings,
+) -> io::Result<TcpStream> {
+    // the lookup counts against the connect timeout too
+    let started = Instant::now();
+    let resolved = timeout(connect.timeout, host.to_hosts(port))
         .await
-        .inspect(|_| tracing::trace!("Successfully connected to {host}"))
-        .inspect_err(|_| tracing::debug!("Connecting to {host} timed out"))??;
+        .inspect_err(|_| tracing::debug!("Resolving {host} timed out"));
+    timings.resolve += started.elapsed();
+    let addrs = health.filter(host, resolved??);
+    let addrs = match connect.failover {
+        Failover::None => &addrs[..addrs.len().min(1)],
+        Failover::Address | Failover::Upstream => &addrs[..],
+    };
 
-        sock_io::copy_socks(downstream, upstream).await
+    let limit = connect.timeout.saturating_sub(started.elapsed());
+    let started = Instant::now();
+    let res = connect::happy_eyeballs(addrs, connect.attempt_delay, limit, |addr, ok| {
+        health.report_connect(addr.ip(), ok)
+    })
+    .await
+    .inspect(|_| tracing::trace!("Successfully connected to {host}"))
+    .inspect_err(|err| {
+        if err.kind() == io::ErrorKind::TimedOut {
+            tracing::debug!("Connecting to {host} timed out")
+        }
+    });
+
+    timings.connect += started.elapsed();
+    match &res {
+        Ok(_) => METRICS.connect_duration.observe(started.elapsed()),
+        Err(err) => METRICS.connect_failures.inc(err.kind()),
+    }
+    res
+}
+
+/// picks an upstream host for `client` and connects to it,
+/// failing over to other hosts and retrying as `connect` says
+async fn connect_upstream<'a>(
+    rule: &'a Rule,
+    port: u16,
+    client: IpAddr,
+    connect: ConnectConfig,
+    timings: &mut Timings,
+) -> io::Result<(Selected<'a>, TcpStream)> {
+    let Rule {
+        upstream, health, ..
+    } = rule;
+
+    let hosts = upstream.hosts().count();
+    let mut retry = 0;
+    loop {
+        let mut tried = SmallVec::<Host, 4>::new();
+        let err = loop {
+            // fail over to the hosts that weren't tried yet, the healthy ones if there are any
+            let untried = |host| !tried.contains(&host);
+            let any_usable = upstream
+                .hosts()
+                .any(|weighted| untried(weighted.host) && health.is_usable(weighted.host));
+            let selected = upstream.select(client, |host| {
+                untried(host) && (!any_usable || health.is_usable(host))
+            });
+
+            let host = selected.host();
+            Span::current().record("upstream", field::display(host));
+            tracing::debug!("Selected upstream {host} ({})", upstream.balance());
+
+            match connect_host(host, port, health, connect, timings).await {
+                Ok(stream) => return Ok((selected, stream)),
+                Err(err) => {
+                    tried.push(host);
+                    if connect.failover != Failover::Upstream || tried.len() >= hosts {
+                        break err;
+                    }
+                    tracing::debug!("Connecting to {host} failed, failing over: {err}");
+                }
+            }
+        };
+
+        if retry >= connect.retries {
+            return Err(err);
+        }
+        retry += 1;
+        let backoff = connect.backoff(retry);
+        tracing::debug!(
+            "Connecting failed, retry {retry} of {} in {backoff:?}: {err}",
+            connect.retries
+        );
+        sleep(backoff).await
+    }
+}
+
+#[instrument(
+    level = "error",
+    skip_all,
+    fields(peer = display(peer), port = display(route.port), upstream = field::Empty)
+)]
+#[allow(clippy::too_many_arguments)]
+async fn copy_to(
+    route: Route,
+    connect: ConnectConfig,
+    access_log: Option<&AccessLog>,
+    session: &Session,
+    downstream: TcpStream,
+    peer: SocketAddr,
+    local: SocketAddr,
+    early_data: Vec<u8>,
+) {
+    let Rule {
+        send_proxy,
+        shaper,
+        timeouts,
+        ..
+    } = &*route.rule;
+
+    let started = Instant::now();
+    let mut timings = Timings::default();
+    let (mut connected, mut upstream_addr) = (false, None);
+    let counters = &session.activity().counters;
+    let proxied = async {
+        // counted as one of the host's active connections until the end
+        let (_selected, mut upstream) = connect_upstream(
+            &route.rule,
+            route.port.get(),
+            peer.ip(),
+            connect,
+            &mut timings,
+        )
+        .await?;
+        connected = true;
+        upstream_addr = upstream.peer_addr().ok();
+        session.set_upstream(upstream_addr);
+
+        if let Some(send_proxy) = send_proxy {
+            send_proxy.send(&mut upstream, peer, local).await?;
+        }
+        if !early_data.is_empty() {
+            let (res, _) = upstream.write_all(early_data).await;
+            res?;
+        }
+
+        let shaping = shaper.connection(peer.ip(), &route.listener);
+        sock_io::copy_socks(downstream, upstream, shaping, *timeouts, counters).await
+    };
+    let (res, killed) = match future::select(pin!(proxied), pin!(session.killed())).await {
+        Either::Left((res, _)) => (res, None),
+        Either::Right((why, _)) => (Err(io::ErrorKind::ConnectionAborted.into()), Some(why)),
+    };
+
+    let Transferred { client, server } = counters.transferred();
+    METRICS.count_bytes(route.listen_port.get(), counters);
+    if res.is_ok() {
+        METRICS.session_duration.observe(started.elapsed());
+    }
+
+    if let Some(access_log) = access_log {
+        let (close, error) = match &res {
+            Ok(Closed { timeout, .. }) => (timeout.map_or(Close::Done, Close::Timeout), None),
+            Err(_) if killed == Some(Kill::Admin) => (Close::Killed, None),
+            Err(_) if killed == Some(Kill::Shutdown) => (Close::Shutdown, None),
+            Err(err) => {
+                let close = match connected {
+                    true => Close::Error,
+                    false => Close::ConnectFailed,
+                };
+                (close, Some(err.to_string()))
+            }
+        };
+
+        access_log.log(Record {
+            time: SystemTime::now(),
+            peer,
+            local,
+            upstream: upstream_addr,
+            resolve: timings.resolve,
+            connect: timings.connect,
+            duration: started.elapsed(),
+            upload: client,
+            download: server,
+            close,
+            error,
+        })
     }
-    .await;
 
     match res {
-        Ok((c, s)) => {
-            tracing::info!("connection successful, metrics {{ client: {c}, server: {s} }}")
+        Ok(Closed { timeout: None, .. }) => {
+            tracing::info!("connection successful, metrics {{ client: {client}, server: {server} }}")
         }
+        Ok(Closed {
+            timeout: Some(timeout),
+            ..
+        }) => tracing::info!(
+            "connection closed by its {timeout}, metrics {{ client: {client}, server: {server} }}"
+        ),
+        Err(_) if killed == Some(Kill::Admin) => tracing::info!(
+            "connection killed through the admin api, metrics {{ client: {client}, server: {server} }}"
+        ),
+        Err(_) if killed == Some(Kill::Shutdown) => tracing::info!(
+            "connection cut off by the shutdown, metrics {{ client: {client}, server: {server} }}"
+        ),
         Err(e) => tracing::error!("{e}"),
     }
 }
 
-async fn listen(ports: Vec<NonZero<u16>>, host: Host, allow: AllowProtocol) -> io::Result<Never> {
-    let mut listener = {
-        let len = ports.len();
-        match allow {
-            proto @ (AllowProtocol::Ipv4 | AllowProtocol::Ipv6) => {
-                let addr = match proto {
-                    AllowProtocol::Ipv4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-                    AllowProtocol::Ipv6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
-                    _ => unreachable!(),
-                };
+/// checks `client` against the global access list, the one of its rule, and the client limits
+fn admit(global: &Global, rule: &Rule, client: SocketAddr) -> Option<Permit> {
+    let res = global
+        .acl
+        .check(client.ip())
+        .and_then(|()| rule.acl.check(client.ip()));
+
+    if let Err(denied) = res {
+        tracing::warn!("Rejected connection from `{client}`: {denied}");
+        METRICS.rejected.inc(Rejection::Acl);
+        return None;
+    }
+
+    global
+        .limiter
+        .admit(client.ip())
+        .inspect_err(|refused| {
+            tracing::warn!("Refused connection from `{client}`: {refused}");
+            METRICS.rejected.inc(Rejection::Limit)
+        })
+        .ok()
+}
+
+/// whether `peer` is a proxy that is going to tell us who the client is
+fn behind_proxy(rule: &Rule, peer: SocketAddr) -> bool {
+    rule.accept_proxy
+        .as_ref()
+        .is_some_and(|accept_proxy| accept_proxy.trusts(peer))
+}
+
+/// a connection that was let through, both permits are held on to until it closes
+struct Admitted {
+    _slot: Slot,
+    session: Session,
+    /// `None` if it came from a proxy, until the proxy said who the client is
+    permit: Option<Permit>,
+}
+
+async fn handle(
+    route: Route,
+    global: Arc<Global>,
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    local: SocketAddr,
+    mut admitted: Admitted,
+) {
+    let (peer, local, early_data) = match &route.rule.accept_proxy {
+        Some(accept_proxy) if accept_proxy.trusts(peer) => {
+            match accept_proxy.accept(&mut stream, peer, local).await {
+                Ok(accepted @ (client, local, _)) => {
+                    if client != peer {
+                        tracing::debug!("Connection from `{peer}` is on behalf of `{client}`");
+                    }
+                    admitted.session.set_client(client, local);
+                    let Some(permit) = admit(&global, &route.rule, client) else {
+                        return;
+                    };
+                    admitted.permit = Some(permit);
+                    accepted
+                }
+                Err(err) => {
+                    tracing::warn!("Dropping connection from `{peer}`: {err}");
+                    METRICS.rejected.inc(Rejection::ProxyProtocol);
+                    return;
+                }
+            }
+        }
+        _ => (peer, local, vec![]),
+    };
+
+    let connect = route.rule.connect.apply(global.connect);
+    let access_log = global.access_log.as_ref();
+    let session = &admitted.session;
+    copy_to(
+        route, connect, access_log, session, stream, peer, local, early_data,
+    )
+    .await;
+    drop(admitted)
+}
+
+/// where connections to `listen` on an address of `rule` get forwarded to
+fn route(rule: &Arc<Rule>, PortMapping { listen, upstream }: PortMapping) -> Route {
+    Route {
+        rule: Arc::clone(rule),
+        listen_port: listen,
+        port: upstream,
+        listener: rule.shaper.listener(),
+    }
+}
+
+/// every address of every rule, and where the connections to it get forwarded to
+fn routes(rules: &[Arc<Rule>]) -> Vec<(SocketAddr, Route)> {
+    rules
+        .iter()
+        .flat_map(|rule| {
+            rule.bind.iter().flat_map(move |&ip| {
+                rule.ports.iter().map(move |&mapping| {
+                    (
+                        SocketAddr::new(ip, mapping.listen.get()),
+                        route(rule, mapping),
+                    )
+                })
+            })
+        })
+        .collect()
+}
+
+/// every route, shared by every thread once the config was reloaded
+type Routes = Arc<[(SocketAddr, Route)]>;
+
+async fn bind(
+    routes: Vec<(SocketAddr, Route)>,
+    reuse_port: bool,
+    inherited: &mut Inherited,
+) -> io::Result<ManyTcpListener<Route>> {
+    let len = routes.len();
+    ManyTcpListener::bind(routes, len, reuse_port, inherited).await
+}
+
+/// starts `config.threads - 1` more threads, each accepting connections on listeners of its own,
+/// waits for all of them to be listening, and returns where to send them reloaded routes
+async fn shard(
+    config: ThreadConfig,
+    routes: &[(SocketAddr, Route)],
+    global: &Arc<Global>,
+) -> io::Result<Vec<flume::Sender<Switch>>> {
+    let (tx, rx) = flume::unbounded();
+    let mut reloads = vec![];
+    for i in 1..config.threads.get() {
+        let (routes, global, tx) = (routes.to_vec(), Arc::clone(global), tx.clone());
+        let (reload_tx, reload_rx) = flume::unbounded();
+        reloads.push(reload_tx);
+        worker::spawn_thread(i, config, move || async move {
+            let listener = match bind(routes, true, &mut Inherited::default()).await {
+                Ok(listener) => listener,
+                Err(err) => return drop(tx.send(Err(err))),
+            };
+            // hanging up lets the main thread know once every thread is listening
+            let _ = tx.send(Ok(()));
+            drop(tx);
+            let workers = Workers::local(&global);
+            accept_loop(listener, &global, &workers, reload_rx, |_| {}).await
+        })?;
+    }
+
+    drop(tx);
+    while let Ok(res) = rx.recv_async().await {
+        res?
+    }
+    Ok(reloads)
+}
+
+/// runs `fut` until `stop` is disconnected
+async fn until(stop: flume::Receiver<()>, fut: impl Future<Output = ()>) {
+    let _ = future::select(pin!(fut), stop.recv_async()).await;
+}
+
+/// starts the tasks `rules` need running in the background,
+/// they're stopped once the returned sender is dropped
+fn spawn_rule_tasks(rules: &[Arc<Rule>]) -> flume::Sender<()> {
+    let (tx, stop) = flume::bounded(0);
+    for rule in rules {
+        if rule.acl.file.is_some() {
+            let rule = Arc::clone(rule);
+            monoio::spawn(until(stop.clone(), async move {
+                rule.acl.file.as_ref().unwrap().watch().await
+            }));
+        }
+
+        if let Some(probe) = rule.health.config().probe.clone() {
+            let rule = Arc::clone(rule);
+            monoio::spawn(until(stop.clone(), async move {
+                let port = rule.ports[0].upstream.get();
+                health::probe_forever(&rule.upstream, &rule.health, &probe, port).await
+            }));
+        }
+    }
+    tx
+}
+
+pub fn log_rules<'a>(rules: impl IntoIterator<Item = &'a Rule>) {
+    for Rule {
+        bind,
+        ports,
+        upstream,
+        ..
+    } in rules
+    {
+        tracing::info!("Listening on ip {bind:?} on ports {ports:?} and forwarding to {upstream}");
+    }
+}
+
+/// What every thread listens on, changed by reloads and through the admin api
+struct Listening {
+    rules: Vec<Arc<Rule>>,
+    routes: Routes,
+    /// where every thread gets the routes to listen on from
+    threads: Vec<flume::Sender<Switch>>,
+    /// stops the tasks the rules need running once dropped
+    _tasks: flume::Sender<()>,
+}
+
+/// Routes a thread is asked to switch to, see [`Listening::set_routes`]
+struct Switch {
+    routes: Routes,
+    /// told whether the addresses that aren't listened on yet could be bound
+    prepared: flume::Sender<io::Result<()>>,
+    /// sent on once every thread bound them, disconnected if one couldn't
+    commit: flume::Receiver<()>,
+}
+
+impl Listening {
+    /// has every thread forward as `rules` say from now on,
+    /// unless their addresses can't be listened on
+    async fn set_rules(&mut self, rules: Vec<Arc<Rule>>) -> io::Result<()> {
+        self.set_routes(routes(&rules)).await?;
+        // the old tasks stop as the new ones start
+        self._tasks = spawn_rule_tasks(&rules);
+        self.rules = rules;
+        Ok(())
+    }
+
+    /// has every thread listen on the addresses of `routes`, and only those,
+    /// if any thread can't bind one of them, every thread keeps the current routes
+    async fn set_routes(&mut self, routes: Vec<(SocketAddr, Route)>) -> io::Result<()> {
+        let routes = Routes::from(routes);
+        let (commit_tx, commit) = flume::unbounded();
+        let (prepared_tx, prepared) = flume::unbounded();
+        for thread in &self.threads {
+            // a thread that stopped accepting is shutting down, and doesn't need to switch
+            let _ = thread.send(Switch {
+                routes: Arc::clone(&routes),
+                prepared: prepared_tx.clone(),
+                commit: commit.clone(),
+            });
+        }
+        drop(prepared_tx);
+
+        let mut ready = 0;
+        while let Ok(res) = prepared.recv_async().await {
+            // returning drops `commit_tx`, which has every thread close what it bound
+            res?;
+            ready += 1;
+        }
+        for _ in 0..ready {
+            let _ = commit_tx.send(());
+        }
+        self.routes = routes;
+        Ok(())
+    }
+}
+
+/// reads the rules again whenever a reload is asked for, and has every thread switch to them,
+/// if the rules can't be read, aren't valid or can't be listened on, the current ones stay in effect
+async fn reload_forever(config_file: Option<ConfigFile>, listening: Rc<Mutex<Listening>>) {
+    loop {
+        reload::requested().await;
+        let Some(config_file) = &config_file else {
+            tracing::warn!("Nothing to reload, the rules were given on the command line");
+            continue;
+        };
+
+        let rules = match (config_file.load)(&config_file.path) {
+            Ok(rules) => rules,
+            Err(err) => {
+                tracing::error!("Unable to reload the config: {err}, keeping the current one");
+                continue;
+            }
+        };
 
-                ManyTcpListener::bind(ports.into_iter().map(|port| (addr, port.get())), len).await?
+        let rules = rules.into_iter().map(Arc::new).collect::<Vec<_>>();
+        match listening.lock().await.set_rules(rules.clone()).await {
+            Ok(()) => {
+                tracing::info!("Reloaded `{}`", config_file.path.display());
+                log_rules(rules.iter().map(|rule| &**rule))
             }
-            AllowProtocol::Both => {
-                ManyTcpListener::bind(
-                    ports.into_iter().flat_map(|port| {
-                        [
-                            SocketAddr::from((Ipv4Addr::UNSPECIFIED, port.get())),
-                            SocketAddr::from((Ipv6Addr::UNSPECIFIED, port.get())),
-                        ]
-                    }),
-                    len * 2,
-                )
-                .await?
+            Err(err) => {
+                tracing::error!("Unable to reload the config: {err}, keeping the current one")
             }
         }
+    }
+}
+
+/// binds the addresses of `switch` that aren't listened on yet, and once every thread could,
+/// listens on exactly those, forwarding to where they say from now on,
+/// returns whether it switched
+async fn switch(listener: &mut ManyTcpListener<Route>, switch: Switch) -> bool {
+    let Switch {
+        routes,
+        prepared,
+        commit,
+    } = switch;
+    let bound = match listener.prepare(routes.iter().map(|(addr, _)| *addr)) {
+        Ok(bound) => {
+            let _ = prepared.send(Ok(()));
+            bound
+        }
+        Err(err) => {
+            let _ = prepared.send(Err(err));
+            return false;
+        }
     };
+    // whoever asked for the switch waits until every thread answered
+    drop(prepared);
+
+    match commit.recv_async().await {
+        Ok(()) => {
+            listener.switch(bound, routes.iter().cloned());
+            true
+        }
+        // dropping what was bound closes it again
+        Err(_) => false,
+    }
+}
+
+/// how long the sessions killed once the drain timeout elapsed get to finish
+const CUT_OFF_TIMEOUT: Duration = Duration::from_secs(1);
+
+async fn listen(args: ProgramArgs) -> io::Result<()> {
+    let ProgramArgs {
+        rules,
+        global,
+        threads,
+        config_file,
+        metrics,
+        admin,
+        #[cfg(unix)]
+        upgrade_socket,
+    } = args;
+    let rules = rules.into_iter().map(Arc::new).collect::<Vec<_>>();
+    let global = Arc::new(global);
+
+    let mut inherited = Inherited::default();
+    #[cfg(unix)]
+    match socket_activation::inherit(&mut inherited, threads.reuse_port)? {
+        0 => {}
+        n => tracing::info!("Took over {n} sockets from systemd"),
+    }
+    #[cfg(unix)]
+    let predecessor = upgrade_socket
+        .as_deref()
+        .map(|path| upgrade::take_over(path, threads.reuse_port, &mut inherited))
+        .transpose()?
+        .flatten();
 
+    let routes = routes(&rules);
+    let listener = bind(routes.clone(), threads.reuse_port, &mut inherited).await?;
+    if inherited.len() != 0 {
+        let unused = inherited.addrs().collect::<Vec<_>>();
+        tracing::info!("Closing the inherited listeners no rule listens on {unused:?}");
+        drop(inherited)
+    }
+
+    {
+        let global = Arc::clone(&global);
+        monoio::spawn(async move { global.limiter.sweep_forever().await });
+    }
+
+    if let Some(addr) = metrics {
+        let listener = monoio::net::TcpListener::bind(addr)?;
+        tracing::info!("Serving metrics on http://{addr}/metrics");
+        monoio::spawn(metrics::serve(listener, Arc::clone(&global)));
+    }
+
+    if global.acl.file.is_some() {
+        let global = Arc::clone(&global);
+        monoio::spawn(async move { global.acl.file.as_ref().unwrap().watch().await });
+    }
+
+    let tasks = spawn_rule_tasks(&rules);
+    let (reload_tx, reloads) = flume::unbounded();
+    let (workers, mut reload_threads) = match threads.reuse_port {
+        true => {
+            let reloads = shard(threads, &routes, &global).await?;
+            (Workers::local(&global), reloads)
+        }
+        false => (Workers::spawn(threads, &global)?, vec![]),
+    };
+    reload_threads.push(reload_tx);
+    let listening = Rc::new(Mutex::new(Listening {
+        rules,
+        routes: Routes::from(routes),
+        threads: reload_threads,
+        _tasks: tasks,
+    }));
+    if let Some(config_file) = config_file.as_ref().filter(|config_file| config_file.watch) {
+        let path = config_file.path.clone();
+        monoio::spawn(async move { reload::watch(&path).await });
+    }
+    monoio::spawn(reload_forever(config_file, Rc::clone(&listening)));
+    if let Some(admin::Admin { addr, log_filter }) = admin {
+        let listener = monoio::net::TcpListener::bind(addr)?;
+        tracing::info!("Serving the admin api on http://{addr}");
+        let state = admin::State {
+            addr,
+            global: Arc::clone(&global),
+            listening,
+            log_filter: Rc::from(log_filter),
+        };
+        monoio::spawn(admin::serve(listener, state));
+    }
+    if threads.pin {
+        worker::pin(0)
+    }
+
+    match (threads.threads.get(), threads.reuse_port) {
+        (1, _) => {}
+        (n, true) => tracing::info!("Accepting and handling connections on {n} threads"),
+        (n, false) => tracing::info!("Handling connections on {n} threads"),
+    }
+
+    #[cfg(unix)]
+    let handover = match upgrade_socket {
+        Some(path) => Some(Handover::serve(&path, listener.try_clone_fds()?)?),
+        None => None,
+    };
+    #[cfg(unix)]
+    if let Some(predecessor) = predecessor {
+        if let Err(err) = predecessor.ready() {
+            tracing::warn!("Unable to tell the process being replaced to shut down: {err}")
+        }
+    }
+
+    // the process replacing this one takes over the listeners of the new config
+    #[cfg(unix)]
+    let after_reload = |listener: &ManyTcpListener<Route>| {
+        if let Some(handover) = &handover {
+            match listener.try_clone_fds() {
+                Ok(fds) => handover.set(fds),
+                Err(err) => tracing::warn!("Unable to hand over the reloaded listeners: {err}"),
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let after_reload = |_: &ManyTcpListener<Route>| {};
+    accept_loop(listener, &global, &workers, reloads, after_reload).await;
+    drop(workers);
+    #[cfg(unix)]
+    if let Some(handover) = handover {
+        handover.close()
+    }
+
+    let open = global.sessions.count();
+    if open != 0 {
+        let drain = global.drain_timeout;
+        tracing::info!("Stopped listening, waiting up to {drain:?} for {open} sessions to finish");
+    }
+    match global.sessions.drain(global.drain_timeout).await {
+        0 => tracing::info!("Every session finished, exiting"),
+        open => {
+            tracing::warn!("Drain timeout elapsed, closing {open} sessions that are still open");
+            global.sessions.kill_all();
+            // long enough for them to log how they ended
+            global.sessions.drain(CUT_OFF_TIMEOUT).await;
+        }
+    }
+    if let Some(access_log) = &global.access_log {
+        access_log.close()
+    }
+    Ok(())
+}
+
+/// accepts connections off `listener` until a shutdown is asked for,
+/// and hands the ones that are let through to `workers`,
+/// whenever routes are reloaded `listener` switches to them,
+/// `after_reload` is called then, and whenever a listener failed or was restarted
+async fn accept_loop(
+    mut listener: ManyTcpListener<Route>,
+    global: &Global,
+    workers: &Workers,
+    reloads: flume::Receiver<Switch>,
+    after_reload: impl Fn(&ManyTcpListener<Route>),
+) {
+    let mut shutdown = pin!(shutdown::requested());
     loop {
-        let res = listener
-            .accept()
-            .await
-            .inspect(|ManyRecvResult { local, peer, .. }| {
-                tracing::info!("New connection from `{peer}` to `{local}`")
+        let next = {
+            let accepted = pin!(async {
+                let slot = global.limiter.slot().await;
+                (slot, listener.accept().await)
+            });
+            let reloaded = pin!(async {
+                match reloads.recv_async().await {
+                    Ok(switch) => switch,
+                    // nothing is going to be reloaded anymore
+                    Err(_) => future::pending().await,
+                }
             });
+            match future::select(accepted, future::select(shutdown.as_mut(), reloaded)).await {
+                Either::Left((accepted, _)) => Either::Left(accepted),
+                // dropping the listener stops listening right away
+                Either::Right((Either::Left(((), _)), _)) => break,
+                Either::Right((Either::Right((switch, _)), _)) => Either::Right(switch),
+            }
+        };
+
+        let (slot, event) = match next {
+            Either::Left(accepted) => accepted,
+            Either::Right(to) => {
+                if switch(&mut listener, to).await {
+                    after_reload(&listener);
+                }
+                continue;
+            }
+        };
 
-        let Ok(ManyRecvResult {
+        let ManyRecvResult {
             stream,
-            local,
             peer,
-        }) = res
-        else {
-            tracing::warn!("Connection failed {res:?}");
-            continue;
+            local,
+            tag: route,
+        } = match event {
+            Event::Accepted(accepted) => accepted,
+            Event::Failed(err) => {
+                tracing::error!("{err}");
+                after_reload(&listener);
+                continue;
+            }
+            Event::Restarted(addr) => {
+                tracing::info!("Listening on `{addr}` again");
+                after_reload(&listener);
+                continue;
+            }
+        };
+        tracing::info!("New connection from `{peer}` to `{local}`");
+        METRICS.accepted.inc();
+
+        // a proxy's clients can only be checked once it said who they are
+        let permit = match behind_proxy(&route.rule, peer) {
+            true => None,
+            false => match admit(global, &route.rule, peer) {
+                Some(permit) => Some(permit),
+                None => continue,
+            },
         };
 
-        monoio::spawn(copy_to(host, local.port(), stream, peer));
+        let admitted = Admitted {
+            _slot: slot,
+            session: global.sessions.open(peer, local),
+            permit,
+        };
+        workers
+            .dispatch(Accepted {
+                stream,
+                peer,
+                local,
+                route,
+                admitted,
+            })
+            .await
+    }
+}
+
+/// A listening port, and the port on the upstream host it forwards to
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub listen: NonZero<u16>,
+    pub upstream: NonZero<u16>,
+}
+
+impl Debug for PortMapping {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.listen == self.upstream {
+            true => write!(f, "{}", self.listen),
+            false => write!(f, "{}:{}", self.listen, self.upstream),
+        }
     }
 }
 
+/// Where connections accepted by a listener get forwarded to
+#[derive(Clone)]
+struct Route {
+    rule: Arc<Rule>,
+    /// the port the connections were accepted on
+    listen_port: NonZero<u16>,
+    /// the port on the upstream host
+    port: NonZero<u16>,
+    /// the bandwidth shared by every connection to this listener
+    listener: Buckets,
+}
+
+impl Debug for Route {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.rule.upstream, self.port)
+    }
+}
+
+/// A single forwarding rule, every port on every bind address gets forwarded to `upstream`
+pub struct Rule {
+    bind: Vec<IpAddr>,
+    ports: Vec<PortMapping>,
+    upstream: Upstreams,
+    health: Health,
+    /// a PROXY protocol header to expect from trusted peers
+    accept_proxy: Option<AcceptProxy>,
+    /// a PROXY protocol header to send to the upstream before anything else
+    send_proxy: Option<SendProxy>,
+    acl: AccessList,
+    shaper: Shaper,
+    timeouts: Timeouts,
+    /// changes to the global connect settings, just for this rule
+    connect: ConnectOverrides,
+}
+
+/// Settings shared by every rule
+pub struct Global {
+    connect: ConnectConfig,
+    acl: AccessList,
+    limiter: Arc<Limiter>,
+    /// every session still open, waited on before exiting
+    sessions: Sessions,
+    /// how long to wait for the sessions still open once a shutdown was asked for
+    drain_timeout: Duration,
+    /// where a record of every session that ended is written
+    access_log: Option<AccessLog>,
+}
+
 pub struct ProgramArgs {
-    ports: Vec<NonZero<u16>>,
-    host: Host,
-    allow: AllowProtocol,
+    rules: Vec<Rule>,
+    global: Global,
+    /// how many threads handle connections, each running its own runtime
+    threads: ThreadConfig,
+    /// where `rules` came from, if they can be reloaded
+    config_file: Option<ConfigFile>,
+    /// where to serve prometheus metrics over http
+    metrics: Option<SocketAddr>,
+    admin: Option<admin::Admin>,
+    /// where to hand the listeners over to the process replacing this one,
+    /// and take them over from the one this replaces
+    #[cfg(unix)]
+    upgrade_socket: Option<PathBuf>,
 }
 
-pub async fn real_main(args: ProgramArgs) -> ! {
-    listen(args.ports, args.host, args.allow)
-        .await
-        .map(Never::never)
-        .unwrap_or_else(|err| panic!("{err}"))
+/// runs `fut` to completion on a new runtime for the current thread
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    monoio::RuntimeBuilder::<monoio::FusionDriver>::new()
+        .enable_timer()
+        .build()
+        .expect("unable to build the runtime")
+        .block_on(fut)
+}
+
+pub async fn real_main(args: ProgramArgs) {
+    if let Err(err) = shutdown::handle_signals() {
+        tracing::error!("Unable to handle signals, shutdowns won't be graceful: {err}")
+    }
+
+    listen(args).await.unwrap_or_else(|err| panic!("{err}"))
 }
 
 pub fn set_hooks() {
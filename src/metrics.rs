@@ -0,0 +1,351 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::Duration;
+
+use monoio::net::TcpListener;
+
+use crate::http::{self, Request, Response};
+use crate::shutdown::Sessions;
+use crate::sock_io::{Counters, Transferred};
+use crate::Global;
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters told apart by their labels
+pub struct Family<K>(Mutex<BTreeMap<K, u64>>);
+
+impl<K: Ord> Family<K> {
+    const fn new() -> Self {
+        Family(Mutex::new(BTreeMap::new()))
+    }
+
+    pub fn add(&self, labels: K, n: u64) {
+        let mut counters = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        *counters.entry(labels).or_default() += n
+    }
+
+    pub fn inc(&self, labels: K) {
+        self.add(labels, 1)
+    }
+
+    fn snapshot(&self) -> Vec<(K, u64)>
+    where
+        K: Clone,
+    {
+        let counters = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        counters.iter().map(|(k, &v)| (k.clone(), v)).collect()
+    }
+}
+
+/// A histogram of durations, in seconds
+pub struct Histogram {
+    /// the upper bound of every bucket, in seconds
+    bounds: &'static [f64],
+    /// how many observations fell in every bucket, and the +Inf one last
+    buckets: Box<[AtomicU64]>,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = self.bounds.partition_point(|&bound| bound < secs);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+/// Why a connection was turned away
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, strum::Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum Rejection {
+    /// an access list doesn't allow the client
+    Acl,
+    /// the client has too many connections open, or opens them too fast
+    Limit,
+    /// a trusted proxy didn't send a valid PROXY protocol header in time
+    ProxyProtocol,
+}
+
+/// Which way bytes went through the proxy
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Direction {
+    /// from the client to the upstream
+    Upload,
+    /// from the upstream to the client
+    Download,
+}
+
+pub struct Metrics {
+    pub accepted: Counter,
+    pub rejected: Family<Rejection>,
+    /// by listening port, counted as they're sent through [`Metrics::count_bytes`]
+    pub bytes: Family<(u16, Direction)>,
+    pub connect_duration: Histogram,
+    pub connect_failures: Family<io::ErrorKind>,
+    pub dns_hits: Counter,
+    pub dns_misses: Counter,
+    pub session_duration: Histogram,
+}
+
+/// the metrics of every thread
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics {
+    accepted: Counter::new(),
+    rejected: Family::new(),
+    bytes: Family::new(),
+    connect_duration: Histogram::new(&[
+        0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+    ]),
+    connect_failures: Family::new(),
+    dns_hits: Counter::new(),
+    dns_misses: Counter::new(),
+    session_duration: Histogram::new(&[
+        0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0, 21600.0,
+    ]),
+});
+
+/// writes metrics in the prometheus text format
+struct Exposition(String);
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}");
+        let _ = writeln!(self.0, "# TYPE {name} {kind}");
+    }
+
+    fn counter(&mut self, name: &str, help: &str, counter: &Counter) {
+        self.header(name, "counter", help);
+        let _ = writeln!(self.0, "{name} {}", counter.get());
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: impl Display) {
+        self.header(name, "gauge", help);
+        let _ = writeln!(self.0, "{name} {value}");
+    }
+
+    fn family<K: Ord + Clone>(
+        &mut self,
+        name: &str,
+        help: &str,
+        family: &Family<K>,
+        labels: impl Fn(&K) -> String,
+    ) {
+        self.header(name, "counter", help);
+        for (k, v) in family.snapshot() {
+            let _ = writeln!(self.0, "{name}{{{}}} {v}", labels(&k));
+        }
+    }
+
+    fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, "histogram", help);
+        let mut count = 0;
+        for (i, bucket) in histogram.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = match histogram.bounds.get(i) {
+                Some(bound) => writeln!(self.0, "{name}_bucket{{le=\"{bound}\"}} {count}"),
+                None => writeln!(self.0, "{name}_bucket{{le=\"+Inf\"}} {count}"),
+            };
+        }
+        let sum = Duration::from_nanos(histogram.sum_nanos.load(Ordering::Relaxed));
+        let _ = writeln!(self.0, "{name}_sum {}", sum.as_secs_f64());
+        let _ = writeln!(self.0, "{name}_count {count}");
+    }
+}
+
+impl Metrics {
+    /// adds the bytes a session on `port` sent since they were last counted
+    pub fn count_bytes(&self, port: u16, counters: &Counters) {
+        let Transferred { client, server } = counters.take_new();
+        self.bytes.add((port, Direction::Upload), client);
+        self.bytes.add((port, Direction::Download), server);
+    }
+
+    /// every metric in the prometheus text format,
+    /// the bytes of the sessions still open are counted first
+    pub fn render(&self, sessions: &Sessions) -> String {
+        let open = sessions.list();
+        for activity in &open {
+            self.count_bytes(activity.port, &activity.counters)
+        }
+
+        let mut out = Exposition(String::new());
+        out.counter(
+            "hptp_connections_accepted_total",
+            "Connections accepted on any listener",
+            &self.accepted,
+        );
+        out.family(
+            "hptp_connections_rejected_total",
+            "Connections turned away, by reason",
+            &self.rejected,
+            |reason| format!("reason=\"{reason}\""),
+        );
+        out.gauge(
+            "hptp_sessions_active",
+            "Sessions currently open",
+            open.len(),
+        );
+        out.family(
+            "hptp_bytes_total",
+            "Bytes proxied by listening port and direction",
+            &self.bytes,
+            |(port, direction)| format!("port=\"{port}\",direction=\"{direction}\""),
+        );
+        out.histogram(
+            "hptp_upstream_connect_duration_seconds",
+            "How long connecting to an upstream host took",
+            &self.connect_duration,
+        );
+        out.family(
+            "hptp_upstream_connect_failures_total",
+            "Failed attempts at connecting to an upstream host, by error kind",
+            &self.connect_failures,
+            |kind| format!("kind=\"{kind:?}\""),
+        );
+        out.counter(
+            "hptp_dns_cache_hits_total",
+            "Upstream host lookups answered from the DNS cache",
+            &self.dns_hits,
+        );
+        out.counter(
+            "hptp_dns_cache_misses_total",
+            "Upstream host lookups that had to be resolved",
+            &self.dns_misses,
+        );
+        out.histogram(
+            "hptp_session_duration_seconds",
+            "How long sessions were open for",
+            &self.session_duration,
+        );
+        out.0
+    }
+}
+
+fn respond(request: &Request, global: &Global) -> Response {
+    let response = |status, body| Response {
+        status,
+        content_type: "text/plain; version=0.0.4",
+        body,
+    };
+    match (&*request.method, &*request.path) {
+        ("GET", "/metrics") => response("200 OK", METRICS.render(&global.sessions)),
+        (_, "/metrics") => response("405 Method Not Allowed", String::new()),
+        _ => response("404 Not Found", String::new()),
+    }
+}
+
+/// serves the metrics on `listener` until the process exits
+pub async fn serve(listener: TcpListener, global: Arc<Global>) {
+    http::serve(listener, "metrics", move |request| {
+        future::ready(respond(&request, &global))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn histogram() {
+        let histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(100));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(5));
+
+        let mut out = Exposition(String::new());
+        out.histogram("h", "help", &histogram);
+        let lines = out.0.lines().skip(2).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "h_bucket{le=\"0.1\"} 2",
+                "h_bucket{le=\"1\"} 3",
+                "h_bucket{le=\"+Inf\"} 4",
+                "h_sum 5.65",
+                "h_count 4",
+            ]
+        );
+    }
+
+    #[test]
+    fn family() {
+        let family = Family::new();
+        family.add((443, Direction::Download), 10);
+        family.add((80, Direction::Upload), 1);
+        family.add((443, Direction::Download), 5);
+
+        let mut out = Exposition(String::new());
+        out.family("b", "help", &family, |(port, direction)| {
+            format!("port=\"{port}\",direction=\"{direction}\"")
+        });
+        let lines = out.0.lines().skip(2).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "b{port=\"80\",direction=\"upload\"} 1",
+                "b{port=\"443\",direction=\"download\"} 15",
+            ]
+        );
+    }
+
+    #[test]
+    fn bytes_of_open_sessions() {
+        // a port no other test uses, the metrics are shared
+        let port = 64999;
+        let bytes = |direction| {
+            METRICS
+                .bytes
+                .snapshot()
+                .into_iter()
+                .find_map(|(labels, n)| (labels == (port, direction)).then_some(n))
+        };
+        let sessions = Sessions::default();
+        let local = SocketAddr::from(([127, 0, 0, 1], port));
+        let session = sessions.open(local, local);
+        let counters = &session.activity().counters;
+
+        counters.client.fetch_add(100, Ordering::Relaxed);
+        METRICS.render(&sessions);
+        assert_eq!(bytes(Direction::Upload), Some(100));
+
+        counters.client.fetch_add(20, Ordering::Relaxed);
+        counters.server.fetch_add(7, Ordering::Relaxed);
+        METRICS.render(&sessions);
+        METRICS.count_bytes(port, counters);
+        drop(session);
+        METRICS.render(&sessions);
+        assert_eq!(bytes(Direction::Upload), Some(120));
+        assert_eq!(bytes(Direction::Download), Some(7));
+    }
+}
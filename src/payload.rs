@@ -0,0 +1,82 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug)]
+#[error("invalid escape sequence in `{0}`, expected one of \\n \\r \\t \\0 \\\\ \\' \\\" or \\xHH")]
+pub struct PayloadParseError(String);
+
+/// Raw bytes, written as a string with `\xHH` style escapes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Payload(Vec<u8>);
+
+impl Payload {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for Payload {
+    type Err = PayloadParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PayloadParseError(s.to_owned());
+
+        let mut bytes = Vec::with_capacity(s.len());
+        let mut rest = s.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            rest = tail;
+            if byte != b'\\' {
+                bytes.push(byte);
+                continue;
+            }
+
+            let (&escape, tail) = rest.split_first().ok_or_else(err)?;
+            rest = tail;
+            bytes.push(match escape {
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b't' => b'\t',
+                b'0' => b'\0',
+                b'\\' | b'\'' | b'"' => escape,
+                b'x' => {
+                    let (hex, tail) = rest.split_at_checked(2).ok_or_else(err)?;
+                    rest = tail;
+                    std::str::from_utf8(hex)
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                        .ok_or_else(err)?
+                }
+                _ => return Err(err()),
+            })
+        }
+
+        Ok(Payload(bytes))
+    }
+}
+
+impl Display for Payload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0.escape_ascii(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_payload() {
+        let payload = r#"PING\r\n\x00\"\\"#.parse::<Payload>().unwrap();
+        assert_eq!(payload.0, b"PING\r\n\0\"\\");
+        assert_eq!(payload.to_string().parse::<Payload>().unwrap(), payload);
+
+        assert!(r"\".parse::<Payload>().is_err());
+        assert!(r"\q".parse::<Payload>().is_err());
+        assert!(r"\x4".parse::<Payload>().is_err());
+        assert!(r"\xzz".parse::<Payload>().is_err());
+    }
+}
@@ -0,0 +1,515 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use std::time::Duration;
+
+use monoio::io::{AsyncReadRent, AsyncWriteRentExt};
+use monoio::net::TcpStream;
+use monoio::time::timeout;
+
+use crate::cidr::{self, Cidrs};
+use crate::payload::{Payload, PayloadParseError};
+
+/// The PROXY protocol version, as described in
+/// <https://www.haproxy.org/download/3.1/doc/proxy-protocol.txt>
+#[derive(Copy, Clone, Debug, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Version {
+    /// the human readable header
+    V1,
+    /// the binary header, which can carry TLVs
+    V2,
+}
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// the longest a v1 header can be, including the CRLF
+const V1_MAX_LEN: usize = 107;
+/// the longest the addresses in a v2 header can be, two ipv6 addresses and ports
+const V2_MAX_ADDRS_LEN: usize = 36;
+
+#[derive(thiserror::Error, Debug)]
+pub enum TlvParseError {
+    #[error("invalid TLV `{0}`, expected <type>=<value> where <type> is a number in 0..=255")]
+    Invalid(String),
+    #[error(transparent)]
+    Value(#[from] PayloadParseError),
+    #[error("TLV value is {0} bytes long, at most {max} bytes fit", max = u16::MAX)]
+    TooLong(usize),
+    #[error(
+        "the TLVs take {0} bytes, at most {max} bytes fit in a v2 header",
+        max = usize::from(u16::MAX) - V2_MAX_ADDRS_LEN
+    )]
+    TooManyBytes(usize),
+}
+
+/// A type-length-value extension to the v2 header, written as `<type>=<value>`,
+/// the type being decimal or `0x` prefixed hex, and the value being a [`Payload`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tlv {
+    pub kind: u8,
+    pub value: Payload,
+}
+
+impl FromStr for Tlv {
+    type Err = TlvParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TlvParseError::Invalid(s.to_owned());
+        let (kind, value) = s.split_once('=').ok_or_else(invalid)?;
+        let kind = kind.trim();
+        let kind = match kind.strip_prefix("0x").or_else(|| kind.strip_prefix("0X")) {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => kind.parse(),
+        }
+        .map_err(|_| invalid())?;
+
+        let value = value.parse::<Payload>()?;
+        match value.as_bytes().len() {
+            len if len > usize::from(u16::MAX) => Err(TlvParseError::TooLong(len)),
+            _ => Ok(Tlv { kind, value }),
+        }
+    }
+}
+
+impl Display for Tlv {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#04x}={}", self.kind, self.value)
+    }
+}
+
+/// How the client's address is passed on to the upstream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SendProxy {
+    pub version: Version,
+    /// only sent with [`Version::V2`]
+    tlvs: Vec<Tlv>,
+}
+
+/// both addresses have to be of the same family,
+/// so an ipv4 address paired with an ipv6 one gets mapped to ipv6
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let unmap = |addr: SocketAddr| SocketAddr::new(cidr::canonical(addr.ip()), addr.port());
+    let map = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+
+    match (unmap(src), unmap(dst)) {
+        (src @ SocketAddr::V4(_), dst @ SocketAddr::V4(_)) => (src, dst),
+        (src, dst) => (map(src), map(dst)),
+    }
+}
+
+impl SendProxy {
+    /// fails if the `tlvs` don't fit in a v2 header along with the addresses,
+    /// its length being a `u16`
+    pub fn new(version: Version, tlvs: Vec<Tlv>) -> Result<Self, TlvParseError> {
+        let len = tlvs
+            .iter()
+            .map(|tlv| 3 + tlv.value.as_bytes().len())
+            .sum::<usize>();
+        match V2_MAX_ADDRS_LEN + len > usize::from(u16::MAX) {
+            true => Err(TlvParseError::TooManyBytes(len)),
+            false => Ok(SendProxy { version, tlvs }),
+        }
+    }
+
+    /// the header telling the upstream a client at `src` connected to us at `dst`
+    pub fn header(&self, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        let (src, dst) = same_family(src, dst);
+        match self.version {
+            Version::V1 => {
+                let family = match src {
+                    SocketAddr::V4(_) => "TCP4",
+                    SocketAddr::V6(_) => "TCP6",
+                };
+                format!(
+                    "PROXY {family} {} {} {} {}\r\n",
+                    src.ip(),
+                    dst.ip(),
+                    src.port(),
+                    dst.port()
+                )
+                .into_bytes()
+            }
+            Version::V2 => {
+                let mut body = Vec::with_capacity(V2_MAX_ADDRS_LEN);
+                let family = match (src, dst) {
+                    (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+                        body.extend_from_slice(&src.ip().octets());
+                        body.extend_from_slice(&dst.ip().octets());
+                        0x11
+                    }
+                    _ => {
+                        let octets = |addr: SocketAddr| match addr.ip() {
+                            IpAddr::V6(ip) => ip.octets(),
+                            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+                        };
+                        body.extend_from_slice(&octets(src));
+                        body.extend_from_slice(&octets(dst));
+                        0x21
+                    }
+                };
+                body.extend_from_slice(&src.port().to_be_bytes());
+                body.extend_from_slice(&dst.port().to_be_bytes());
+
+                // the lengths fit, as checked by `SendProxy::new`
+                for Tlv { kind, value } in &self.tlvs {
+                    let value = value.as_bytes();
+                    body.push(*kind);
+                    body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                    body.extend_from_slice(value);
+                }
+
+                let mut header = Vec::with_capacity(16 + body.len());
+                header.extend_from_slice(&V2_SIGNATURE);
+                // version 2, PROXY command
+                header.push(0x21);
+                header.push(family);
+                header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+                header.extend_from_slice(&body);
+                header
+            }
+        }
+    }
+
+    pub async fn send(
+        &self,
+        stream: &mut TcpStream,
+        src: SocketAddr,
+        dst: SocketAddr,
+    ) -> io::Result<()> {
+        let (res, _) = stream.write_all(self.header(src, dst)).await;
+        res.map(drop)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum HeaderError {
+    #[error("connection didn't start with a PROXY protocol header")]
+    Missing,
+    #[error("malformed PROXY protocol header")]
+    Malformed,
+}
+
+impl From<HeaderError> for io::Error {
+    fn from(err: HeaderError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// the source and destination a header carried, and how long it was,
+/// the addresses are `None` for a LOCAL command, or for anything other than tcp over ip
+type Parsed = (Option<(SocketAddr, SocketAddr)>, usize);
+
+fn parse_v1(buf: &[u8]) -> Result<Option<Parsed>, HeaderError> {
+    let Some(end) = buf
+        .windows(2)
+        .take(V1_MAX_LEN - 1)
+        .position(|w| w == b"\r\n")
+    else {
+        return match buf.len() >= V1_MAX_LEN {
+            true => Err(HeaderError::Malformed),
+            false => Ok(None),
+        };
+    };
+
+    let line =
+        std::str::from_utf8(&buf[V1_PREFIX.len()..end]).map_err(|_| HeaderError::Malformed)?;
+    let mut fields = line.split(' ');
+    let addrs = match fields.next() {
+        Some("UNKNOWN") => None,
+        Some(family @ ("TCP4" | "TCP6")) => {
+            let mut next = || fields.next().ok_or(HeaderError::Malformed);
+            let (src, dst, sport, dport) = (next()?, next()?, next()?, next()?);
+            let ip = |s: &str| match s.parse::<IpAddr>() {
+                Ok(ip) if ip.is_ipv4() == (family == "TCP4") => Ok(ip),
+                _ => Err(HeaderError::Malformed),
+            };
+            let port = |s: &str| s.parse::<u16>().map_err(|_| HeaderError::Malformed);
+            if fields.next().is_some() {
+                return Err(HeaderError::Malformed);
+            }
+
+            Some((
+                SocketAddr::new(ip(src)?, port(sport)?),
+                SocketAddr::new(ip(dst)?, port(dport)?),
+            ))
+        }
+        _ => return Err(HeaderError::Malformed),
+    };
+
+    Ok(Some((addrs, end + 2)))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<Parsed>, HeaderError> {
+    let Some(&[version_command, family, len_hi, len_lo]) = buf.get(12..16) else {
+        return Ok(None);
+    };
+
+    let len = 16 + usize::from(u16::from_be_bytes([len_hi, len_lo]));
+    let Some(body) = buf.get(16..len) else {
+        return Ok(None);
+    };
+
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    let addrs = match (version_command, family) {
+        // LOCAL, the connection was made by the proxy itself
+        (0x20, _) => None,
+        (0x21, 0x11) if body.len() >= 12 => {
+            let ip = |at: usize| IpAddr::from(<[u8; 4]>::try_from(&body[at..at + 4]).unwrap());
+            Some((
+                SocketAddr::new(ip(0), port(8)),
+                SocketAddr::new(ip(4), port(10)),
+            ))
+        }
+        (0x21, 0x21) if body.len() >= 36 => {
+            let ip = |at: usize| IpAddr::from(<[u8; 16]>::try_from(&body[at..at + 16]).unwrap());
+            Some((
+                SocketAddr::new(ip(0), port(32)),
+                SocketAddr::new(ip(16), port(34)),
+            ))
+        }
+        (0x21, 0x11 | 0x21) => return Err(HeaderError::Malformed),
+        (0x21, _) => None,
+        _ => return Err(HeaderError::Malformed),
+    };
+
+    Ok(Some((addrs, len)))
+}
+
+/// parses a v1 or v2 header at the start of `buf`,
+/// returning `None` if `buf` doesn't hold all of it yet
+fn parse(buf: &[u8]) -> Result<Option<Parsed>, HeaderError> {
+    let starts_with = |prefix: &[u8]| {
+        let len = buf.len().min(prefix.len());
+        buf[..len] == prefix[..len]
+    };
+
+    match () {
+        _ if buf.is_empty() => Ok(None),
+        _ if starts_with(&V2_SIGNATURE) => match buf.len() < V2_SIGNATURE.len() {
+            true => Ok(None),
+            false => parse_v2(buf),
+        },
+        _ if starts_with(V1_PREFIX) => match buf.len() < V1_PREFIX.len() {
+            true => Ok(None),
+            false => parse_v1(buf),
+        },
+        _ => Err(HeaderError::Missing),
+    }
+}
+
+/// Recovering the client's address from a PROXY protocol header sent by a proxy in front of us
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AcceptProxy {
+    /// only these peers are trusted to send a header, anyone else is taken to be the client
+    pub trusted: Cidrs,
+    /// how long a trusted peer has to send its header
+    pub timeout: Duration,
+}
+
+impl AcceptProxy {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+    pub fn trusts(&self, peer: SocketAddr) -> bool {
+        self.trusted.contains(peer.ip())
+    }
+
+    /// reads the header a trusted `peer` starts the connection with,
+    /// returning the client's address, the address it connected to,
+    /// and whatever was read past the header
+    pub async fn accept(
+        &self,
+        stream: &mut TcpStream,
+        peer: SocketAddr,
+        local: SocketAddr,
+    ) -> io::Result<(SocketAddr, SocketAddr, Vec<u8>)> {
+        if !self.trusts(peer) {
+            tracing::trace!("not expecting a PROXY protocol header from untrusted peer {peer}");
+            return Ok((peer, local, vec![]));
+        }
+
+        let read = async {
+            let mut buf = Vec::new();
+            loop {
+                if let Some((addrs, len)) = parse(&buf)? {
+                    return Ok((addrs, buf.split_off(len)));
+                }
+
+                let (res, chunk) = stream.read(Vec::with_capacity(512)).await;
+                match res? {
+                    0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                    _ => buf.extend_from_slice(&chunk),
+                }
+            }
+        };
+
+        let (addrs, rest) = timeout(self.timeout, read).await.unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out waiting for a PROXY protocol header",
+            ))
+        })?;
+
+        let (src, dst) = addrs.unwrap_or((peer, local));
+        Ok((src, dst, rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn v4(ip: [u8; 4], port: u16) -> SocketAddr {
+        (Ipv4Addr::from(ip), port).into()
+    }
+
+    #[test]
+    fn v1_header() {
+        let send = SendProxy {
+            version: Version::V1,
+            tlvs: vec![],
+        };
+
+        assert_eq!(
+            send.header(v4([192, 0, 2, 1], 56324), v4([10, 0, 0, 1], 443)),
+            b"PROXY TCP4 192.0.2.1 10.0.0.1 56324 443\r\n"
+        );
+        assert_eq!(
+            send.header((Ipv6Addr::LOCALHOST, 1000).into(), v4([10, 0, 0, 1], 80)),
+            b"PROXY TCP6 ::1 ::ffff:10.0.0.1 1000 80\r\n"
+        );
+        // a v4 client accepted on a dual stack socket
+        assert_eq!(
+            send.header(
+                (Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped(), 1000).into(),
+                v4([10, 0, 0, 1], 80)
+            ),
+            b"PROXY TCP4 192.0.2.1 10.0.0.1 1000 80\r\n"
+        );
+    }
+
+    #[test]
+    fn v2_header() {
+        let send = SendProxy {
+            version: Version::V2,
+            tlvs: vec!["0xE0=ab".parse().unwrap()],
+        };
+
+        let header = send.header(v4([192, 0, 2, 1], 0x1234), v4([10, 0, 0, 1], 443));
+        assert_eq!(header[..12], V2_SIGNATURE);
+        assert_eq!(header[12..16], [0x21, 0x11, 0, 12 + 5]);
+        assert_eq!(header[16..24], [192, 0, 2, 1, 10, 0, 0, 1]);
+        assert_eq!(header[24..28], [0x12, 0x34, 0x01, 0xbb]);
+        assert_eq!(header[28..], [0xe0, 0, 2, b'a', b'b']);
+
+        let header = send.header(
+            (Ipv6Addr::LOCALHOST, 1).into(),
+            (Ipv6Addr::LOCALHOST, 2).into(),
+        );
+        assert_eq!(header[12..16], [0x21, 0x21, 0, 36 + 5]);
+    }
+
+    #[test]
+    fn header_limit() {
+        let tlv = |len: usize| format!("0xe0={}", "a".repeat(len)).parse::<Tlv>().unwrap();
+        // a single TLV taking up all the room left by two ipv6 addresses
+        let max = usize::from(u16::MAX) - V2_MAX_ADDRS_LEN - 3;
+        let send = SendProxy::new(Version::V2, vec![tlv(max)]).unwrap();
+        let header = send.header(
+            (Ipv6Addr::LOCALHOST, 1).into(),
+            (Ipv6Addr::LOCALHOST, 2).into(),
+        );
+        assert_eq!(header[14..16], u16::MAX.to_be_bytes());
+        assert_eq!(header.len(), 16 + usize::from(u16::MAX));
+
+        assert!(matches!(
+            SendProxy::new(Version::V2, vec![tlv(max + 1)]),
+            Err(TlvParseError::TooManyBytes(_))
+        ));
+        // each one fits on its own, but not together
+        assert!(matches!(
+            SendProxy::new(Version::V2, vec![tlv(max / 2 + 1), tlv(max / 2 + 1)]),
+            Err(TlvParseError::TooManyBytes(_))
+        ));
+    }
+
+    #[test]
+    fn parse_tlv() {
+        let tlv = r"0xe0=abc\x00".parse::<Tlv>().unwrap();
+        assert_eq!((tlv.kind, tlv.value.as_bytes()), (0xe0, &b"abc\0"[..]));
+        assert_eq!(tlv.to_string(), r"0xe0=abc\x00");
+        assert_eq!(tlv.to_string().parse::<Tlv>().unwrap(), tlv);
+        assert_eq!("2=example.com".parse::<Tlv>().unwrap().kind, 2);
+
+        assert!("0xe0".parse::<Tlv>().is_err());
+        assert!("256=a".parse::<Tlv>().is_err());
+        assert!("x=a".parse::<Tlv>().is_err());
+    }
+
+    #[test]
+    fn parse_headers() {
+        let src = v4([192, 0, 2, 1], 56324);
+        let dst = v4([10, 0, 0, 1], 443);
+        for version in [Version::V1, Version::V2] {
+            let send = SendProxy {
+                version,
+                tlvs: vec!["0xE0=ab".parse().unwrap()],
+            };
+            let mut buf = send.header(src, dst);
+            let len = buf.len();
+            buf.extend_from_slice(b"GET / HTTP/1.1");
+
+            assert_eq!(parse(&buf).unwrap(), Some((Some((src, dst)), len)));
+            for partial in 0..len {
+                assert_eq!(parse(&buf[..partial]).unwrap(), None);
+            }
+        }
+
+        let v6 = SendProxy {
+            version: Version::V1,
+            tlvs: vec![],
+        }
+        .header(
+            (Ipv6Addr::LOCALHOST, 1).into(),
+            (Ipv6Addr::LOCALHOST, 2).into(),
+        );
+        assert_eq!(
+            parse(&v6).unwrap(),
+            Some((
+                Some((
+                    (Ipv6Addr::LOCALHOST, 1).into(),
+                    (Ipv6Addr::LOCALHOST, 2).into()
+                )),
+                v6.len()
+            ))
+        );
+
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").unwrap(), Some((None, 15)));
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(parse(&local).unwrap(), Some((None, 16)));
+
+        assert!(matches!(
+            parse(b"GET / HTTP/1.1"),
+            Err(HeaderError::Missing)
+        ));
+        assert!(matches!(
+            parse(b"PROXY TCP4 ::1 10.0.0.1 1 2\r\n"),
+            Err(HeaderError::Malformed)
+        ));
+        assert!(matches!(
+            parse(b"PROXY TCP4 192.0.2.1 10.0.0.1 1\r\n"),
+            Err(HeaderError::Malformed)
+        ));
+        assert!(matches!(
+            parse(&[V1_PREFIX, &[b'a'; 200]].concat()),
+            Err(HeaderError::Malformed)
+        ));
+    }
+}
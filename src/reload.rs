@@ -0,0 +1,82 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime};
+
+use monoio::time::sleep;
+
+use crate::Rule;
+
+/// reads and validates the rules in a config file
+pub type Load = fn(&Path) -> Result<Vec<Rule>, Box<dyn Error>>;
+
+/// Reading the rules again from the config file they came from
+pub struct ConfigFile {
+    pub path: PathBuf,
+    /// reload whenever the file changes, on top of on SIGHUP
+    pub watch: bool,
+    pub load: Load,
+}
+
+/// how often a watched config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// asks for a reload whenever the file at `path` changes
+pub async fn watch(path: &Path) -> ! {
+    let mut last = modified(path);
+    loop {
+        sleep(POLL_INTERVAL).await;
+        let now = modified(path);
+        if now != last {
+            last = now;
+            tracing::info!("`{}` changed, reloading it", path.display());
+            request()
+        }
+    }
+}
+
+static RELOAD: LazyLock<(flume::Sender<()>, flume::Receiver<()>)> = LazyLock::new(flume::unbounded);
+
+/// asks for the config to be read again
+pub fn request() {
+    let (tx, _) = &*RELOAD;
+    let _ = tx.send(());
+}
+
+/// resolves once a reload was asked for, every request made until then is handled by it
+pub async fn requested() {
+    let (_, rx) = &*RELOAD;
+    // the static holds on to a sender, so this never disconnects
+    let _ = rx.recv_async().await;
+    rx.drain().for_each(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{select, Either};
+    use std::pin::pin;
+
+    #[monoio::test(timer_enabled = true)]
+    #[cfg_attr(miri, ignore)]
+    async fn requests_coalesce() {
+        for _ in 0..3 {
+            request()
+        }
+        requested().await;
+
+        // the requests made until then were all handled by that one reload
+        let again = pin!(requested());
+        let timeout = pin!(sleep(Duration::from_millis(50)));
+        assert!(matches!(select(again, timeout).await, Either::Right(_)));
+
+        request();
+        requested().await;
+    }
+}
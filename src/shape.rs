@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::num::NonZero;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::{Duration, Instant};
+
+use monoio::time::sleep;
+use smallvec::SmallVec;
+
+use crate::cidr;
+use crate::limit::TokenBucket;
+
+#[derive(thiserror::Error, Debug)]
+#[error("invalid bandwidth `{0}`, expected bytes a second like 512KiB, 10MB or 1GiB")]
+pub struct BandwidthParseError(String);
+
+/// A rate in bytes a second
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bandwidth(NonZero<u64>);
+
+impl Bandwidth {
+    const UNITS: [(&'static str, u64); 7] = [
+        ("GiB", 1 << 30),
+        ("MiB", 1 << 20),
+        ("KiB", 1 << 10),
+        ("GB", 1_000_000_000),
+        ("MB", 1_000_000),
+        ("KB", 1_000),
+        ("B", 1),
+    ];
+
+    pub fn bytes_per_sec(self) -> u64 {
+        self.0.get()
+    }
+}
+
+/// parses a number of bytes like 512KiB, 10MB or 1GiB, a bare number is in bytes
+pub fn parse_bytes(s: &str) -> Option<NonZero<u64>> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(digits);
+    let unit = unit.trim_start();
+
+    let scale = match unit {
+        "" => 1,
+        unit => Bandwidth::UNITS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+            .map(|&(_, scale)| scale)?,
+    };
+
+    amount
+        .parse::<u64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(scale))
+        .and_then(NonZero::new)
+}
+
+impl FromStr for Bandwidth {
+    type Err = BandwidthParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rate = s.trim();
+        let rate = rate.strip_suffix("/s").unwrap_or(rate).trim_end();
+        parse_bytes(rate)
+            .map(Bandwidth)
+            .ok_or_else(|| BandwidthParseError(s.to_owned()))
+    }
+}
+
+impl Display for Bandwidth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let bytes = self.0.get();
+        let (name, scale) = Self::UNITS
+            .iter()
+            .find(|&&(_, scale)| bytes.is_multiple_of(scale))
+            .copied()
+            .unwrap_or(("B", 1));
+        write!(f, "{}{name}", bytes / scale)
+    }
+}
+
+/// The bandwidth limits of one direction of traffic
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rates {
+    /// of every connection on its own
+    pub connection: Option<Bandwidth>,
+    /// of every connection from the same client ip put together
+    pub ip: Option<Bandwidth>,
+    /// of every connection to the same listening address put together
+    pub listener: Option<Bandwidth>,
+}
+
+impl Rates {
+    fn is_unlimited(self) -> bool {
+        self.connection.is_none() && self.ip.is_none() && self.listener.is_none()
+    }
+}
+
+/// Upload is the traffic from clients to the upstream, download the traffic back
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShapeConfig {
+    pub upload: Rates,
+    pub download: Rates,
+}
+
+type Bucket = Arc<Mutex<TokenBucket>>;
+
+fn bucket(rate: Option<Bandwidth>) -> Option<Bucket> {
+    rate.map(|rate| {
+        // allow up to a second worth of traffic to go through in a burst
+        let rate = rate.bytes_per_sec() as f64;
+        Arc::new(Mutex::new(TokenBucket::new(rate, rate)))
+    })
+}
+
+/// Buckets shared by every connection in some scope, one for each direction
+#[derive(Clone)]
+pub struct Buckets {
+    upload: Option<Bucket>,
+    download: Option<Bucket>,
+}
+
+impl Buckets {
+    fn new(upload: Option<Bandwidth>, download: Option<Bandwidth>) -> Self {
+        Buckets {
+            upload: bucket(upload),
+            download: bucket(download),
+        }
+    }
+}
+
+/// Hands out the buckets every connection of a rule draws from
+pub struct Shaper {
+    config: ShapeConfig,
+    /// the buckets of every client ip with a connection open
+    ips: Mutex<HashMap<IpAddr, Weak<Buckets>>>,
+}
+
+impl Shaper {
+    pub fn new(config: ShapeConfig) -> Self {
+        Shaper {
+            config,
+            ips: Mutex::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn config(&self) -> &ShapeConfig {
+        &self.config
+    }
+
+    /// the buckets shared by a single listening address of the rule
+    pub fn listener(&self) -> Buckets {
+        Buckets::new(self.config.upload.listener, self.config.download.listener)
+    }
+
+    fn ip(&self, ip: IpAddr) -> Arc<Buckets> {
+        let ip = cidr::canonical(ip);
+        let mut ips = self.ips.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(buckets) = ips.get(&ip).and_then(Weak::upgrade) {
+            return buckets;
+        }
+
+        // forget about the clients that have since gone away
+        ips.retain(|_, buckets| buckets.strong_count() != 0);
+        let buckets = Arc::new(Buckets::new(self.config.upload.ip, self.config.download.ip));
+        ips.insert(ip, Arc::downgrade(&buckets));
+        buckets
+    }
+
+    /// the throttles of a new connection from `client` to a listener with the `listener` buckets
+    pub fn connection(&self, client: IpAddr, listener: &Buckets) -> Shaping {
+        let ShapeConfig { upload, download } = self.config;
+        if upload.is_unlimited() && download.is_unlimited() {
+            return Shaping::default();
+        }
+
+        let ip = self.ip(client);
+        let connection = Buckets::new(upload.connection, download.connection);
+        let throttle = |rates: Rates, pick: fn(&Buckets) -> &Option<Bucket>| Throttle {
+            buckets: [&connection, &ip, listener]
+                .into_iter()
+                .filter_map(|buckets| pick(buckets).clone())
+                .collect(),
+            chunk: Throttle::chunk(rates),
+        };
+
+        Shaping {
+            upload: throttle(upload, |buckets| &buckets.upload),
+            download: throttle(download, |buckets| &buckets.download),
+            _ip: Some(ip),
+        }
+    }
+}
+
+/// The buckets one direction of a connection draws from, none if it's unlimited
+pub struct Throttle {
+    buckets: SmallVec<Bucket, 3>,
+    chunk: u32,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle {
+            buckets: SmallVec::new(),
+            chunk: Self::MAX_CHUNK,
+        }
+    }
+}
+
+impl Throttle {
+    /// the most that's copied in one go without shaping
+    pub const MAX_CHUNK: u32 = 64 * 1024;
+
+    /// small enough that the slowest of `rates` is paid off in about a tenth of a second
+    fn chunk(rates: Rates) -> u32 {
+        [rates.connection, rates.ip, rates.listener]
+            .into_iter()
+            .flatten()
+            .min()
+            .map_or(Self::MAX_CHUNK, |slowest| {
+                (slowest.bytes_per_sec() / 10).clamp(1, u64::from(Self::MAX_CHUNK)) as u32
+            })
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// how many bytes to copy before calling [`Throttle::consume`]
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk
+    }
+
+    /// accounts for `bytes` that were read, and waits until they're allowed through
+    pub async fn consume(&self, bytes: u32) {
+        if self.is_unlimited() {
+            return;
+        }
+
+        let now = Instant::now();
+        let wait = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                bucket
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .take(f64::from(bytes), now)
+            })
+            .max()
+            .unwrap_or(Duration::ZERO);
+
+        if !wait.is_zero() {
+            sleep(wait).await
+        }
+    }
+}
+
+/// The throttles of a single connection, unlimited by default
+#[derive(Default)]
+pub struct Shaping {
+    pub upload: Throttle,
+    pub download: Throttle,
+    /// keeps the client's buckets around for as long as the connection is open
+    _ip: Option<Arc<Buckets>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn rate(s: &str) -> Option<Bandwidth> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_bandwidth() {
+        for (s, bytes) in [
+            ("1024", 1024),
+            ("512KiB", 512 * 1024),
+            ("10 MB/s", 10_000_000),
+            ("1gib", 1 << 30),
+            ("3B", 3),
+        ] {
+            assert_eq!(
+                s.parse::<Bandwidth>().unwrap().bytes_per_sec(),
+                bytes,
+                "{s}"
+            );
+        }
+
+        assert_eq!(rate("10MB").unwrap().to_string(), "10MB");
+        assert_eq!(rate("2048KiB").unwrap().to_string(), "2MiB");
+        assert_eq!(rate("1500").unwrap().to_string(), "1500B");
+
+        for s in ["", "0", "0MiB", "10 mbit", "1.5MB", "MB", "-1"] {
+            assert!(s.parse::<Bandwidth>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn shared_buckets() {
+        let shaper = Shaper::new(ShapeConfig {
+            upload: Rates {
+                connection: rate("1MiB"),
+                ip: rate("2MiB"),
+                listener: None,
+            },
+            download: Rates {
+                connection: None,
+                ip: None,
+                listener: rate("100KB"),
+            },
+        });
+        let listener = shaper.listener();
+        let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        let first = shaper.connection(client, &listener);
+        let second = shaper.connection(client, &listener);
+        assert_eq!(first.upload.buckets.len(), 2);
+        assert_eq!(first.download.buckets.len(), 1);
+        assert_eq!(first.upload.chunk_size(), Throttle::MAX_CHUNK);
+        assert_eq!(first.download.chunk_size(), 10_000);
+        // the per ip and per listener buckets are shared, the per connection ones aren't
+        assert!(Arc::ptr_eq(
+            &first.upload.buckets[1],
+            &second.upload.buckets[1]
+        ));
+        assert!(!Arc::ptr_eq(
+            &first.upload.buckets[0],
+            &second.upload.buckets[0]
+        ));
+        assert!(Arc::ptr_eq(
+            &first.download.buckets[0],
+            &second.download.buckets[0]
+        ));
+
+        drop((first, second));
+        let third = shaper.connection(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), &listener);
+        assert_eq!(shaper.ips.lock().unwrap().len(), 1);
+        drop(third);
+
+        let unlimited = Shaper::new(ShapeConfig::default()).connection(client, &listener);
+        assert!(unlimited.upload.is_unlimited() && unlimited.download.is_unlimited());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn throttle() {
+        let shaper = Shaper::new(ShapeConfig {
+            upload: Rates {
+                connection: rate("1000B"),
+                ..Rates::default()
+            },
+            ..ShapeConfig::default()
+        });
+        let shaping = shaper.connection(IpAddr::V4(Ipv4Addr::LOCALHOST), &shaper.listener());
+
+        let start = Instant::now();
+        // the first second worth goes through in a burst
+        shaping.upload.consume(1000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        shaping.upload.consume(200).await;
+        assert!(start.elapsed() >= Duration::from_millis(190));
+        shaping.download.consume(1 << 20).await;
+        assert!(start.elapsed() < Duration::from_millis(300));
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use futures::future;
+use monoio::time::sleep;
+
+use crate::reload;
+use crate::sock_io::Counters;
+
+/// how long to wait for the sessions still open once a shutdown was asked for
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Shutdown = (Mutex<Option<flume::Sender<()>>>, flume::Receiver<()>);
+
+/// disconnected once a shutdown was asked for, which wakes up every thread waiting on it
+static SHUTDOWN: LazyLock<Shutdown> = LazyLock::new(|| {
+    let (tx, rx) = flume::bounded(0);
+    (Mutex::new(Some(tx)), rx)
+});
+
+/// A signal this process reacts to
+enum Signal {
+    /// SIGHUP, reload the config
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Reload,
+    Shutdown(&'static str),
+}
+
+#[cfg(unix)]
+struct Signals {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+    hangup: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    /// has to be called within a tokio runtime
+    fn new() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Signals {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) -> Signal {
+        let terminate = std::pin::pin!(self.terminate.recv());
+        let interrupt = std::pin::pin!(self.interrupt.recv());
+        let hangup = std::pin::pin!(self.hangup.recv());
+        match future::select(hangup, future::select(terminate, interrupt)).await {
+            future::Either::Left(_) => Signal::Reload,
+            future::Either::Right((future::Either::Left(_), _)) => Signal::Shutdown("SIGTERM"),
+            future::Either::Right((future::Either::Right(_), _)) => Signal::Shutdown("SIGINT"),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> io::Result<Self> {
+        Ok(Signals)
+    }
+
+    async fn recv(&mut self) -> Signal {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => Signal::Shutdown("ctrl-c"),
+            Err(err) => {
+                tracing::error!("Unable to wait for ctrl-c: {err}");
+                future::pending().await
+            }
+        }
+    }
+}
+
+/// starts listening for SIGTERM and SIGINT (ctrl-c on windows) on a thread of its own,
+/// the first one asks for a shutdown, the second one exits right away,
+/// SIGHUP asks for the config to be reloaded
+pub fn handle_signals() -> io::Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let mut signals = {
+        let _guard = rt.enter();
+        Signals::new()?
+    };
+
+    std::thread::Builder::new()
+        .name("hptp-signals".to_owned())
+        .spawn(move || {
+            rt.block_on(async move {
+                let mut requested = false;
+                loop {
+                    match signals.recv().await {
+                        Signal::Reload => {
+                            tracing::info!("Received SIGHUP, reloading the config");
+                            reload::request()
+                        }
+                        Signal::Shutdown(signal) if !requested => {
+                            tracing::info!("Received {signal}, shutting down");
+                            request();
+                            requested = true
+                        }
+                        Signal::Shutdown(signal) => {
+                            tracing::warn!("Received {signal} again, exiting right away");
+                            std::process::exit(1)
+                        }
+                    }
+                }
+            })
+        })
+        .map(drop)
+}
+
+/// asks every thread to stop accepting connections, and the process to exit once they're drained
+pub fn request() {
+    let (tx, _) = &*SHUTDOWN;
+    drop(tx.lock().unwrap_or_else(PoisonError::into_inner).take())
+}
+
+/// resolves once a shutdown was asked for
+pub async fn requested() {
+    let (_, rx) = &*SHUTDOWN;
+    // nothing is ever sent, the channel only gets disconnected
+    let _ = rx.recv_async().await;
+}
+
+/// The sessions still open, across every thread
+#[derive(Default)]
+pub struct Sessions(Arc<Registry>);
+
+#[derive(Default)]
+struct Registry {
+    next_id: AtomicU64,
+    open: Mutex<BTreeMap<u64, Arc<Activity>>>,
+}
+
+impl Registry {
+    fn open(&self) -> MutexGuard<'_, BTreeMap<u64, Arc<Activity>>> {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Where a session goes
+#[derive(Copy, Clone, Debug)]
+pub struct Endpoints {
+    /// the client, as a PROXY protocol header said if it came with one
+    pub peer: SocketAddr,
+    /// the address the client connected to
+    pub local: SocketAddr,
+    /// the upstream address, once connected to it
+    pub upstream: Option<SocketAddr>,
+}
+
+/// Why a session was killed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kill {
+    /// through the admin api
+    Admin,
+    /// it was still open once the drain timeout elapsed
+    Shutdown,
+}
+
+/// What an open session is up to
+pub struct Activity {
+    pub id: u64,
+    pub opened: Instant,
+    /// the port the session was accepted on
+    pub port: u16,
+    endpoints: Mutex<Endpoints>,
+    pub counters: Counters,
+    /// sent on, and dropped, to kill the session
+    kill: Mutex<Option<flume::Sender<Kill>>>,
+}
+
+impl Activity {
+    pub fn endpoints(&self) -> Endpoints {
+        *self
+            .endpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Endpoints)) {
+        f(&mut self
+            .endpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn kill(&self, why: Kill) {
+        let kill = self
+            .kill
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(kill) = kill {
+            // there's room for it, and the first reason given is the one that counts
+            let _ = kill.try_send(why);
+        }
+    }
+}
+
+/// A session that is still open, until it's dropped
+pub struct Session {
+    activity: Arc<Activity>,
+    registry: Arc<Registry>,
+    /// told why once the session is killed
+    killed: flume::Receiver<Kill>,
+}
+
+impl Session {
+    pub fn activity(&self) -> &Activity {
+        &self.activity
+    }
+
+    pub fn set_client(&self, peer: SocketAddr, local: SocketAddr) {
+        self.activity.update(|endpoints| {
+            endpoints.peer = peer;
+            endpoints.local = local;
+        })
+    }
+
+    pub fn set_upstream(&self, upstream: Option<SocketAddr>) {
+        self.activity
+            .update(|endpoints| endpoints.upstream = upstream)
+    }
+
+    /// resolves once the session was killed, with why
+    pub async fn killed(&self) -> Kill {
+        match self.killed.recv_async().await {
+            Ok(why) => why,
+            // the sender is only dropped along with a reason
+            Err(_) => future::pending().await,
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.registry.open().remove(&self.activity.id);
+    }
+}
+
+impl Sessions {
+    /// how often to check whether every session finished
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+    /// how often to log how many sessions remain
+    const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn open(&self, peer: SocketAddr, local: SocketAddr) -> Session {
+        let (kill, killed) = flume::bounded(1);
+        let activity = Arc::new(Activity {
+            id: self.0.next_id.fetch_add(1, Ordering::Relaxed),
+            opened: Instant::now(),
+            port: local.port(),
+            endpoints: Mutex::new(Endpoints {
+                peer,
+                local,
+                upstream: None,
+            }),
+            counters: Counters::default(),
+            kill: Mutex::new(Some(kill)),
+        });
+        self.0.open().insert(activity.id, Arc::clone(&activity));
+        Session {
+            activity,
+            registry: Arc::clone(&self.0),
+            killed,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.open().len()
+    }
+
+    /// every session still open, the oldest first
+    pub fn list(&self) -> Vec<Arc<Activity>> {
+        self.0.open().values().cloned().collect()
+    }
+
+    /// closes the session `id` if it's still open, and returns whether it was
+    pub fn kill(&self, id: u64) -> bool {
+        let Some(activity) = self.0.open().get(&id).cloned() else {
+            return false;
+        };
+        activity.kill(Kill::Admin);
+        true
+    }
+
+    /// closes every session still open, once they can't be waited on anymore
+    pub fn kill_all(&self) {
+        for activity in self.list() {
+            activity.kill(Kill::Shutdown)
+        }
+    }
+
+    /// waits up to `timeout` for every session to finish,
+    /// and returns how many are still open
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut next_log = Instant::now() + Self::LOG_INTERVAL;
+        loop {
+            let open = self.count();
+            let now = Instant::now();
+            if open == 0 || now >= deadline {
+                break open;
+            }
+
+            if now >= next_log {
+                let left = deadline - now;
+                tracing::info!("Waiting for {open} sessions to finish, {left:.0?} left");
+                next_log = now + Self::LOG_INTERVAL;
+            }
+            sleep(Self::POLL_INTERVAL.min(deadline - now)).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[monoio::test(timer_enabled = true)]
+    async fn drain() {
+        let sessions = Sessions::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 80));
+        let first = sessions.open(addr, addr);
+        let second = sessions.open(addr, addr);
+        assert_eq!(sessions.count(), 2);
+
+        drop(first);
+        let start = Instant::now();
+        assert_eq!(sessions.drain(Duration::from_millis(100)).await, 1);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        monoio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            drop(second)
+        });
+        let start = Instant::now();
+        assert_eq!(sessions.drain(Duration::from_secs(5)).await, 0);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn kill() {
+        let sessions = Sessions::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 80));
+        let session = sessions.open(addr, addr);
+        let id = session.activity().id;
+        assert_eq!(
+            sessions.list().iter().map(|a| a.id).collect::<Vec<_>>(),
+            [id]
+        );
+
+        assert!(!sessions.kill(id + 1));
+        assert!(sessions.kill(id));
+        sessions.kill_all();
+        let why = monoio::time::timeout(Duration::from_secs(1), session.killed())
+            .await
+            .unwrap();
+        assert_eq!(why, Kill::Admin);
+
+        drop(session);
+        assert_eq!(sessions.count(), 0);
+        assert!(!sessions.kill(id));
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn kill_all() {
+        let sessions = Sessions::default();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 80));
+        let open = [sessions.open(addr, addr), sessions.open(addr, addr)];
+
+        sessions.kill_all();
+        for session in &open {
+            let why = monoio::time::timeout(Duration::from_secs(1), session.killed())
+                .await
+                .unwrap();
+            assert_eq!(why, Kill::Shutdown);
+        }
+    }
+}
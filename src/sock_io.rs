@@ -1,29 +1,377 @@
+use futures::future::{self, Either};
 use monoio::io::{AsyncWriteRent, Splitable};
+use monoio::net::tcp::{TcpOwnedReadHalf, TcpOwnedWriteHalf};
 use monoio::net::TcpStream;
+use monoio::time::{sleep, timeout};
+use std::cell::Cell;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
 use std::io;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
-cfg_if::cfg_if! {
-    if #[cfg(target_os = "linux")] {
-        use monoio::io::zero_copy as copy_impl;
-    } else {
-        use monoio::io::copy as copy_impl;
+use crate::shape::{Shaping, Throttle};
+
+/// Timeouts that close a session early, all of them off by default
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// nothing was sent either way for this long
+    pub idle: Option<Duration>,
+    /// one side shut down its half of the connection and the other didn't follow for this long
+    pub linger: Option<Duration>,
+    /// the session was open for this long
+    pub lifetime: Option<Duration>,
+}
+
+/// The timeout that closed a session
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Timeout {
+    Idle(Duration),
+    Linger(Duration),
+    Lifetime(Duration),
+}
+
+impl Display for Timeout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Timeout::Idle(after) => write!(f, "idle timeout of {after:?}"),
+            Timeout::Linger(after) => write!(f, "half-close linger timeout of {after:?}"),
+            Timeout::Lifetime(after) => write!(f, "max lifetime of {after:?}"),
+        }
     }
 }
 
-pub async fn copy_socks(a: TcpStream, b: TcpStream) -> io::Result<(u64, u64)> {
+/// Bytes sent by the client, and by the upstream server
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Transferred {
+    pub client: u64,
+    pub server: u64,
+}
+
+/// How a session ended without an error
+#[derive(Copy, Clone, Debug)]
+pub struct Closed {
+    /// `None` if both sides closed the connection
+    pub timeout: Option<Timeout>,
+}
+
+/// Bytes sent so far, readable from any thread while the session is open
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub client: AtomicU64,
+    pub server: AtomicU64,
+    /// how much of the client's and the server's bytes [`Counters::take_new`] returned already
+    taken: [AtomicU64; 2],
+}
+
+impl Counters {
+    pub fn transferred(&self) -> Transferred {
+        Transferred {
+            client: self.client.load(Ordering::Relaxed),
+            server: self.server.load(Ordering::Relaxed),
+        }
+    }
+
+    /// the bytes sent since the last call, every byte is returned only once,
+    /// even when it's called from several threads at a time
+    pub fn take_new(&self) -> Transferred {
+        let take = |sent: &AtomicU64, taken: &AtomicU64| {
+            let sent = sent.load(Ordering::Relaxed);
+            sent.saturating_sub(taken.fetch_max(sent, Ordering::Relaxed))
+        };
+        Transferred {
+            client: take(&self.client, &self.taken[0]),
+            server: take(&self.server, &self.taken[1]),
+        }
+    }
+}
+
+struct Progress {
+    last_active: Cell<Instant>,
+}
+
+impl Progress {
+    fn sent(&self, by: &AtomicU64, bytes: u32) {
+        by.fetch_add(u64::from(bytes), Ordering::Relaxed);
+        self.last_active.set(Instant::now());
+    }
+}
+
+/// splices through a pipe like [`monoio::io::zero_copy`],
+/// but no more than a chunk at a time so every chunk can be throttled and counted
+#[cfg(target_os = "linux")]
+async fn copy(
+    reader: &mut TcpOwnedReadHalf,
+    writer: &mut TcpOwnedWriteHalf,
+    throttle: &Throttle,
+    progress: &Progress,
+    sent: &AtomicU64,
+) -> io::Result<()> {
+    use monoio::io::splice::{SpliceDestination, SpliceSource};
+
+    let (mut pipe_read, mut pipe_write) = monoio::net::unix::new_pipe()?;
+    loop {
+        let read = reader
+            .splice_to_pipe(&mut pipe_write, throttle.chunk_size())
+            .await?;
+        if read == 0 {
+            break Ok(());
+        }
+
+        throttle.consume(read).await;
+        let mut to_write = read;
+        while to_write > 0 {
+            match writer.splice_from_pipe(&mut pipe_read, to_write).await? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                written => to_write -= written,
+            }
+        }
+        progress.sent(sent, read);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn copy(
+    reader: &mut TcpOwnedReadHalf,
+    writer: &mut TcpOwnedWriteHalf,
+    throttle: &Throttle,
+    progress: &Progress,
+    sent: &AtomicU64,
+) -> io::Result<()> {
+    use monoio::io::{AsyncReadRent, AsyncWriteRentExt};
+
+    let mut buf = Vec::with_capacity(throttle.chunk_size() as usize);
+    loop {
+        buf.clear();
+        let (res, read_buf) = reader.read(buf).await;
+        let read = res?;
+        if read == 0 {
+            break Ok(());
+        }
+
+        throttle.consume(read as u32).await;
+        let (res, written_buf) = writer.write_all(read_buf).await;
+        res?;
+        buf = written_buf;
+        progress.sent(sent, read as u32);
+    }
+}
+
+/// waits for the side still open once the other one closed
+async fn linger(
+    rest: impl Future<Output = io::Result<()>>,
+    linger: Option<Duration>,
+) -> io::Result<Option<Timeout>> {
+    match linger {
+        Some(linger) => match timeout(linger, rest).await {
+            Ok(res) => res.map(|()| None),
+            Err(_) => Ok(Some(Timeout::Linger(linger))),
+        },
+        None => rest.await.map(|()| None),
+    }
+}
+
+/// resolves once the session was idle, or open, for too long
+async fn watchdog(timeouts: Timeouts, started: Instant, progress: &Progress) -> Timeout {
+    let idle = async {
+        let Some(idle) = timeouts.idle else {
+            return future::pending().await;
+        };
+
+        loop {
+            let deadline = progress.last_active.get() + idle;
+            let now = Instant::now();
+            if now >= deadline {
+                break Timeout::Idle(idle);
+            }
+            sleep(deadline - now).await
+        }
+    };
+
+    let lifetime = async {
+        let Some(lifetime) = timeouts.lifetime else {
+            return future::pending().await;
+        };
+
+        sleep(lifetime.saturating_sub(started.elapsed())).await;
+        Timeout::Lifetime(lifetime)
+    };
+
+    future::select(pin!(idle), pin!(lifetime))
+        .await
+        .factor_first()
+        .0
+}
+
+/// copies between the client `a` and upstream `b` until both sides close, or a timeout fires,
+/// counting the bytes sent either way in `counters` as they are
+pub async fn copy_socks(
+    a: TcpStream,
+    b: TcpStream,
+    shaping: Shaping,
+    timeouts: Timeouts,
+    counters: &Counters,
+) -> io::Result<Closed> {
     let (mut ra, mut wa) = a.into_split();
     let (mut rb, mut wb) = b.into_split();
 
-    monoio::try_join!(
-        async move {
-            let written = copy_impl(&mut ra, &mut wb).await?;
-            wb.shutdown().await?;
-            Ok(written)
-        },
-        async move {
-            let written = copy_impl(&mut rb, &mut wa).await?;
-            wa.shutdown().await?;
-            Ok(written)
+    let started = Instant::now();
+    let progress = Progress {
+        last_active: Cell::new(started),
+    };
+
+    let upload = pin!(async {
+        copy(
+            &mut ra,
+            &mut wb,
+            &shaping.upload,
+            &progress,
+            &counters.client,
+        )
+        .await?;
+        wb.shutdown().await
+    });
+    let download = pin!(async {
+        copy(
+            &mut rb,
+            &mut wa,
+            &shaping.download,
+            &progress,
+            &counters.server,
+        )
+        .await?;
+        wa.shutdown().await
+    });
+
+    let copying = async {
+        match future::select(upload, download).await {
+            Either::Left((res, download)) => {
+                res?;
+                linger(download, timeouts.linger).await
+            }
+            Either::Right((res, upload)) => {
+                res?;
+                linger(upload, timeouts.linger).await
+            }
         }
-    )
+    };
+
+    let timeout =
+        match future::select(pin!(copying), pin!(watchdog(timeouts, started, &progress))).await {
+            Either::Left((res, _)) => res?,
+            Either::Right((timeout, _)) => Some(timeout),
+        };
+
+    Ok(Closed { timeout })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use monoio::io::{AsyncReadRent, AsyncWriteRentExt};
+    use monoio::net::TcpListener;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn take_new() {
+        let counters = Counters::default();
+        counters.client.fetch_add(10, Ordering::Relaxed);
+        counters.server.fetch_add(3, Ordering::Relaxed);
+        assert_eq!(
+            counters.take_new(),
+            Transferred {
+                client: 10,
+                server: 3
+            }
+        );
+        assert_eq!(counters.take_new(), Transferred::default());
+
+        counters.client.fetch_add(5, Ordering::Relaxed);
+        assert_eq!(
+            counters.take_new(),
+            Transferred {
+                client: 5,
+                server: 0
+            }
+        );
+        assert_eq!(
+            counters.transferred(),
+            Transferred {
+                client: 15,
+                server: 3
+            }
+        );
+    }
+
+    /// both ends of a fresh connection
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = monoio::join!(TcpStream::connect_addr(addr), listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    #[cfg_attr(miri, ignore)]
+    async fn timeouts() {
+        let copy = |timeouts| async move {
+            let ((mut client, downstream), (upstream, mut server)) = (pair().await, pair().await);
+            let session = monoio::spawn(async move {
+                let counters = Counters::default();
+                copy_socks(
+                    downstream,
+                    upstream,
+                    Shaping::default(),
+                    timeouts,
+                    &counters,
+                )
+                .await
+                .map(|closed| (closed, counters.transferred()))
+            });
+
+            let (res, _) = client.write_all(b"hello").await;
+            res.unwrap();
+            let (res, buf) = server.read(Vec::with_capacity(16)).await;
+            res.unwrap();
+            assert_eq!(buf, b"hello");
+            client.shutdown().await.unwrap();
+
+            let (closed, transferred) = session.await.unwrap();
+            assert_eq!(
+                transferred,
+                Transferred {
+                    client: 5,
+                    server: 0
+                }
+            );
+            closed.timeout
+        };
+
+        let after = Duration::from_millis(50);
+        assert_eq!(
+            copy(Timeouts {
+                idle: Some(after),
+                ..Timeouts::default()
+            })
+            .await,
+            Some(Timeout::Idle(after))
+        );
+        assert_eq!(
+            copy(Timeouts {
+                linger: Some(after),
+                ..Timeouts::default()
+            })
+            .await,
+            Some(Timeout::Linger(after))
+        );
+        assert_eq!(
+            copy(Timeouts {
+                lifetime: Some(after),
+                ..Timeouts::default()
+            })
+            .await,
+            Some(Timeout::Lifetime(after))
+        );
+    }
 }
@@ -0,0 +1,88 @@
+use std::env;
+use std::io;
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+
+use nix::sys::socket::{getsockopt, sockopt};
+
+use crate::stream::Inherited;
+
+/// the first descriptor a service manager passes, see `sd_listen_fds(3)`
+const LISTEN_FDS_START: RawFd = 3;
+
+/// how many descriptors the service manager passed to the process `pid`, if any,
+/// `var` reads the environment
+fn passed_fds(var: impl Fn(&str) -> Option<String>, pid: u32) -> Option<u16> {
+    let listen_pid = var("LISTEN_PID")?.parse::<u32>().ok()?;
+    if listen_pid != pid {
+        return None;
+    }
+    var("LISTEN_FDS")?.parse().ok()
+}
+
+/// takes over the listening sockets systemd passed to this process, and returns how many it took,
+/// with `reuse_port` there can't be any, the other threads couldn't bind their addresses
+pub fn inherit(inherited: &mut Inherited, reuse_port: bool) -> io::Result<usize> {
+    let Some(fds) = passed_fds(|key| env::var(key).ok(), std::process::id()) else {
+        return Ok(0);
+    };
+    if reuse_port {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--reuse-port can't be used with sockets passed by systemd, the other threads couldn't bind them",
+        ));
+    }
+
+    let before = inherited.len();
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    for fd in (0..fds).map(|i| LISTEN_FDS_START + RawFd::from(i)) {
+        let name = names
+            .next()
+            .filter(|name| !name.is_empty())
+            .unwrap_or("unknown");
+        // Safety: the descriptor was passed to this process, and nothing else owns it
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        let listening = getsockopt(&listener, sockopt::AcceptConn).unwrap_or(false);
+        match listener.local_addr() {
+            Ok(addr) if listening => {
+                tracing::debug!("Took over `{addr}` ({name}) from systemd");
+                inherited.insert(listener)?
+            }
+            _ => {
+                tracing::warn!(
+                    "Ignoring the socket `{name}` passed by systemd, it's not a listening tcp socket"
+                );
+                // left open, something else might be using the descriptor after all
+                let _ = listener.into_raw_fd();
+            }
+        }
+    }
+
+    // the variables are left alone, changing the environment isn't sound once threads run,
+    // and `LISTEN_PID` already tells any process this one starts they aren't meant for it
+
+    Ok(inherited.len() - before)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passed() {
+        let env = |pid: &'static str, fds: &'static str| {
+            move |key: &str| match key {
+                "LISTEN_PID" => Some(pid.to_owned()),
+                "LISTEN_FDS" => Some(fds.to_owned()),
+                _ => None,
+            }
+        };
+
+        assert_eq!(passed_fds(env("42", "2"), 42), Some(2));
+        // meant for another process
+        assert_eq!(passed_fds(env("41", "2"), 42), None);
+        assert_eq!(passed_fds(env("x", "2"), 42), None);
+        assert_eq!(passed_fds(env("42", "-1"), 42), None);
+        assert_eq!(passed_fds(|_| None, 42), None);
+    }
+}
@@ -1,72 +1,531 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd};
+use std::pin::pin;
+use std::rc::Rc;
+use std::time::Duration;
 
-use futures::future::Either;
-use futures::{stream, FutureExt, StreamExt, TryStreamExt};
-use monoio::net::{TcpListener, TcpStream};
+use futures::future::{self, Either};
+use futures::{stream, StreamExt, TryStreamExt};
+use monoio::net::{ListenerOpts, TcpListener, TcpStream};
+use monoio::time::sleep;
+
+/// gives up `stream`'s runtime, so it can be moved to another
+pub fn into_std(stream: TcpStream) -> std::net::TcpStream {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::io::{FromRawFd, IntoRawFd};
+            // Safety: the descriptor was just given up by `stream`
+            unsafe { std::net::TcpStream::from_raw_fd(stream.into_raw_fd()) }
+        } else {
+            use std::os::windows::io::{FromRawSocket, IntoRawSocket};
+            // Safety: the socket was just given up by `stream`
+            unsafe { std::net::TcpStream::from_raw_socket(stream.into_raw_socket()) }
+        }
+    }
+}
+
+#[cfg(unix)]
+type Fd = OwnedFd;
+#[cfg(not(unix))]
+type Fd = ();
+
+/// a copy of the descriptor of `listener`, that stays open once it's closed
+fn dup(listener: &TcpListener) -> io::Result<Fd> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            // Safety: the descriptor belongs to `listener`, which is open
+            unsafe { BorrowedFd::borrow_raw(listener.as_raw_fd()) }.try_clone_to_owned()
+        } else {
+            let _ = listener;
+            Ok(())
+        }
+    }
+}
+
+/// Listeners bound by someone else and handed to this process, by the address they're bound to
+#[derive(Default)]
+pub struct Inherited(HashMap<SocketAddr, std::net::TcpListener>);
+
+impl Inherited {
+    pub fn insert(&mut self, listener: std::net::TcpListener) -> io::Result<()> {
+        self.0.insert(listener.local_addr()?, listener);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// the addresses of the listeners that weren't taken
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.0.keys().copied()
+    }
+
+    fn listen(&mut self, addr: SocketAddr, opts: &ListenerOpts) -> io::Result<TcpListener> {
+        match self.0.remove(&addr) {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            }
+            None => TcpListener::bind_with_config(addr, opts),
+        }
+    }
+}
 
 #[derive(Debug)]
-pub struct ManyRecvResult {
+pub struct ManyRecvResult<T> {
     pub stream: TcpStream,
     pub peer: SocketAddr,
     pub local: SocketAddr,
+    /// the tag the accepting listener was bound with
+    pub tag: T,
+}
+
+/// A listener that stopped accepting connections
+#[derive(Debug, thiserror::Error)]
+#[error("the listener on `{addr}` failed: {source}, binding it again in {retry:?}")]
+pub struct ListenerError {
+    pub addr: SocketAddr,
+    pub source: io::Error,
+    /// how long until it's bound again
+    pub retry: Duration,
 }
 
-pub struct ManyTcpListener {
-    listeners: flume::Receiver<io::Result<ManyRecvResult>>,
+/// What happened on one of the listeners
+#[derive(Debug)]
+pub enum Event<T> {
+    Accepted(ManyRecvResult<T>),
+    /// a listener failed, or binding it again did
+    Failed(ListenerError),
+    /// a listener that failed is bound again
+    Restarted(SocketAddr),
+}
+
+/// what the listener tasks, and the restart timers, send to [`ManyTcpListener::accept`]
+enum Message<T> {
+    Accepted(ManyRecvResult<T>),
+    /// along with the tag of the listener, to tell it apart from one bound since on the same address
+    Failed(SocketAddr, Rc<RefCell<T>>, io::Error),
+    Restart(SocketAddr),
 }
 
-impl ManyTcpListener {
+/// whether `a` and `b` can't both be listened on, being the same address,
+/// or on the same port with one of them the wildcard address of the other's family
+pub fn overlap(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() == b.port()
+        && (a.ip() == b.ip()
+            || (a.is_ipv4() == b.is_ipv4() && (a.ip().is_unspecified() || b.ip().is_unspecified())))
+}
+
+/// whether accepting again a little later might work
+fn is_transient(err: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    if matches!(
+        err.kind(),
+        ConnectionAborted | ConnectionReset | Interrupted | WouldBlock | OutOfMemory
+    ) {
+        return true;
+    }
+
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use nix::libc::{EMFILE, ENFILE, ENOBUFS, ENOMEM};
+            matches!(err.raw_os_error(), Some(EMFILE | ENFILE | ENOBUFS | ENOMEM))
+        } else {
+            // WSAEMFILE and WSAENOBUFS
+            matches!(err.raw_os_error(), Some(10024 | 10055))
+        }
+    }
+}
+
+/// how long a listener waits before accepting again after a transient error, doubled every time
+const ACCEPT_BACKOFF: (Duration, Duration) = (Duration::from_millis(5), Duration::from_secs(1));
+/// how long until a failed listener is bound again, doubled every time it fails again
+const RESTART_BACKOFF: (Duration, Duration) = (Duration::from_millis(100), Duration::from_secs(30));
+
+/// Listeners bound by [`ManyTcpListener::prepare`], closed if they're dropped
+pub struct Prepared(HashMap<SocketAddr, (TcpListener, Fd)>);
+
+/// A listener that's accepting connections
+struct Bound<T> {
+    /// what the connections it accepts get tagged with, can change while it's listening
+    tag: Rc<RefCell<T>>,
+    /// never sent on, dropping it stops the listener
+    _stop: flume::Sender<()>,
+    /// a copy of the listener's descriptor, to hand it over to another process
+    fd: Fd,
+}
+
+/// A listener that failed, waiting to be bound again
+struct Failed<T> {
+    tag: T,
+    /// how long to wait if binding it again fails too
+    backoff: Duration,
+}
+
+pub struct ManyTcpListener<T> {
+    tx: flume::Sender<Message<T>>,
+    listeners: flume::Receiver<Message<T>>,
+    bound: HashMap<SocketAddr, Bound<T>>,
+    failed: HashMap<SocketAddr, Failed<T>>,
+    reuse_port: bool,
+}
+
+impl<T: Clone + 'static> ManyTcpListener<T> {
+    /// takes over the `inherited` listener of every address there is one for, and binds the rest,
+    /// with `reuse_port` set, every address can be bound again by another listener
+    /// that set it too, and the kernel spreads the connections between them
     pub async fn bind<A: Into<SocketAddr>>(
-        addrs: impl IntoIterator<Item = A>,
+        addrs: impl IntoIterator<Item = (A, T)>,
         bind_concurrent: usize,
+        reuse_port: bool,
+        inherited: &mut Inherited,
     ) -> io::Result<Self> {
-        let stream = stream::iter(addrs.into_iter().map(Into::into))
-            .map(|addr| async move { TcpListener::bind(addr).map(|l| (l, addr)) });
-
-        let (tx, rx) = flume::unbounded();
-        let listener_op = |tx: flume::Sender<_>, listener: TcpListener, local_sock| async move {
-            monoio::spawn(async move {
-                loop {
-                    match listener.accept().await {
-                        Ok((stream, peer)) => {
-                            let res = ManyRecvResult {
-                                stream,
-                                peer,
-                                local: local_sock,
-                            };
-                            let Ok(()) = tx.send(Ok(res)) else { break };
-                        }
-                        Err(err) => {
-                            let _ = tx.send(Err(err));
-                            break;
-                        }
-                    }
-                }
-            });
-        };
+        let opts = &ListenerOpts::new().reuse_port(reuse_port);
+        let stream = stream::iter(addrs.into_iter().map(|(addr, tag)| (addr.into(), tag))).map(
+            |(addr, tag)| {
+                let listener = inherited
+                    .listen(addr, opts)
+                    .and_then(|l| dup(&l).map(|fd| (l, fd)))
+                    .map_err(|err| {
+                        io::Error::new(err.kind(), format!("unable to listen on `{addr}`: {err}"))
+                    });
+                async move { listener.map(|(l, fd)| (l, fd, addr, tag)) }
+            },
+        );
 
         let stream = match bind_concurrent {
             n @ 2.. => Either::Right(stream.buffer_unordered(n)),
             _ => Either::Left(stream.then(std::convert::identity)),
         };
 
+        // nothing is accepted until the previous connection was taken,
+        // so a caller that stops accepting leaves connections in the listen backlog
+        let (tx, rx) = flume::bounded(0);
+        let mut this = Self {
+            tx,
+            listeners: rx,
+            bound: HashMap::new(),
+            failed: HashMap::new(),
+            reuse_port,
+        };
+
         stream
-            .try_for_each(|(listener, sock)| {
-                let tx = tx.clone();
-                listener_op(tx, listener, sock).map(Ok)
+            .try_for_each(|(listener, fd, addr, tag)| {
+                this.spawn(addr, listener, fd, tag);
+                future::ready(Ok(()))
             })
             .await?;
 
-        Ok(Self { listeners: rx })
+        Ok(this)
     }
 
-    /// this returns
-    /// ((established TcpStream between \<local\> and \<remote\>), \<local\>, \<remote\>)
-    pub async fn accept(&mut self) -> io::Result<ManyRecvResult> {
-        self.listeners.recv_async().await.unwrap_or_else(|_| {
-            panic!();
-            // Err(io::Error::other("all listeners disconnected"))
-        })
+    fn spawn(&mut self, local_sock: SocketAddr, listener: TcpListener, fd: Fd, tag: T) {
+        let (tx, (stop_tx, stop)) = (self.tx.clone(), flume::bounded::<()>(0));
+        let tag = Rc::new(RefCell::new(tag));
+        self.bound.insert(
+            local_sock,
+            Bound {
+                tag: Rc::clone(&tag),
+                _stop: stop_tx,
+                fd,
+            },
+        );
+
+        monoio::spawn(async move {
+            let mut backoff = None;
+            loop {
+                let wait = backoff;
+                let accept = pin!(async {
+                    if let Some(wait) = wait {
+                        sleep(wait).await
+                    }
+                    listener.accept().await
+                });
+                let accepted = match future::select(accept, stop.recv_async()).await {
+                    Either::Left((accepted, _)) => accepted,
+                    // stopped listening on the address, close the listener right away
+                    Either::Right(_) => break,
+                };
+
+                let message = match accepted {
+                    Ok((stream, peer)) => {
+                        backoff = None;
+                        // the listener's address might be unspecified
+                        let local = stream.local_addr().unwrap_or(local_sock);
+                        Message::Accepted(ManyRecvResult {
+                            stream,
+                            peer,
+                            local,
+                            tag: tag.borrow().clone(),
+                        })
+                    }
+                    Err(err) if is_transient(&err) => {
+                        let next = backoff.map_or(ACCEPT_BACKOFF.0, |backoff| {
+                            (backoff * 2).min(ACCEPT_BACKOFF.1)
+                        });
+                        tracing::warn!(
+                            "Unable to accept a connection on `{local_sock}`: {err}, retrying in {next:?}"
+                        );
+                        backoff = Some(next);
+                        continue;
+                    }
+                    Err(err) => {
+                        let _ = tx.send_async(Message::Failed(local_sock, tag, err)).await;
+                        break;
+                    }
+                };
+
+                let Ok(()) = tx.send_async(message).await else {
+                    break;
+                };
+            }
+        });
+    }
+
+    /// binds `addr` again once `retry` elapsed
+    fn restart_in(&mut self, addr: SocketAddr, tag: T, retry: Duration) {
+        let backoff = (retry * 2).min(RESTART_BACKOFF.1);
+        self.failed.insert(addr, Failed { tag, backoff });
+
+        let tx = self.tx.clone();
+        monoio::spawn(async move {
+            sleep(retry).await;
+            let _ = tx.send_async(Message::Restart(addr)).await;
+        });
+    }
+
+    /// the addresses listened on, and the ones waiting to be bound again
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.bound.keys().chain(self.failed.keys()).copied()
+    }
+
+    /// binds every one of `addrs` that isn't listened on yet, without accepting on them,
+    /// so that switching to them can't fail half way,
+    /// fails if any of them can't be bound, closing the ones that were
+    pub fn prepare(&self, addrs: impl IntoIterator<Item = SocketAddr>) -> io::Result<Prepared> {
+        let opts = ListenerOpts::new().reuse_port(self.reuse_port);
+        addrs
+            .into_iter()
+            .filter(|addr| !self.bound.contains_key(addr) && !self.failed.contains_key(addr))
+            .map(|addr| {
+                let listener = TcpListener::bind_with_config(addr, &opts).map_err(|err| {
+                    io::Error::new(err.kind(), format!("unable to listen on `{addr}`: {err}"))
+                })?;
+                let fd = dup(&listener)?;
+                Ok((addr, (listener, fd)))
+            })
+            .collect::<io::Result<_>>()
+            .map(Prepared)
+    }
+
+    /// listens on the addresses of `tags`, and only those, tagging the connections accepted
+    /// from now on as it says, `prepared` holds the ones that weren't listened on before,
+    /// the connections accepted already aren't affected
+    pub fn switch(
+        &mut self,
+        mut prepared: Prepared,
+        tags: impl IntoIterator<Item = (SocketAddr, T)>,
+    ) {
+        let mut kept = HashSet::new();
+        for (addr, tag) in tags {
+            kept.insert(addr);
+            if let Some(bound) = self.bound.get(&addr) {
+                *bound.tag.borrow_mut() = tag;
+            } else if let Some(failed) = self.failed.get_mut(&addr) {
+                failed.tag = tag;
+            } else if let Some((listener, fd)) = prepared.0.remove(&addr) {
+                tracing::debug!("Started listening on `{addr}`");
+                self.spawn(addr, listener, fd, tag)
+            }
+        }
+
+        let stale = self
+            .addrs()
+            .filter(|addr| !kept.contains(addr))
+            .collect::<Vec<_>>();
+        for addr in stale {
+            tracing::debug!("Stopped listening on `{addr}`");
+            self.bound.remove(&addr);
+            self.failed.remove(&addr);
+        }
+    }
+
+    fn bind_one(&mut self, addr: SocketAddr, tag: T) -> io::Result<()> {
+        let opts = ListenerOpts::new().reuse_port(self.reuse_port);
+        let listener = TcpListener::bind_with_config(addr, &opts)?;
+        let fd = dup(&listener)?;
+        self.spawn(addr, listener, fd, tag);
+        Ok(())
+    }
+
+    /// copies of the descriptors of every listener
+    #[cfg(unix)]
+    pub fn try_clone_fds(&self) -> io::Result<Vec<OwnedFd>> {
+        self.bound
+            .values()
+            .map(|bound| bound.fd.try_clone())
+            .collect()
+    }
+
+    /// the next connection accepted on any of the listeners, or the next one of them to fail,
+    /// a listener that failed is closed, and bound again after a backoff
+    pub async fn accept(&mut self) -> Event<T> {
+        loop {
+            // `self` holds on to a sender, so this never disconnects
+            let Ok(message) = self.listeners.recv_async().await else {
+                unreachable!("the listeners' channel disconnected")
+            };
+
+            match message {
+                Message::Accepted(accepted) => return Event::Accepted(accepted),
+                Message::Failed(addr, tag, source) => {
+                    // stopped listening on it in the meantime
+                    if !self
+                        .bound
+                        .get(&addr)
+                        .is_some_and(|bound| Rc::ptr_eq(&bound.tag, &tag))
+                    {
+                        continue;
+                    }
+                    self.bound.remove(&addr);
+                    let retry = RESTART_BACKOFF.0;
+                    self.restart_in(addr, tag.borrow().clone(), retry);
+                    return Event::Failed(ListenerError {
+                        addr,
+                        source,
+                        retry,
+                    });
+                }
+                Message::Restart(addr) => {
+                    // stopped listening on it in the meantime
+                    let Some(Failed { tag, backoff }) = self.failed.remove(&addr) else {
+                        continue;
+                    };
+                    return match self.bind_one(addr, tag.clone()) {
+                        Ok(()) => Event::Restarted(addr),
+                        Err(source) => {
+                            self.restart_in(addr, tag, backoff);
+                            Event::Failed(ListenerError {
+                                addr,
+                                source,
+                                retry: backoff,
+                            })
+                        }
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn overlapping() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert!(overlap(addr("127.0.0.1:80"), addr("127.0.0.1:80")));
+        assert!(overlap(addr("0.0.0.0:80"), addr("127.0.0.1:80")));
+        assert!(overlap(addr("[::1]:80"), addr("[::]:80")));
+        assert!(!overlap(addr("0.0.0.0:80"), addr("127.0.0.1:81")));
+        assert!(!overlap(addr("127.0.0.2:80"), addr("127.0.0.1:80")));
+        // they're bound side by side for dual stack
+        assert!(!overlap(addr("0.0.0.0:80"), addr("[::]:80")));
+        assert!(!overlap(addr("0.0.0.0:80"), addr("[::1]:80")));
+    }
+
+    #[test]
+    fn transient() {
+        assert!(is_transient(&io::ErrorKind::ConnectionAborted.into()));
+        #[cfg(unix)]
+        assert!(is_transient(&io::Error::from_raw_os_error(
+            nix::libc::EMFILE
+        )));
+        assert!(!is_transient(&io::ErrorKind::PermissionDenied.into()));
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    #[cfg_attr(miri, ignore)]
+    async fn restart() {
+        let addr = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let mut listener = ManyTcpListener::bind([(addr, 7)], 1, false, &mut Inherited::default())
+            .await
+            .unwrap();
+
+        let (tx, tag) = (listener.tx.clone(), Rc::clone(&listener.bound[&addr].tag));
+        monoio::spawn(async move {
+            let err = io::Error::other("failed");
+            tx.send_async(Message::Failed(addr, tag, err)).await
+        });
+        let Event::Failed(err) = listener.accept().await else {
+            panic!("the listener should have failed")
+        };
+        assert_eq!((err.addr, err.retry), (addr, RESTART_BACKOFF.0));
+        assert_eq!(listener.addrs().collect::<Vec<_>>(), [addr]);
+
+        let Event::Restarted(restarted) = listener.accept().await else {
+            panic!("the listener should have been restarted")
+        };
+        assert_eq!(restarted, addr);
+
+        let (connected, accepted) = monoio::join!(TcpStream::connect_addr(addr), listener.accept());
+        connected.unwrap();
+        let Event::Accepted(accepted) = accepted else {
+            panic!("a connection should have been accepted")
+        };
+        assert_eq!(accepted.tag, 7);
+    }
+
+    fn free_addr() -> SocketAddr {
+        std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+    }
+
+    async fn accepted_tag(listener: &mut ManyTcpListener<i32>, addr: SocketAddr) -> i32 {
+        let (connected, accepted) = monoio::join!(TcpStream::connect_addr(addr), listener.accept());
+        connected.unwrap();
+        let Event::Accepted(accepted) = accepted else {
+            panic!("a connection should have been accepted")
+        };
+        accepted.tag
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    #[cfg_attr(miri, ignore)]
+    async fn switch() {
+        let (a, b) = (free_addr(), free_addr());
+        let mut listener = ManyTcpListener::bind([(a, 1)], 1, false, &mut Inherited::default())
+            .await
+            .unwrap();
+
+        // one address that can't be bound fails all of them, and changes nothing
+        let taken = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        assert!(listener.prepare([b, taken.local_addr().unwrap()]).is_err());
+        assert_eq!(listener.addrs().collect::<Vec<_>>(), [a]);
+        assert_eq!(accepted_tag(&mut listener, a).await, 1);
+        // `b` was closed again, which io_uring gets around to on its own time
+        sleep(Duration::from_millis(10)).await;
+
+        // only the new address is bound, the other one swaps its tag
+        let prepared = listener.prepare([a, b]).unwrap();
+        assert_eq!(prepared.0.keys().collect::<Vec<_>>(), [&b]);
+        listener.switch(prepared, [(a, 2), (b, 3)]);
+        assert_eq!(accepted_tag(&mut listener, a).await, 2);
+        assert_eq!(accepted_tag(&mut listener, b).await, 3);
+
+        let prepared = listener.prepare([b]).unwrap();
+        listener.switch(prepared, [(b, 4)]);
+        assert_eq!(listener.addrs().collect::<Vec<_>>(), [b]);
+        assert_eq!(accepted_tag(&mut listener, b).await, 4);
     }
 }
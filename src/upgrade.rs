@@ -0,0 +1,264 @@
+use std::io::{self, IoSlice, IoSliceMut, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use nix::sys::socket::{
+    getsockopt, recvmsg, sendmsg, sockopt, ControlMessage, ControlMessageOwned, MsgFlags,
+};
+
+use crate::shutdown;
+use crate::stream::Inherited;
+
+/// sent along with every listener handed over
+const LISTENER: u8 = 1;
+/// sent once every listener was handed over
+const DONE: u8 = 0;
+/// sent back once the new process is listening
+const READY: u8 = 2;
+
+/// The process being replaced, waiting to hear that its replacement is listening
+pub struct Predecessor(UnixStream);
+
+impl Predecessor {
+    /// lets the old process stop listening and drain its sessions
+    pub fn ready(mut self) -> io::Result<()> {
+        self.0.write_all(&[READY])
+    }
+}
+
+fn recv_listener(stream: &UnixStream) -> io::Result<Option<OwnedFd>> {
+    let mut byte = [DONE];
+    let mut iov = [IoSliceMut::new(&mut byte)];
+    let mut cmsgs = nix::cmsg_space!(RawFd);
+    let msg = recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsgs),
+        MsgFlags::empty(),
+    )?;
+
+    let mut listener = None;
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            // Safety: the descriptors were just received, and nothing else owns them
+            let mut fds = fds
+                .into_iter()
+                .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+            listener = fds.next();
+            // only one is ever sent with a message, close any others
+            fds.for_each(drop);
+        }
+    }
+
+    let read = msg.bytes;
+    match (read, byte, listener) {
+        (0, ..) => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the process being replaced hung up, it might be shutting down",
+        )),
+        (_, [LISTENER], Some(listener)) => Ok(Some(listener)),
+        (_, [DONE], None) => Ok(None),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the process being replaced sent something other than a listener",
+        )),
+    }
+}
+
+/// fails unless `listener` was bound with `SO_REUSEPORT` exactly if `reuse_port` is set,
+/// with `--reuse-port` every thread binds the addresses of the listeners taken over again,
+/// and without it they're meant to be bound exclusively
+fn check_reuse_port(listener: &OwnedFd, reuse_port: bool) -> io::Result<()> {
+    match getsockopt(listener, sockopt::ReusePort)? == reuse_port {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the process being replaced runs {} --reuse-port, restart it instead to change that",
+                if reuse_port { "without" } else { "with" }
+            ),
+        )),
+    }
+}
+
+/// takes over the listeners of the process serving them on `path`, if there is one,
+/// as long as it was started with the same `reuse_port`
+pub fn take_over(
+    path: &Path,
+    reuse_port: bool,
+    inherited: &mut Inherited,
+) -> io::Result<Option<Predecessor>> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(err) => {
+            return match err.kind() {
+                // nothing to replace
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => Ok(None),
+                _ => Err(err),
+            };
+        }
+    };
+
+    while let Some(listener) = recv_listener(&stream)? {
+        // hanging up lets the old process carry on
+        check_reuse_port(&listener, reuse_port)?;
+        inherited.insert(std::net::TcpListener::from(listener))?;
+    }
+
+    tracing::info!(
+        "Took over {} listeners from the process being replaced",
+        inherited.len()
+    );
+    Ok(Some(Predecessor(stream)))
+}
+
+/// Listeners to hand over to the process replacing this one, for as long as this one listens
+type Listeners = Arc<Mutex<Option<Vec<OwnedFd>>>>;
+
+/// whether the new process took over the listeners, and is ready
+fn hand_over(mut stream: UnixStream, listeners: &Listeners) -> io::Result<bool> {
+    {
+        let listeners = listeners.lock().unwrap_or_else(PoisonError::into_inner);
+        // this process is shutting down already, hanging up tells the new one
+        let Some(listeners) = &*listeners else {
+            return Ok(false);
+        };
+
+        for listener in listeners {
+            sendmsg::<()>(
+                stream.as_raw_fd(),
+                &[IoSlice::new(&[LISTENER])],
+                &[ControlMessage::ScmRights(&[listener.as_raw_fd()])],
+                MsgFlags::empty(),
+                None,
+            )?;
+        }
+    }
+    stream.write_all(&[DONE])?;
+
+    let mut ready = [DONE];
+    match stream.read(&mut ready)? {
+        1 => Ok(ready == [READY]),
+        _ => Ok(false),
+    }
+}
+
+/// Serves the listeners of this process to the one replacing it
+pub struct Handover(Listeners);
+
+impl Handover {
+    /// serves `listeners` on `path` from a thread of its own,
+    /// once the new process is listening this one shuts down
+    pub fn serve(path: &Path, listeners: Vec<OwnedFd>) -> io::Result<Self> {
+        // left behind by the process this one replaced, or one that didn't shut down cleanly
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let server = UnixListener::bind(path)?;
+
+        let listeners = Arc::new(Mutex::new(Some(listeners)));
+        let handover = Handover(Arc::clone(&listeners));
+        std::thread::Builder::new()
+            .name("hptp-upgrade".to_owned())
+            .spawn(move || {
+                for stream in server.incoming() {
+                    let res = stream.and_then(|stream| hand_over(stream, &listeners));
+                    match res {
+                        Ok(true) => {
+                            tracing::info!(
+                                "The new process took over the listeners, shutting down"
+                            );
+                            shutdown::request();
+                            break;
+                        }
+                        Ok(false) => {
+                            tracing::warn!("The new process went away before taking over")
+                        }
+                        Err(err) => tracing::warn!("Unable to hand the listeners over: {err}"),
+                    }
+                }
+            })?;
+
+        Ok(handover)
+    }
+
+    /// hands `listeners` over instead, once the ones listened on changed
+    pub fn set(&self, listeners: Vec<OwnedFd>) {
+        let mut current = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        // don't start handing them over again once this process is shutting down
+        if let Some(current) = &mut *current {
+            *current = listeners
+        }
+    }
+
+    /// stops handing the listeners over, so they close along with the ones of this process
+    pub fn close(&self) {
+        drop(self.0.lock().unwrap_or_else(PoisonError::into_inner).take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::setsockopt;
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+
+    fn bound(n: usize) -> (Vec<SocketAddr>, Listeners) {
+        let listeners = (0..n)
+            .map(|_| TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap())
+            .collect::<Vec<_>>();
+        let addrs = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        let fds = listeners.into_iter().map(OwnedFd::from).collect();
+        (addrs, Arc::new(Mutex::new(Some(fds))))
+    }
+
+    #[test]
+    fn handover() {
+        let (addrs, listeners) = bound(2);
+        let (old, new) = UnixStream::pair().unwrap();
+        let old = std::thread::spawn(move || hand_over(old, &listeners));
+
+        let mut received = vec![];
+        while let Some(listener) = recv_listener(&new).unwrap() {
+            received.push(TcpListener::from(listener).local_addr().unwrap());
+        }
+        assert_eq!(received, addrs);
+
+        Predecessor(new).ready().unwrap();
+        assert!(old.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn hang_up() {
+        // the new process went away before it was ready
+        let (_, listeners) = bound(1);
+        let (old, new) = UnixStream::pair().unwrap();
+        let old = std::thread::spawn(move || hand_over(old, &listeners));
+        assert!(recv_listener(&new).unwrap().is_some());
+        assert!(recv_listener(&new).unwrap().is_none());
+        drop(new);
+        assert!(!old.join().unwrap().unwrap());
+
+        // the old process is shutting down, and hangs up right away
+        let (_, listeners) = bound(1);
+        Handover(Arc::clone(&listeners)).close();
+        let (old, new) = UnixStream::pair().unwrap();
+        assert!(!hand_over(old, &listeners).unwrap());
+        let err = recv_listener(&new).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn reuse_port() {
+        let listener = OwnedFd::from(TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap());
+        assert!(check_reuse_port(&listener, false).is_ok());
+        assert!(check_reuse_port(&listener, true).is_err());
+
+        setsockopt(&listener, sockopt::ReusePort, &true).unwrap();
+        assert!(check_reuse_port(&listener, true).is_ok());
+        assert!(check_reuse_port(&listener, false).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+use std::fmt::{Display, Formatter, Write};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::IpAddr;
+use std::num::NonZero;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use hickory_resolver::proto::ProtoError as DnsProtoError;
+use itertools::Itertools;
+use smallvec::SmallVec;
+
+use crate::host::Host;
+
+/// How an upstream is picked for every new connection
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum Balance {
+    /// cycle through the upstreams, proportionally to their weight
+    #[default]
+    RoundRobin,
+    /// the upstream with the least active connections relative to its weight
+    LeastConnections,
+    /// the less loaded of two upstreams picked at random
+    RandomTwoChoices,
+    /// the same client ip always gets the same upstream, as long as the upstreams don't change
+    ConsistentHash,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UpstreamParseError {
+    #[error("expected at least one upstream host")]
+    Empty,
+    #[error("invalid upstream host: {0}")]
+    Host(#[from] DnsProtoError),
+    #[error("invalid weight `{0}`, expected a positive integer")]
+    Weight(String),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WeightedHost {
+    pub host: Host,
+    pub weight: NonZero<u32>,
+}
+
+impl FromStr for WeightedHost {
+    type Err = UpstreamParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, weight) = match s.rsplit_once('=') {
+            Some((host, weight)) => {
+                let weight = weight
+                    .trim()
+                    .parse()
+                    .map_err(|_| UpstreamParseError::Weight(weight.trim().to_owned()))?;
+                (host, weight)
+            }
+            None => (s, NonZero::<u32>::MIN),
+        };
+
+        Ok(WeightedHost {
+            host: host.parse()?,
+            weight,
+        })
+    }
+}
+
+impl Display for WeightedHost {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.host.as_string())?;
+        match self.weight {
+            NonZero::<u32>::MIN => Ok(()),
+            weight => write!(f, "={weight}"),
+        }
+    }
+}
+
+/// A comma separated list of upstream hosts, each optionally followed by `=<weight>`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamHosts(Vec<WeightedHost>);
+
+impl FromStr for UpstreamHosts {
+    type Err = UpstreamParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hosts = s
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match hosts.is_empty() {
+            true => Err(UpstreamParseError::Empty),
+            false => Ok(UpstreamHosts(hosts)),
+        }
+    }
+}
+
+impl Display for UpstreamHosts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, host) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_char(',')?;
+            }
+            Display::fmt(host, f)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Backend {
+    host: Host,
+    weight: NonZero<u32>,
+    /// stable across restarts, unlike the `Host` itself
+    key: u64,
+    active: AtomicUsize,
+}
+
+impl Backend {
+    /// orders backends by their active connections relative to their weight
+    fn load_cmp(&self, other: &Backend) -> std::cmp::Ordering {
+        let load = |a: &Backend, b: &Backend| {
+            a.active.load(Ordering::Relaxed) as u64 * u64::from(b.weight.get())
+        };
+
+        load(self, other).cmp(&load(other, self))
+    }
+}
+
+/// The upstream hosts of a rule, and the state needed to balance connections across them
+pub struct Upstreams {
+    backends: Box<[Backend]>,
+    balance: Balance,
+    next: AtomicUsize,
+}
+
+/// An upstream picked for a connection, counted as active until dropped
+pub struct Selected<'a> {
+    backend: &'a Backend,
+}
+
+impl Selected<'_> {
+    pub fn host(&self) -> Host {
+        self.backend.host
+    }
+}
+
+impl Drop for Selected<'_> {
+    fn drop(&mut self) {
+        self.backend.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Upstreams {
+    pub fn new(UpstreamHosts(hosts): UpstreamHosts, balance: Balance) -> Self {
+        let backends = hosts
+            .into_iter()
+            .map(|WeightedHost { host, weight }| {
+                let mut hasher = DefaultHasher::new();
+                host.as_string().hash(&mut hasher);
+                Backend {
+                    host,
+                    weight,
+                    key: hasher.finish(),
+                    active: AtomicUsize::new(0),
+                }
+            })
+            .collect::<Box<[_]>>();
+
+        Upstreams {
+            backends,
+            balance,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn balance(&self) -> Balance {
+        self.balance
+    }
+
+    pub fn hosts(&self) -> impl Iterator<Item = WeightedHost> + '_ {
+        self.backends.iter().map(|backend| WeightedHost {
+            host: backend.host,
+            weight: backend.weight,
+        })
+    }
+
+    /// the backend a point in `0..` the total weight of the `candidates` falls on
+    fn weighted(&self, mut point: u64, candidates: impl Fn(usize) -> bool) -> usize {
+        self.backends
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| candidates(i))
+            .find(
+                |(_, backend)| match point.checked_sub(u64::from(backend.weight.get())) {
+                    Some(rest) => {
+                        point = rest;
+                        false
+                    }
+                    None => true,
+                },
+            )
+            .map(|(i, _)| i)
+            .expect("point should be less than the total weight")
+    }
+
+    fn total_weight(&self, candidates: impl Fn(usize) -> bool) -> u64 {
+        self.backends
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| candidates(i))
+            .map(|(_, backend)| u64::from(backend.weight.get()))
+            .sum()
+    }
+
+    fn pick(&self, client: IpAddr, candidates: impl Fn(usize) -> bool + Copy) -> usize {
+        if self.backends.len() == 1 {
+            return 0;
+        }
+
+        match self.balance {
+            Balance::RoundRobin => {
+                let n = self.next.fetch_add(1, Ordering::Relaxed) as u64;
+                self.weighted(n % self.total_weight(candidates), candidates)
+            }
+            Balance::LeastConnections => {
+                // start from a different backend every time, so ties are broken fairly
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % self.backends.len();
+                (start..self.backends.len())
+                    .chain(0..start)
+                    .filter(|&i| candidates(i))
+                    .min_by(|&a, &b| self.backends[a].load_cmp(&self.backends[b]))
+                    .unwrap_or(start)
+            }
+            Balance::RandomTwoChoices => {
+                let a = self.weighted(fastrand::u64(..self.total_weight(candidates)), candidates);
+                let others = |i| i != a && candidates(i);
+                let rest = self.total_weight(others);
+                if rest == 0 {
+                    return a;
+                }
+
+                let b = self.weighted(fastrand::u64(..rest), others);
+                match self.backends[b].load_cmp(&self.backends[a]) {
+                    std::cmp::Ordering::Less => b,
+                    _ => a,
+                }
+            }
+            Balance::ConsistentHash => {
+                // weighted rendezvous hashing
+                let score = |backend: &Backend| {
+                    let mut hasher = DefaultHasher::new();
+                    (client, backend.key).hash(&mut hasher);
+                    let unit = (hasher.finish() as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+                    f64::from(backend.weight.get()) / -unit.ln()
+                };
+
+                self.backends
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, _)| candidates(i))
+                    .map(|(i, backend)| (i, score(backend)))
+                    .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map_or(0, |(i, _)| i)
+            }
+        }
+    }
+
+    /// picks an upstream for `client` out of the ones `usable` accepts,
+    /// or out of all of them if it accepts none
+    pub fn select(&self, client: IpAddr, usable: impl Fn(Host) -> bool) -> Selected<'_> {
+        let usable = self
+            .backends
+            .iter()
+            .map(|backend| usable(backend.host))
+            .collect::<SmallVec<bool, 8>>();
+
+        let index = match usable.contains(&true) {
+            true => self.pick(client, |i| usable[i]),
+            false => self.pick(client, |_| true),
+        };
+
+        let backend = &self.backends[index];
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Selected { backend }
+    }
+}
+
+impl Display for Upstreams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut hosts = self.hosts();
+        match (hosts.next(), hosts.next()) {
+            (Some(WeightedHost { host, .. }), None) => Display::fmt(&host, f),
+            _ => write!(f, "[{}] ({})", self.hosts().join(", "), self.balance),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+
+    fn upstreams(hosts: &str, balance: Balance) -> Upstreams {
+        Upstreams::new(hosts.parse().unwrap(), balance)
+    }
+
+    fn client(i: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, i))
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn parse_upstream_hosts() {
+        let hosts = "10.0.0.1=3, example.com,::1=2"
+            .parse::<UpstreamHosts>()
+            .unwrap();
+        assert_eq!(hosts.to_string(), "10.0.0.1=3,example.com,::1=2");
+        assert_eq!(hosts.to_string().parse::<UpstreamHosts>().unwrap(), hosts);
+
+        assert!("".parse::<UpstreamHosts>().is_err());
+        assert!("10.0.0.1=0".parse::<UpstreamHosts>().is_err());
+        assert!("10.0.0.1=-1".parse::<UpstreamHosts>().is_err());
+        assert!("10.0.0.1=a".parse::<UpstreamHosts>().is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn round_robin_respects_weights() {
+        let upstreams = upstreams("10.0.0.1=3,10.0.0.2", Balance::RoundRobin);
+        let mut counts = HashMap::new();
+        for _ in 0..400 {
+            *counts
+                .entry(upstreams.select(client(1), |_| true).host().as_string())
+                .or_insert(0) += 1;
+        }
+
+        assert_eq!(counts["10.0.0.1"], 300);
+        assert_eq!(counts["10.0.0.2"], 100);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn least_connections() {
+        let upstreams = upstreams("10.0.0.1,10.0.0.2,10.0.0.3", Balance::LeastConnections);
+        let held = [
+            upstreams.select(client(1), |_| true),
+            upstreams.select(client(1), |_| true),
+        ];
+        let third = upstreams.select(client(1), |_| true);
+        assert!(held.iter().all(|held| held.host() != third.host()));
+
+        drop(held);
+        // the only backend with an active connection left shouldn't be picked
+        for _ in 0..10 {
+            assert_ne!(upstreams.select(client(1), |_| true).host(), third.host());
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn random_two_choices_prefers_idle() {
+        let upstreams = upstreams("10.0.0.1,10.0.0.2", Balance::RandomTwoChoices);
+        let busy = (0..8)
+            .map(|_| upstreams.select(client(1), |_| true))
+            .filter(|selected| selected.host().as_string() == "10.0.0.1")
+            .collect::<Vec<_>>();
+
+        if !busy.is_empty() {
+            for _ in 0..10 {
+                assert_eq!(
+                    upstreams.select(client(1), |_| true).host().as_string(),
+                    "10.0.0.2"
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn consistent_hash_is_sticky() {
+        let upstreams = upstreams("10.0.0.1,10.0.0.2,10.0.0.3", Balance::ConsistentHash);
+        let picks = (0..32)
+            .map(|i| upstreams.select(client(i), |_| true).host())
+            .collect::<Vec<_>>();
+
+        for (i, &host) in picks.iter().enumerate() {
+            assert_eq!(upstreams.select(client(i as u8), |_| true).host(), host);
+        }
+        assert!(picks.iter().unique().count() > 1);
+
+        // removing an upstream only moves the clients that were on it
+        let fewer = self::upstreams("10.0.0.1,10.0.0.2", Balance::ConsistentHash);
+        for (i, &host) in picks.iter().enumerate() {
+            if host.as_string() != "10.0.0.3" {
+                assert_eq!(fewer.select(client(i as u8), |_| true).host(), host);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn skips_unusable() {
+        for balance in [
+            Balance::RoundRobin,
+            Balance::LeastConnections,
+            Balance::RandomTwoChoices,
+            Balance::ConsistentHash,
+        ] {
+            let upstreams = upstreams("10.0.0.1=5,10.0.0.2,10.0.0.3", balance);
+            let usable = |host: Host| host.as_string() != "10.0.0.1";
+            for i in 0..32 {
+                assert_ne!(
+                    upstreams.select(client(i), usable).host().as_string(),
+                    "10.0.0.1"
+                );
+            }
+
+            // if nothing is usable, anything goes
+            upstreams.select(client(1), |_| false);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::num::NonZero;
+use std::sync::Arc;
+
+use futures::future;
+use monoio::net::TcpStream;
+
+use crate::stream::into_std;
+use crate::{block_on, handle, Admitted, Global, Route};
+
+/// How many threads handle connections, and how connections get to them
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ThreadConfig {
+    pub threads: NonZero<usize>,
+    /// every thread accepts on a listener of its own, bound with SO_REUSEPORT,
+    /// instead of having connections handed to it by the main thread
+    pub reuse_port: bool,
+    /// pin every thread to a cpu of its own
+    pub pin: bool,
+}
+
+impl Default for ThreadConfig {
+    fn default() -> Self {
+        ThreadConfig {
+            threads: NonZero::<usize>::MIN,
+            reuse_port: false,
+            pin: false,
+        }
+    }
+}
+
+/// pins the calling thread, the `i`th one, to a cpu
+pub fn pin(i: usize) {
+    let cpus = std::thread::available_parallelism().map_or(1, NonZero::get);
+    let cpu = i % cpus;
+    match monoio::utils::bind_to_cpu_set([cpu]) {
+        Ok(()) => tracing::debug!("Pinned thread {i} to cpu {cpu}"),
+        Err(err) => tracing::warn!("Unable to pin thread {i} to cpu {cpu}: {err}"),
+    }
+}
+
+/// starts the `i`th thread, running the future `make` returns on a runtime of its own
+pub fn spawn_thread<F: Future<Output = ()>>(
+    i: usize,
+    config: ThreadConfig,
+    make: impl FnOnce() -> F + Send + 'static,
+) -> io::Result<()> {
+    std::thread::Builder::new()
+        .name(format!("hptp-worker-{i}"))
+        .spawn(move || {
+            if config.pin {
+                pin(i)
+            }
+            block_on(async {
+                make().await;
+                // keeps the sessions on this thread going,
+                // the main thread waits for them before it exits the process
+                future::pending::<()>().await
+            })
+        })
+        .map(drop)
+}
+
+/// A connection that was let through, for any thread to handle
+pub struct Accepted {
+    pub stream: TcpStream,
+    pub peer: SocketAddr,
+    pub local: SocketAddr,
+    pub route: Route,
+    pub admitted: Admitted,
+}
+
+/// An [`Accepted`] connection on its way to another thread's runtime
+struct Handoff {
+    stream: std::net::TcpStream,
+    peer: SocketAddr,
+    local: SocketAddr,
+    route: Route,
+    admitted: Admitted,
+}
+
+/// Where the connections accepted on a thread are handled
+pub struct Workers {
+    global: Arc<Global>,
+    /// `None` if they're handled on the accepting thread
+    queue: Option<flume::Sender<Handoff>>,
+}
+
+/// handles connections off the queue until it's closed
+async fn work(queue: flume::Receiver<Handoff>, global: Arc<Global>) {
+    while let Ok(handoff) = queue.recv_async().await {
+        let Handoff {
+            stream,
+            peer,
+            local,
+            route,
+            admitted,
+        } = handoff;
+
+        match TcpStream::from_std(stream) {
+            Ok(stream) => {
+                monoio::spawn(handle(
+                    route,
+                    Arc::clone(&global),
+                    stream,
+                    peer,
+                    local,
+                    admitted,
+                ));
+            }
+            Err(err) => tracing::error!("Unable to take over the connection from `{peer}`: {err}"),
+        }
+    }
+}
+
+impl Workers {
+    /// how many connections can be waiting for a worker, per worker
+    const QUEUE_PER_THREAD: usize = 64;
+
+    /// connections are handled right on the accepting thread
+    pub fn local(global: &Arc<Global>) -> Self {
+        Workers {
+            global: Arc::clone(global),
+            queue: None,
+        }
+    }
+
+    /// starts `config.threads - 1` more threads to hand connections to,
+    /// the calling thread has to be running a runtime, and handles connections too
+    pub fn spawn(config: ThreadConfig, global: &Arc<Global>) -> io::Result<Self> {
+        let threads = config.threads.get();
+        if threads == 1 {
+            return Ok(Self::local(global));
+        }
+
+        let (tx, rx) = flume::bounded(threads * Self::QUEUE_PER_THREAD);
+        for i in 1..threads {
+            let (rx, global) = (rx.clone(), Arc::clone(global));
+            spawn_thread(i, config, move || work(rx, global))?;
+        }
+
+        monoio::spawn(work(rx, Arc::clone(global)));
+        Ok(Workers {
+            global: Arc::clone(global),
+            queue: Some(tx),
+        })
+    }
+
+    /// hands `accepted` to whichever worker gets to it first,
+    /// waiting while every worker is busy
+    pub async fn dispatch(&self, accepted: Accepted) {
+        let Accepted {
+            stream,
+            peer,
+            local,
+            route,
+            admitted,
+        } = accepted;
+
+        let Some(queue) = &self.queue else {
+            let global = Arc::clone(&self.global);
+            monoio::spawn(handle(route, global, stream, peer, local, admitted));
+            return;
+        };
+
+        let handoff = Handoff {
+            stream: into_std(stream),
+            peer,
+            local,
+            route,
+            admitted,
+        };
+
+        if queue.send_async(handoff).await.is_err() {
+            tracing::error!("Every worker has stopped, dropping the connection from `{peer}`")
+        }
+    }
+}